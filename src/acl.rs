@@ -0,0 +1,207 @@
+//! Per-path access control for client commands.
+//!
+//! An `Acl` maps authentication tokens to identities, and `Path` prefixes to the `Call`s each
+//! identity may make under that prefix. Prefixes may contain wildcards: `*` matches any single
+//! path component and `**` matches all remaining components.
+//!
+//! For a given identity, the most specific rule covering a path decides access. A rule with no
+//! calls can be used to deny access to a subtree. Requests that can reach a more specific rule
+//! (e.g. through wildcards, or by writing / killing a whole subtree) must also be allowed by it.
+//!
+//! An empty `Acl` allows everything.
+
+use std::collections::HashMap;
+use std::fs::File;
+
+use serde_json;
+
+use command::Call;
+use path::Path;
+
+/// Identity of clients that have not authenticated.
+pub const ANONYMOUS: &'static str = "anonymous";
+
+/// Matches any identity, including `ANONYMOUS`.
+const ANY: &'static str = "*";
+
+#[derive(Debug, Default)]
+pub struct Acl {
+    identities: HashMap<String, String>, // Token -> identity
+    rules: Vec<Rule>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub prefix: Path,
+    pub identities: Vec<String>,
+    pub calls: Vec<Call>
+}
+
+/// On-disk representation of an `Acl`.
+#[derive(Deserialize)]
+struct AclFile {
+    #[serde(default)]
+    identities: HashMap<String, String>,
+    #[serde(default)]
+    rules: Vec<RuleFile>
+}
+
+#[derive(Deserialize)]
+struct RuleFile {
+    path: Vec<String>,
+    identities: Vec<String>,
+    calls: Vec<String>
+}
+
+impl Acl {
+    pub fn new(identities: HashMap<String, String>, rules: Vec<Rule>) -> Acl {
+        Acl {
+            identities: identities,
+            rules: rules
+        }
+    }
+
+    /// Loads an `Acl` from a JSON file.
+    pub fn load(filename: &str) -> Result<Acl, String> {
+        let file = try!(File::open(filename).map_err(|e| format!("Cannot open {}: {}", filename, e)));
+        let acl: AclFile = try!(serde_json::from_reader(file).map_err(|e| format!("Bad ACL file {}: {}", filename, e)));
+
+        let mut rules = vec![];
+
+        for rule in acl.rules {
+            let mut calls = vec![];
+
+            for call in rule.calls {
+                calls.push(try!(call.parse()));
+            }
+
+            rules.push(Rule {
+                prefix: Path::new(rule.path),
+                identities: rule.identities,
+                calls: calls
+            });
+        }
+
+        Ok(Acl::new(acl.identities, rules))
+    }
+
+    /// Returns true if no rules are defined, i.e. everything is allowed.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Resolves an authentication token to an identity.
+    pub fn authenticate(&self, token: &str) -> Option<String> {
+        self.identities.get(token).cloned()
+    }
+
+    /// Returns true if `identity` may make `call` on `path`.
+    pub fn allows(&self, identity: &str, call: Call, path: &Path) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        let rules: Vec<&Rule> = self.rules.iter()
+            .filter(|r| r.applies_to(identity))
+            .collect();
+
+        // Most specific rule covering path decides
+        let governing = rules.iter()
+            .filter(|r| r.covers(path))
+            .max_by_key(|r| r.prefix.len());
+
+        let governing_len = match governing {
+            Some(rule) if rule.calls.contains(&call) => rule.prefix.len(),
+            _ => return false
+        };
+
+        // More specific rules reachable from path must also allow call
+        rules.iter()
+            .filter(|r| r.prefix.len() > governing_len && r.reachable(call, path))
+            .all(|r| r.calls.contains(&call))
+    }
+}
+
+impl Rule {
+    fn applies_to(&self, identity: &str) -> bool {
+        self.identities.iter().any(|i| i == identity || i == ANY)
+    }
+
+    /// Returns true if this rule's prefix is a prefix of `path`.
+    fn covers(&self, path: &Path) -> bool {
+        let mut parts = path.path.iter();
+
+        for p in &self.prefix.path {
+            if p == "**" {
+                return true;
+            }
+
+            match parts.next() {
+                Some(part) if p == part => continue,
+                Some(part) if p == "*" && ! is_wildcard(part) => continue,
+                _ => return false
+            }
+        }
+
+        true
+    }
+
+    /// Returns true if a `call` on `path` may touch data under this rule's prefix.
+    fn reachable(&self, call: Call, path: &Path) -> bool {
+        let mut parts = path.path.iter();
+
+        for p in &self.prefix.path {
+            match parts.next() {
+                // Reads only return data at path, writes / kills affect entire subtree
+                None => return call == Call::Write || call == Call::Kill,
+                Some(part) if part == "**" || part == "*#" => return true,
+                Some(part) if part == "*" || p == "*" || p == part => continue,
+                Some(_) if p == "**" => return true,
+                Some(_) => return false
+            }
+        }
+
+        true
+    }
+}
+
+fn is_wildcard(part: &str) -> bool {
+    part == "*" || part == "**" || part == "*#"
+}
+
+#[test]
+fn test_allows() {
+    use command::Call::*;
+
+    let rule = |prefix: Path, identities: Vec<&str>, calls: Vec<Call>| Rule {
+        prefix: prefix,
+        identities: identities.iter().map(|i| i.to_string()).collect(),
+        calls: calls
+    };
+
+    let acl = Acl::new(HashMap::new(), vec![
+        rule(path!(), vec!["*"], vec![Read]),
+        rule(path!(moo), vec!["cow"], vec![Read, Write, Bind, Kill]),
+        rule(path!(moo.secret), vec!["cow"], vec![]),
+        rule(path!(teams.*.public), vec!["cow"], vec![Read])
+    ]);
+
+    assert!(acl.allows(ANONYMOUS, Read, &path!(moo)));
+    assert!(!acl.allows(ANONYMOUS, Write, &path!(moo)));
+
+    assert!(acl.allows("cow", Write, &path!(moo.cow)));
+    assert!(acl.allows("cow", Read, &path!(moo.cow.*)));
+    assert!(!acl.allows("cow", Read, &path!(moo.secret)));
+    assert!(!acl.allows("cow", Read, &path!(moo.secret.stuff)));
+
+    // Wildcard reads and subtree writes reach moo.secret
+    assert!(!acl.allows("cow", Read, &path!(moo.*)));
+    assert!(!acl.allows("cow", Read, &path!(moo.%)));
+    assert!(!acl.allows("cow", Write, &path!(moo)));
+    assert!(!acl.allows("cow", Kill, &path!(moo)));
+
+    assert!(acl.allows("cow", Read, &path!(teams.a.public)));
+    assert!(!acl.allows("cow", Write, &path!(teams.a.public)));
+
+    assert!(Acl::default().allows(ANONYMOUS, Kill, &path!()));
+}
@@ -0,0 +1,164 @@
+//! Running headless: the shell on a local admin socket, and a pid file.
+//!
+//! The admin socket accepts the same commands as the stdin shell, one session per connection.
+//! `exit` or `quit` ends the session, `shutdown` stops the node. It listens on a Unix domain socket
+//! only accessible by its owner; there is no TCP listener, as any local user could connect to one.
+
+use std;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::thread::Builder;
+
+use serde::{de, Deserialize, Deserializer};
+
+use app::AppHandle;
+use shell;
+
+/// Path of the admin socket.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdminAddr(pub PathBuf);
+
+impl fmt::Display for AdminAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.display())
+    }
+}
+
+impl FromStr for AdminAddr {
+    type Err = String;
+
+    /// Parses a socket path. IP/port combinations are rejected rather than taken as file names.
+    fn from_str(s: &str) -> Result<AdminAddr, String> {
+        match s.parse::<SocketAddr>() {
+            Ok(addr) => Err(format!("Admin address {} is not a socket path", addr)),
+            Err(_) if s.is_empty() => Err("Empty admin address".into()),
+            Err(_) => Ok(AdminAddr(PathBuf::from(s)))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AdminAddr {
+    fn deserialize<D>(deserializer: D) -> Result<AdminAddr, D::Error> where D: Deserializer<'de> {
+        let s = try!(String::deserialize(deserializer));
+
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Starts accepting shell sessions on `addr`. A stale socket is replaced, but any other file at
+/// that path is left alone and fails the call.
+pub fn spawn(app: &AppHandle, addr: &AdminAddr) -> io::Result<()> {
+    let app = app.clone();
+    let path = &addr.0;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.file_type().is_socket() => try!(std::fs::remove_file(path)),
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is not a socket", addr))),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err)
+    }
+
+    let listener = try!(UnixListener::bind(path));
+
+    try!(std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)));
+
+    try!(thread("Admin").spawn(move || {
+        for stream in listener.incoming() {
+            match stream.and_then(|s| s.try_clone().map(|r| (BufReader::new(r), s))) {
+                Ok((reader, writer)) => session(&app, reader, writer),
+                Err(e) => println!("Admin connection error: {}", e)
+            }
+        }
+    }));
+
+    Ok(())
+}
+
+/// Writes the process id to `filename`. Fails if it names a process that is still running.
+pub fn write_pid_file(filename: &str) -> io::Result<()> {
+    let mut contents = String::new();
+
+    match File::open(filename).and_then(|mut f| f.read_to_string(&mut contents)) {
+        Ok(_) => {
+            let pid = contents.trim();
+
+            if ! pid.is_empty() && std::path::Path::new("/proc").join(pid).exists() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("Process {} is running", pid)));
+            }
+        },
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err)
+    }
+
+    let mut file = try!(File::create(filename));
+
+    try!(writeln!(file, "{}", process::id()));
+    file.sync_all()
+}
+
+/// Runs a shell session in its own thread.
+fn session<R: BufRead + Send + 'static, W: Write + Send + 'static>(app: &AppHandle, reader: R, writer: W) {
+    let app = app.clone();
+
+    let spawned = thread("Admin.session").spawn(move || {
+        shell::session(app, reader, writer);
+    });
+
+    if let Err(e) = spawned {
+        println!("Admin session failed: {}", e);
+    }
+}
+
+fn thread(name: &str) -> Builder {
+    Builder::new().name(name.into())
+}
+
+#[test]
+fn test_admin() {
+    use std::os::unix::net::UnixStream;
+
+    use app::App;
+
+    assert_eq!("admin.sock".parse(), Ok(AdminAddr(PathBuf::from("admin.sock"))));
+    assert!("127.0.0.1:1".parse::<AdminAddr>().is_err());
+
+    let dir = PathBuf::from("test_data/admin");
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let app = App::new("127.0.0.1:1000".parse().unwrap());
+    let file = AdminAddr(dir.join("file"));
+    let addr = AdminAddr(dir.join("admin.sock"));
+
+    // Other files are not replaced
+    File::create(&file.0).unwrap();
+
+    assert!(spawn(&app.handle(), &file).is_err());
+    assert!(file.0.is_file());
+
+    spawn(&app.handle(), &addr).unwrap();
+
+    // Stale sockets are
+    spawn(&app.handle(), &addr).unwrap();
+
+    let mut stream = UnixStream::connect(&addr.0).unwrap();
+    let mut output = String::new();
+
+    stream.write_all(b"bad\nquit\n").unwrap();
+    stream.read_to_string(&mut output).unwrap();
+
+    assert_eq!(output, "> Bad command\n> ");
+}
@@ -0,0 +1,265 @@
+//! Anti-entropy: repairing differences between owners of a Zone by comparing digests.
+//!
+//! A replica sends each Peer the digests of the Zones they both own (`Digests`). For each Zone
+//! that differs, the Peer answers with a `Summary` of its root node: its own data and the digests
+//! of its children. Comparing a summary, each side sends the other node data that differs, whole
+//! children the other is missing or that are small, and further summaries of large children that
+//! differ, until only the differences were transferred. Children only the other has are requested
+//! with `Fetch`. Data is sent as `Merge`, which is idempotent, so repeated rounds are harmless.
+//!
+//! Runs every `INTERVAL_SECS` and whenever a Peer becomes reachable again, see `Cluster`. Digests
+//! are cached by each Zone and only hashed again for changed nodes, see `Zone::digest`.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use app::AppHandle;
+use cluster::ClusterMessage;
+use node::Node;
+use path::Path;
+use replica::Replica;
+
+pub const INTERVAL_SECS: u64 = 60;
+
+/// Differing children up to this size are sent whole rather than compared further.
+const SMALL_SUBTREE: usize = 1024;
+
+/// A node of a Zone without children, and digests of the children.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Summary {
+    pub zone: Path,
+    pub path: Path, // Relative to Zone
+    pub node: Node,
+    pub children: Vec<(String, u64)>
+}
+
+/// Result of comparing a remote `Summary` with local data.
+#[derive(Debug, Default)]
+pub struct Comparison {
+    pub merge: Node,            // Local data the remote side lacks, relative to the Zone
+    pub fetch: Vec<String>,     // Children only the remote side has
+    pub compare: Vec<Summary>   // Large local children that differ
+}
+
+impl Summary {
+    pub fn new(zone: &Path, path: &Path, node: &Node) -> Summary {
+        Summary {
+            zone: zone.clone(),
+            path: path.clone(),
+            node: node.shallow(),
+            children: node.child_digests()
+        }
+    }
+}
+
+/// Compares `summary` with the matching node in `local`, the data of its Zone.
+pub fn compare(local: &Node, summary: &Summary) -> Comparison {
+    let empty = Node::default();
+    let node = local.descendant(&summary.path.path).unwrap_or(&empty);
+    let remote: HashMap<_, _> = summary.children.iter().cloned().collect();
+
+    let mut comparison = Comparison::default();
+
+    // Remote side has nothing here
+    if summary.node.is_noop() && summary.children.is_empty() {
+        comparison.merge = node.clone().prepend_path(&summary.path.path);
+        return comparison;
+    }
+
+    let mut merge = match node.shallow() == summary.node {
+        true => Node::default(),
+        false => node.shallow()
+    };
+
+    node.each_child(|k, child| {
+        match remote.get(k) {
+            Some(&digest) if digest == child.digest() => (),
+            None => merge.add_child(k.clone(), child.clone()),
+            Some(_) if child.total_byte_size() <= SMALL_SUBTREE => {
+                merge.add_child(k.clone(), child.clone());
+                comparison.fetch.push(k.clone());
+            },
+            Some(_) => {
+                let mut path = summary.path.clone();
+
+                path.push(k);
+                comparison.compare.push(Summary::new(&summary.zone, &path, child));
+            }
+        }
+    });
+
+    for &(ref k, _) in &summary.children {
+        if node.descendant(&[k.clone()]).is_none() {
+            comparison.fetch.push(k.clone());
+        }
+    }
+
+    if ! merge.is_noop() {
+        comparison.merge = merge.prepend_path(&summary.path.path);
+    }
+
+    comparison
+}
+
+/// Returns children `keys` of the node at `path` in `local`, relative to the Zone.
+pub fn fetch(local: &Node, path: &Path, keys: &[String]) -> Node {
+    let mut node = Node::default();
+
+    if let Some(parent) = local.descendant(&path.path) {
+        for k in keys {
+            if let Some(child) = parent.descendant(&[k.clone()]) {
+                node.add_child(k.clone(), child.clone());
+            }
+        }
+    }
+
+    node.prepend_path(&path.path)
+}
+
+/// Digests of stored Zones accepted by `filter` that each of `peers` also owns, by Peer.
+pub fn digests<F>(app: &AppHandle, peers: &[Replica], filter: F) -> Vec<(Replica, Vec<(Path, u64)>)> where F: Fn(&Path) -> bool {
+    let mut digests: Vec<_> = peers.iter().map(|peer| (peer.clone(), vec![])).collect();
+
+    app.store.each_zone(|path| {
+        if ! filter(&path) {
+            return;
+        }
+
+        let owners: Vec<Replica> = app.shards.read().unwrap().owners(&path).into_iter().cloned().collect();
+
+        if ! digests.iter().any(|&(ref peer, _)| owners.contains(peer)) {
+            return;
+        }
+
+        let digest = match app.manager.load(&path).digest() {
+            Some(digest) => digest,
+            None => return println!("Could not load {:?} for anti-entropy", path)
+        };
+
+        for &mut (ref peer, ref mut list) in &mut digests {
+            if owners.contains(peer) {
+                list.push((path.clone(), digest));
+            }
+        }
+    });
+
+    digests.retain(|&(_, ref list)| ! list.is_empty());
+    digests
+}
+
+/// Answers `Digests` from a Peer with summaries of Zones stored differently here. `local` is the
+/// local peer address.
+pub fn handle_digests(app: &AppHandle, local: SocketAddr, digests: Vec<(Path, u64)>) -> Vec<ClusterMessage> {
+    let mut replies = vec![];
+
+    for (path, digest) in digests {
+        let zone = app.manager.load(&path);
+
+        match zone.digest() {
+            Some(local_digest) if local_digest != digest => {
+                app.stats.cluster.anti_entropy_diffs.increment();
+                replies.push(ClusterMessage::Compare(local, Summary::new(&path, &Path::empty(), &zone.dump_digested().node)));
+            },
+            _ => ()
+        }
+    }
+
+    replies
+}
+
+/// Answers `Compare` from a Peer, merging its node data.
+pub fn handle_compare(app: &AppHandle, local: SocketAddr, summary: Summary) -> Vec<ClusterMessage> {
+    let zone = app.manager.load(&summary.zone);
+    let comparison = compare(&zone.dump_digested().node, &summary);
+    let mut replies = vec![];
+
+    if ! summary.node.is_noop() {
+        zone.merge(summary.node.clone().prepend_path(&summary.path.path).noop_vis(), false);
+    }
+
+    if ! comparison.merge.is_noop() {
+        replies.push(ClusterMessage::Merge(summary.zone.clone(), comparison.merge.noop_vis()));
+    }
+
+    if ! comparison.fetch.is_empty() {
+        replies.push(ClusterMessage::Fetch(local, summary.zone.clone(), summary.path.clone(), comparison.fetch));
+    }
+
+    for summary in comparison.compare {
+        replies.push(ClusterMessage::Compare(local, summary));
+    }
+
+    replies
+}
+
+/// Answers `Fetch` from a Peer.
+pub fn handle_fetch(app: &AppHandle, zone: Path, path: Path, keys: Vec<String>) -> Vec<ClusterMessage> {
+    let node = fetch(&app.manager.load(&zone).dump().node, &path, &keys);
+
+    match node.is_noop() {
+        true => vec![],
+        false => vec![ClusterMessage::Merge(zone, node.noop_vis())]
+    }
+}
+
+#[test]
+fn test_anti_entropy() {
+    use serde_json;
+
+    use node::NodeTree;
+
+    let json = |s: &str| -> serde_json::Value { serde_json::from_str(s).unwrap() };
+    let big = "x".repeat(SMALL_SUBTREE);
+
+    // Same large subtree, with small differences on both sides
+    let mut a = NodeTree::default();
+    let mut b = NodeTree::default();
+
+    for tree in vec![&mut a, &mut b] {
+        let data = json(&format!(r#"{{ "moo": {{ "big": "{}", "cow": 1 }}, "same": 2 }}"#, big));
+
+        tree.merge(&mut Node::expand(data, 1).noop_vis());
+    }
+
+    a.merge(&mut Node::expand(json(r#"{ "moo": { "cow": 3 } }"#), 2).noop_vis());
+    b.merge(&mut Node::expand(json(r#"{ "new": 4 }"#), 2).noop_vis());
+
+    assert!(a.node.digest() != b.node.digest());
+
+    // Exchange messages until done, starting with a summary of `b`
+    let zone = Path::empty();
+    let mut messages = vec![(0, ClusterMessage::Compare("127.0.0.1:1".parse().unwrap(), Summary::new(&zone, &zone, &b.node)))];
+    let mut sent = 0;
+
+    while let Some((to, message)) = messages.pop() {
+        let (local, from) = match to {
+            0 => (&mut a, 1),
+            _ => (&mut b, 0)
+        };
+
+        match message {
+            ClusterMessage::Merge(_, mut tree) => {
+                sent += tree.node.total_byte_size();
+                local.merge(&mut tree);
+            },
+            ClusterMessage::Compare(addr, summary) => {
+                let comparison = compare(&local.node, &summary);
+
+                local.merge(&mut summary.node.clone().prepend_path(&summary.path.path).noop_vis());
+
+                messages.push((from, ClusterMessage::Merge(zone.clone(), comparison.merge.noop_vis())));
+                messages.push((from, ClusterMessage::Fetch(addr, zone.clone(), summary.path, comparison.fetch)));
+                messages.extend(comparison.compare.into_iter().map(|s| (from, ClusterMessage::Compare(addr, s))));
+            },
+            ClusterMessage::Fetch(_, _, path, keys) => {
+                messages.push((from, ClusterMessage::Merge(zone.clone(), fetch(&local.node, &path, &keys).noop_vis())));
+            },
+            _ => unreachable!()
+        }
+    }
+
+    assert_eq!(a.node.digest(), b.node.digest());
+
+    // Large subtree was not sent
+    assert!(sent < SMALL_SUBTREE);
+}
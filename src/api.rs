@@ -0,0 +1,157 @@
+//! In-process API, for services embedding a node without a TCP connection.
+//!
+//! Commands take the same path as those of TCP clients, see `client::process`, including access
+//! control for the identity of the `Api`.
+
+use std::sync::Arc;
+
+use mioco::sync::mpsc::{channel, Receiver, Sender};
+use serde_json;
+use serde_json::Value;
+
+use acl::ANONYMOUS;
+use app::AppHandle;
+use client::{self, Reply};
+use command::{Call, Command};
+use path::Path;
+
+#[derive(Clone)]
+pub struct Api {
+    app: AppHandle,
+    identity: Arc<String>
+}
+
+/// Updates of a bound path, until dropped.
+pub struct Subscription {
+    rx: Receiver<String>
+}
+
+impl Api {
+    /// Creates an `Api` acting as the anonymous identity.
+    pub fn new(app: &AppHandle) -> Api {
+        Api {
+            app: app.clone(),
+            identity: Arc::new(ANONYMOUS.to_string())
+        }
+    }
+
+    /// Returns an `Api` acting as the identity of `token`, if valid.
+    pub fn authenticate(&self, token: &str) -> Option<Api> {
+        self.app.acl.authenticate(token).map(|identity| {
+            Api {
+                app: self.app.clone(),
+                identity: Arc::new(identity)
+            }
+        })
+    }
+
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    /// Reads data at `path`, which may contain wildcards. Replies once for each Zone.
+    pub fn read(&self, path: &Path) -> Vec<Reply> {
+        let (tx, _rx) = channel();
+
+        self.call(Call::Read, path, Value::Null, &tx)
+    }
+
+    /// Reads data at `path` like `read`, then subscribes to changes.
+    pub fn bind(&self, path: &Path) -> (Vec<Reply>, Subscription) {
+        let (tx, rx) = channel();
+        let replies = self.call(Call::Bind, path, Value::Null, &tx);
+
+        (replies, Subscription { rx: rx })
+    }
+
+    /// Merges `value` into data at `path`.
+    pub fn write(&self, path: &Path, value: Value) -> Result<(), String> {
+        let (tx, _rx) = channel();
+
+        first(self.call(Call::Write, path, value, &tx))
+    }
+
+    /// Deletes data at `path`.
+    pub fn kill(&self, path: &Path) -> Result<(), String> {
+        let (tx, _rx) = channel();
+
+        first(self.call(Call::Kill, path, Value::Null, &tx))
+    }
+
+    fn call(&self, call: Call, path: &Path, params: Value, listener: &Sender<String>) -> Vec<Reply> {
+        let mut replies = vec![];
+
+        client::process(&self.app, listener, &self.identity, Command::new(0, call, path.clone(), params), |r| {
+            replies.push(r);
+        });
+
+        replies
+    }
+}
+
+impl Subscription {
+    /// Waits for the next update, returning the path of the bound Zone and changes in the format
+    /// sent to TCP clients. Returns `None` once the node has stopped.
+    pub fn recv(&self) -> Option<(Path, Value)> {
+        self.rx.recv().ok().map(parse_update)
+    }
+
+    /// Returns the next update if one is available.
+    pub fn try_recv(&self) -> Option<(Path, Value)> {
+        self.rx.try_recv().ok().map(parse_update)
+    }
+}
+
+fn first(replies: Vec<Reply>) -> Result<(), String> {
+    match replies.into_iter().next() {
+        Some(reply) => reply.result.map(|_| ()),
+        None => Err("No reply".into())
+    }
+}
+
+/// Parses `[ 0, null, <path>, <update> ]` as sent by `Listener`.
+fn parse_update(json: String) -> (Path, Value) {
+    let mut update: Vec<Value> = serde_json::from_str(&json).expect("Bad update from Listener");
+    let value = update.pop().unwrap_or(Value::Null);
+    let path = update.pop().and_then(|p| serde_json::from_value(p).ok()).unwrap_or(vec![]);
+
+    (Path::new(path), value)
+}
+
+#[test]
+fn test_api() {
+    use app::App;
+    use manager::Manager;
+    use store;
+    use store::memory::{Faults, Memory};
+
+    let mut app = App::new("127.0.0.1:1000".parse().unwrap());
+
+    store::start(Memory::new(app.handle(), Faults::new()), app.channels.store.take().unwrap());
+    Manager::spawn(&mut app);
+    app.manager.load(&Path::empty());
+
+    let api = Api::new(&app.handle());
+
+    assert_eq!(api.identity(), ANONYMOUS);
+    assert!(api.authenticate("moo").is_none());
+
+    let (replies, subscription) = api.bind(&path![moo.cow]);
+
+    assert_eq!(replies.len(), 1);
+    assert!(replies[0].result.is_ok());
+
+    api.write(&Path::empty(), serde_json::from_str(r#"{ "moo": { "cow": 42 } }"#).unwrap()).unwrap();
+
+    let (path, update) = subscription.recv().unwrap();
+
+    assert_eq!(path, Path::empty());
+    assert_eq!(update[0]["moo"][0]["cow"][2], Value::from(42.0));
+
+    let replies = api.read(&path![moo.cow]);
+    let update = replies[0].result.as_ref().unwrap().as_ref().unwrap();
+
+    assert_eq!(update.to_json()[0]["moo"][0]["cow"][2], Value::from(42.0));
+
+    api.kill(&path![moo]).unwrap();
+}
@@ -2,35 +2,56 @@
 //!
 //! `handle` Contains handles of all processes.
 
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
+use time;
+
+use acl::Acl;
 use command::Call;
 use cluster::{ClusterHandle, ClusterChannel};
 use manager::{ManagerHandle, ManagerChannel};
 use replica::Replica;
+use shard::{self, Shards};
+use store;
 use store::{StoreHandle, StoreChannel};
+use wal::WalHandle;
 
 pub struct App {
     pub id: Replica,
+    pub replica_id: String,  // Stable across address changes, see `replica::load_id`
+    pub data_dir: String,
+    pub pid_file: Option<Arc<String>>,  // Removed on shutdown
+
+    pub acl: Arc<Acl>,
+    pub shards: Arc<RwLock<Shards>>,  // Updated by Cluster
 
     pub cluster: ClusterHandle,
     pub manager: ManagerHandle,
     pub store: StoreHandle,
+    pub wal: Option<WalHandle>,
 
     pub channels: Channels,
 
-    pub stats: Arc<Stats>
+    pub stats: Arc<Stats>,
+    pub stopping: Arc<AtomicBool>  // Set once shutdown starts, see `shutdown`
 }
 
 /// The shareable reference to the App
 #[derive(Clone)]
 pub struct AppHandle {
+    pub acl: Arc<Acl>,
+    pub data_dir: Arc<String>,
+    pub pid_file: Option<Arc<String>>,
+    pub shards: Arc<RwLock<Shards>>,
+
     pub cluster: ClusterHandle,
     pub manager: ManagerHandle,
     pub store: StoreHandle,
+    pub wal: Option<WalHandle>,
 
-    pub stats: Arc<Stats>
+    pub stats: Arc<Stats>,
+    pub stopping: Arc<AtomicBool>
 }
 
 #[derive(Clone)]
@@ -51,12 +72,14 @@ pub struct Stats {
     pub clients: ClientStats,
     pub cluster: ClusterStats,
     pub store: StoreStats,
+    pub wal: WalStats,
     pub zones: ZoneStats
 }
 
 #[derive(Default, Serialize)]
 pub struct ClientStats {
     pub connects: Stat,
+    pub denied: Stat,
     pub disconnects: Stat,
     pub commands: CommandStats,
     pub replies: Stat
@@ -64,20 +87,51 @@ pub struct ClientStats {
 
 #[derive(Default, Serialize)]
 pub struct ClusterStats {
+    pub anti_entropy: Stat,          // Rounds started
+    pub anti_entropy_diffs: Stat,    // Zones found to differ
     pub broadcast: Stat,
+    pub commands_forwarded: Stat,    // To owners of Zones, see `router`
+    pub commands_served: Stat,       // Forwarded by Peers
+    pub compress_in_bytes: Stat,     // Before compression
+    pub compress_out_bytes: Stat,    // After, divide by compress_in_bytes for ratio
+    pub gossip: Stat,
     pub handle_cluster_message: Stat,
+    pub heartbeats: Stat,
+    pub peer_batch_max: Stat,        // Most messages written at once
+    pub peer_batch_messages: Stat,   // Total, divide by peer_batches for average batch size
+    pub peer_batches: Stat,
+    pub peer_coalesced: Stat,        // Merges combined with another for the same Zone
+    pub peer_dropped: Stat,          // Messages dropped on overflow
+    pub peer_failures: Stat,         // Failed connections or writes
+    pub peer_overflows: Stat,
+    pub peers_alive: Stat,
+    pub peers_suspect: Stat,
+    pub peers_dead: Stat,
     pub replicas: Stat,
     pub replicate: Stat
 }
 
 #[derive(Default, Serialize)]
 pub struct StoreStats {
+    pub compactions: Stat,
+    pub compress_in_bytes: Stat,     // Before compression
+    pub compress_out_bytes: Stat,    // After, divide by compress_in_bytes for ratio
+    pub corruptions: Stat,
     pub reads: Stat,
     pub reads_pending: Stat,
     pub reads_errors: Stat,
     pub writes: Stat,
     pub writes_pending: Stat,
-    pub writes_errors: Stat
+    pub writes_errors: Stat,
+    pub writes_latency_us: Stat,     // Total, divide by writes for average
+    pub writes_latency_max_us: Stat
+}
+
+#[derive(Default, Serialize)]
+pub struct WalStats {
+    pub appends: Stat,
+    pub replayed: Stat,
+    pub truncations: Stat
 }
 
 #[derive(Default, Serialize)]
@@ -88,6 +142,7 @@ pub struct ZoneStats {
 
 #[derive(Default, Serialize)]
 pub struct CommandStats {
+    pub auth: Stat,
     pub bind: Stat,
     pub kill: Stat,
     pub read: Stat,
@@ -107,11 +162,18 @@ impl App {
         let store = StoreChannel::new();
 
         App {
+            data_dir: store::data_dir(&id),
+            replica_id: String::new(),
             id: id,
+            pid_file: None,
+
+            acl: Default::default(),
+            shards: Arc::new(RwLock::new(Shards::new(shard::DEFAULT_OWNERS))),
 
             cluster: cluster.handle(),
             manager: manager.handle(),
             store: store.handle(),
+            wal: None,
 
             channels: Channels {
                 cluster: Some(cluster),
@@ -119,17 +181,25 @@ impl App {
                 store: Some(store)
             },
 
-            stats: Default::default()
+            stats: Default::default(),
+            stopping: Default::default()
         }
     }
 
     pub fn handle(&self) -> AppHandle {
         AppHandle {
+            acl: self.acl.clone(),
+            data_dir: Arc::new(self.data_dir.clone()),
+            pid_file: self.pid_file.clone(),
+            shards: self.shards.clone(),
+
             cluster: self.cluster.clone(),
             manager: self.manager.clone(),
             store: self.store.clone(),
+            wal: self.wal.clone(),
 
-            stats: self.stats.clone()
+            stats: self.stats.clone(),
+            stopping: self.stopping.clone()
         }
     }
 
@@ -139,6 +209,14 @@ impl App {
 }
 
 impl Stats {
+    /// Records latency of a store write that started at `start` (from `time::precise_time_ns`).
+    pub fn write_latency(&self, start: u64) {
+        let latency = ((time::precise_time_ns() - start) / 1000) as usize;
+
+        self.store.writes_latency_us.add(latency);
+        self.store.writes_latency_max_us.max(latency);
+    }
+
     pub fn to_json(&self) -> String {
         use serde_json;
 
@@ -149,6 +227,7 @@ impl Stats {
 impl CommandStats {
     pub fn increment(&self, call: &Call) {
         match call {
+            &Call::Auth => self.auth.increment(),
             &Call::Bind => self.bind.increment(),
             &Call::Kill => self.kill.increment(),
             &Call::Read => self.read.increment(),
@@ -162,6 +241,10 @@ impl Stat {
         self.value.fetch_add(1, Ordering::Relaxed);
     }
 
+    pub fn add(&self, value: usize) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+    }
+
     pub fn decrement(&self) {
         self.value.fetch_sub(1, Ordering::Relaxed);
     }
@@ -170,6 +253,18 @@ impl Stat {
         self.value.store(value, Ordering::Relaxed);
     }
 
+    /// Raises value to at least `value`.
+    pub fn max(&self, value: usize) {
+        let mut current = self.value();
+
+        while value > current {
+            match self.value.compare_exchange(current, value, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(v) => current = v
+            }
+        }
+    }
+
     pub fn value(&self) -> usize {
         self.value.load(Ordering::Relaxed)
     }
@@ -5,6 +5,7 @@ use std::io::prelude::*;
 use std::io::BufReader;
 use std::mem;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 
 use mioco::sync::mpsc::{channel, Receiver, Sender};
@@ -14,10 +15,12 @@ use mioco;
 use serde_json;
 use serde_json::Value;
 
+use acl::ANONYMOUS;
 use app::AppHandle;
-use command::Command;
+use command::{Call, Command};
 use node::{DelegatedMatch, Update};
 use path::Path;
+use router;
 
 pub struct Client {
     app: AppHandle,
@@ -25,6 +28,16 @@ pub struct Client {
     tx: Sender<String>
 }
 
+/// Reply to a command from one Zone. Recursive commands reply once for each Zone matching data is
+/// delegated to.
+#[derive(Debug)]
+pub struct Reply {
+    pub id: u64,
+    pub left: u64,   // Replies still to come
+    pub path: Path,  // Absolute path of the replying Zone
+    pub result: Result<Option<Update>, String>
+}
+
 impl Client {
     /// Creates a new `Client` from a `TcpStream`
     pub fn new(app: AppHandle, stream: TcpStream) {
@@ -58,7 +71,8 @@ impl Client {
 
         let reader = BufReader::new(self.stream.try_clone().unwrap());
 
-        let (commands_tx, commands_rx) = mioco::sync::mpsc::channel::<Command>();
+        // Commands are queued along with the identity of the client at the time
+        let (commands_tx, commands_rx) = mioco::sync::mpsc::channel::<(Arc<String>, Command)>();
 
         let commands_rx = Arc::new(Mutex::new(commands_rx));
 
@@ -71,7 +85,7 @@ impl Client {
             mioco::spawn(move|| {
                 loop {
                     // quit if disconnected
-                    let command = match commands_rx.lock() {
+                    let (identity, command) = match commands_rx.lock() {
                         Ok(c) => match c.recv() {
                             Ok(c) => c,
                             Err(_) => return
@@ -79,18 +93,26 @@ impl Client {
                         Err(_) => return
                     };
 
-                    process(&app, &tx, command);
+                    process(&app, &tx, &identity, command, |r| send_reply(&app, &tx, r));
                 }
             });
         }
 
+        let mut identity = Arc::new(ANONYMOUS.to_string());
+
         // Read loop, push decoded commands into queue
         for line in reader.lines() {
             match line {
                 Ok(line) => {
                     match Command::from_json(&line) {
+                        Ok(ref command) if command.call == Call::Auth => {
+                            // Applies to all commands read after this one
+                            if let Some(i) = self.authenticate(command) {
+                                identity = i;
+                            }
+                        },
                         Ok(command) => {
-                            commands_tx.send(command).unwrap();
+                            commands_tx.send((identity.clone(), command)).unwrap();
                         },
                         Err(e) => {
                             self.tx.send("[0,\"error\",\"".to_string() + &e + "\"]").unwrap();
@@ -107,6 +129,29 @@ impl Client {
         // command_tx is dropped here, threads using command_rx will panic
     }
 
+    /// Resolves the token in `command.params` to an identity and replies with it.
+    fn authenticate(&self, command: &Command) -> Option<Arc<String>> {
+        self.app.stats.clients.commands.increment(&command.call);
+
+        let identity = command.params.as_str().and_then(|token| self.app.acl.authenticate(token));
+
+        match identity {
+            Some(identity) => {
+                let mut result = serde_json::Map::new();
+
+                result.insert("identity".to_string(), Value::String(identity.clone()));
+                reply(&self.app, &self.tx, command.id, 0, &Path::empty(), Value::Object(result));
+
+                Some(Arc::new(identity))
+            },
+            None => {
+                reply_error(&self.app, &self.tx, command.id, 0, &Path::empty(), "Authentication failed");
+
+                None
+            }
+        }
+    }
+
     fn create_writer_thread(&self, channel: Receiver<String>) {
         let mut writer = self.stream.try_clone().unwrap();
 
@@ -131,10 +176,45 @@ impl Client {
     }
 }
 
-/// Process a single command from client. Recursively dispatch for delegated zones.
-fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command) {
+impl Reply {
+    fn error(id: u64, left: u64, path: &Path, error: &str) -> Reply {
+        Reply { id: id, left: left, path: path.clone(), result: Err(error.to_string()) }
+    }
+}
+
+/// Process a single command from client. Recursively dispatch for delegated zones, calling `reply`
+/// for each. Updates of bound paths are sent to `listener`. Zones owned by other replicas are
+/// served by them, see `router`.
+///
+/// Commands and delegated reads are checked against the `Acl` for `identity`. Denied commands or
+/// delegated zones are replied to with an error.
+pub fn process<F: FnMut(Reply)>(app: &AppHandle, listener: &Sender<String>, identity: &str, mut command: Command, mut reply: F) {
+    if ! app.acl.allows(identity, command.call, &command.path) {
+        app.stats.clients.denied.increment();
+        reply(Reply::error(command.id, 0, &command.path, "Access denied"));
+        return;
+    }
+
+    // Zones may already be saved for the last time
+    if app.stopping.load(Ordering::SeqCst) && (command.call == Call::Write || command.call == Call::Kill) {
+        reply(Reply::error(command.id, 0, &command.path, "Shutting down"));
+        return;
+    }
+
+    // Log writes before they are acknowledged
+    let logged = match (&app.wal, command.call) {
+        (&Some(ref wal), Call::Write) | (&Some(ref wal), Call::Kill) => match wal.append(&command) {
+            None => {
+                reply(Reply::error(command.id, 0, &command.path, "Write failed"));
+                return;
+            },
+            seq => seq
+        },
+        _ => None
+    };
+
     let resolved_path = command.path.resolved();
-    let (prefix, zone) = app.manager.find_nearest(&resolved_path);
+    let (prefix, _) = app.manager.find_nearest(&resolved_path);
 
     let c = Command {
         path: command.path.slice(prefix.len()),
@@ -144,7 +224,19 @@ fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command) {
 
     app.stats.clients.commands.increment(&c.call);
 
-    let mut result = zone.dispatch(c, tx);
+    let mut result = router::dispatch(app, &prefix, c, listener);
+
+    if let (&Some(ref wal), Some(seq)) = (&app.wal, logged) {
+        match result.error {
+            Some(_) => wal.failed(seq),
+            None => wal.done(seq)
+        }
+    }
+
+    if let Some(error) = result.error {
+        reply(Reply::error(command.id, 0, &prefix, &error));
+        return;
+    }
 
     let mut queue: VecDeque<DelegatedMatch> = VecDeque::new();
 
@@ -157,14 +249,22 @@ fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command) {
         queue.push_back(d);
     }
 
-    reply(app, tx, command.id, queue.len() as u64, &prefix, result.update);
+    reply(Reply { id: command.id, left: queue.len() as u64, path: prefix, result: Ok(result.update) });
 
     if ! command.recursive() {
         return;
     }
 
     while let Some(delegated) = queue.pop_front() {
-        let zone = app.manager.load(&delegated.path);
+        let mut path = delegated.path.clone();
+
+        path.append(&mut delegated.match_spec.clone());
+
+        if ! app.acl.allows(identity, command.call, &path) {
+            app.stats.clients.denied.increment();
+            reply(Reply::error(command.id, queue.len() as u64, &delegated.path, "Access denied"));
+            continue;
+        }
 
         let c = Command {
             path: delegated.match_spec,
@@ -172,7 +272,12 @@ fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command) {
             ..command
         };
 
-        let result = zone.dispatch(c, tx);
+        let result = router::dispatch(app, &delegated.path, c, listener);
+
+        if let Some(error) = result.error {
+            reply(Reply::error(command.id, queue.len() as u64, &delegated.path, &error));
+            continue;
+        }
 
         for mut d in result.delegated {
             let mut path = delegated.path.clone();
@@ -182,22 +287,38 @@ fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command) {
             queue.push_back(d);
         }
 
-        reply(app, tx, command.id, queue.len() as u64, &delegated.path, result.update);
+        reply(Reply { id: command.id, left: queue.len() as u64, path: delegated.path, result: Ok(result.update) });
     }
+}
 
-    fn reply(app: &AppHandle, tx: &Sender<String>, id: u64, left: u64, path: &Path, update: Option<Update>) {
-        let response = vec![
-            id.into(),
-            left.into(),
-            path.to_json(),
-            update.map_or(Value::Null, |u| u.to_json())
-        ];
+fn send_reply(app: &AppHandle, tx: &Sender<String>, r: Reply) {
+    match r.result {
+        Ok(update) => reply(app, tx, r.id, r.left, &r.path, update.map_or(Value::Null, |u| u.to_json())),
+        Err(error) => reply_error(app, tx, r.id, r.left, &r.path, &error)
+    }
+}
 
-        app.stats.clients.replies.increment();
+/// Replies with an error in place of an update, e.g. `{ "error": "Access denied" }`.
+fn reply_error(app: &AppHandle, tx: &Sender<String>, id: u64, left: u64, path: &Path, error: &str) {
+    let mut result = serde_json::Map::new();
 
-        // TODO stop processing if unable to reply, otherwise we're just wasting cycles
-        tx.send(serde_json::to_string(&response).unwrap()).unwrap_or_default();
-    }
+    result.insert("error".to_string(), Value::String(error.to_string()));
+
+    reply(app, tx, id, left, path, Value::Object(result));
+}
+
+fn reply(app: &AppHandle, tx: &Sender<String>, id: u64, left: u64, path: &Path, result: Value) {
+    let response = vec![
+        id.into(),
+        left.into(),
+        path.to_json(),
+        result
+    ];
+
+    app.stats.clients.replies.increment();
+
+    // TODO stop processing if unable to reply, otherwise we're just wasting cycles
+    tx.send(serde_json::to_string(&response).unwrap()).unwrap_or_default();
 }
 
 fn pinger(tx: Sender<String>) {
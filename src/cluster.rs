@@ -1,17 +1,35 @@
-//! Cluster manager. Handles Cluster and Sharding (TODO)
-
-use std::collections::{HashMap};
-use std::net::{SocketAddr,TcpListener,TcpStream};
-use std::sync::Arc;
-use std::sync::mpsc::{channel, Receiver, Sender};
+//! Cluster manager. Handles Cluster membership and replication to Zone owners, see `shard`.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown,SocketAddr,TcpListener,TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
 use std::thread::Builder;
+use std::time::{Duration, Instant};
 
 use bincode;
-
-use app::{App, AppHandle};
+use mioco::sync::mpsc;
+use rand;
+use serde_json;
+use serde_json::Value;
+use threadpool::ThreadPool;
+
+use anti_entropy::{self, Summary};
+use app::{App, AppHandle, Stat, Stats};
+use command::{Call, Command};
+use compression::{self, Compression};
+use listener::RListener;
+use membership::{Change, Member, Membership};
 use node::NodeTree;
 use path::Path;
 use replica::Replica;
+use wal::Hold;
+use zone::ZoneResult;
 
 /// A handle to the Cluster process. This is the shareable public interface.
 #[derive(Clone)]
@@ -25,36 +43,239 @@ pub struct ClusterChannel {
     rx: Receiver<ClusterCall>
 }
 
+/// Largest message accepted from a Peer, before and after decompression.
+const MAX_MESSAGE: usize = 10 * 1024 * 1024;
+
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+/// Starts a `Handshake`, telling it apart from a message of a Peer that sends none.
+const HANDSHAKE_MAGIC: [u8; 4] = *b"QMLS";
+
+/// Version of the Peer protocol, sent in `Handshake`.
+const PROTOCOL_VERSION: u32 = 3;
+
+/// First protocol version, sent by Peers with a handshake. Older Peers only decode `Merge` and
+/// `Sync`, see `min_version`.
+const HANDSHAKE_VERSION: u32 = 1;
+
+/// First protocol version that acks heartbeats, see `Health`.
+const ACK_VERSION: u32 = 2;
+
+/// First protocol version that acks delegated data, see `Cluster::delegate`.
+const DELEGATE_ACK_VERSION: u32 = 3;
+
+/// Time a connecting Peer waits for the `Handshake` of the accepting side, before assuming that
+/// it runs an older version without handshakes.
+const LEGACY_TIMEOUT_MS: u64 = 1000;
+
+/// Interval between sending the member list to a random Peer.
+const GOSSIP_INTERVAL_MS: u64 = 1000;
+
+/// Interval between heartbeats sent to a Peer.
+const HEARTBEAT_INTERVAL_MS: u64 = 1000;
+
+/// Peers that ack heartbeats fail once no ack arrived for this long.
+const ACK_TIMEOUT_MS: u64 = 3000;
+
+/// Interval of checking for the ack of a heartbeat while waiting for it.
+const ACK_POLL_MS: u64 = 20;
+
+/// Peers failing for this long are dead rather than suspect.
+const DEAD_AFTER_MS: u64 = 10000;
+
+const MIN_BACKOFF_MS: u64 = 100;
+const MAX_BACKOFF_MS: u64 = 30000;
+const CONNECT_TIMEOUT_MS: u64 = 5000;
+const WRITE_TIMEOUT_MS: u64 = 5000;
+
+/// Commands forwarded to an owner fail if not replied to in time. Delegated data not acked in
+/// time is merged locally.
+const FORWARD_TIMEOUT_SECS: u64 = 10;
+
+/// Threads serving commands forwarded by Peers.
+const DISPATCH_THREADS: usize = 8;
+
+/// Interval at which binds served for Peers are polled for updates, see `spawn_relay`.
+const RELAY_POLL_MS: u64 = 10;
+
+/// Droppable messages queued for a Peer before dropping them, see `PeerState::check_overflow`.
+/// Also the number that may wait in its channel, see `Peer::send`.
+const MAX_QUEUE: usize = 10000;
+
+/// Messages are collected for up to this long, or until `MAX_BATCH` are queued, and then written
+/// to a Peer at once, see `PeerState::next_batch`.
+const BATCH_WINDOW_MS: u64 = 5;
+const MAX_BATCH: usize = 1000;
+
 /// The Cluster manager.
 pub struct Cluster {
     app: AppHandle,
+    binds: HashMap<u64, (SocketAddr, mpsc::Sender<String>)>, // Forwarded binds, with owner address
+    compression: Compression,
+    delegated: HashMap<u64, Delegated>,
+    forwarded: HashMap<u64, Forwarded>,
     handle: ClusterHandle,
     id: Replica,
+    last_anti_entropy: Instant,
+    membership: Membership,
+    next_id: u64,
     peers: HashMap<Replica, Peer>,
+    pool: ThreadPool,
+    relay: Sender<RelayCall>, // Relays binds served for Peers
     replicas: Vec<Replica>,
     rx: Receiver<ClusterCall>
 }
 
+/// Command forwarded to an owner, waiting for `ClusterMessage::Result`.
+struct Forwarded {
+    reply: mpsc::Sender<ZoneResult>,
+    since: Instant
+}
+
+/// Delegated data sent to an owner, held in the WAL until `ClusterMessage::DelegateAck`.
+struct Delegated {
+    path: Path,
+    tree: NodeTree,
+    hold: Hold,
+    since: Instant
+}
+
+/// Where the result and bind updates of a forwarded command go.
+pub struct ReplyTo {
+    reply: mpsc::Sender<ZoneResult>,
+    listener: mpsc::Sender<String>
+}
+
 /// Intra-Cluster Messages.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum ClusterMessage {
     /// Data to be merged for Path
     Merge(Path, NodeTree),
-    Sync
+    Sync,
+    /// Request to send data for Zone at Path, e.g. to recover from local corruption
+    SyncZone(Path),
+    /// Members known to the sender, see `membership`
+    Gossip(Vec<Member>),
+    /// Sent while idle, see `Health`
+    Heartbeat,
+    /// Command for a Zone owned by the recipient, replied to with `Result`
+    Dispatch(Forward),
+    /// Result of `Dispatch` with id
+    Result(u64, ZoneResult),
+    /// Update of a Zone bound by `Dispatch` with id, as sent to clients
+    Notify(u64, String),
+    /// Stops updates of a bind by `Dispatch` from the Peer at address with id
+    Unbind(SocketAddr, u64),
+    /// Delegated data for Zone at Path, merged and replicated by its owner
+    Delegate(Path, NodeTree),
+    /// Digests of Zones owned by both, see `anti_entropy`
+    Digests(SocketAddr, Vec<(Path, u64)>),
+    /// Node of a Zone that differs between the sender and recipient
+    Compare(SocketAddr, Summary),
+    /// Request for children with keys of the node at Path in Zone at Path
+    Fetch(SocketAddr, Path, Path, Vec<String>),
+    /// `Delegate` from the Peer at address with id, acked with `DelegateAck` once saved
+    DelegateHeld(SocketAddr, u64, Path, NodeTree),
+    /// Ack of `DelegateHeld` with id
+    DelegateAck(u64)
+}
+
+/// Command forwarded to an owner of `zone`, with `path` relative to it. Params are sent as JSON,
+/// which bincode cannot encode as `serde_json::Value`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Forward {
+    pub from: SocketAddr, // Peer address of sender
+    pub id: u64,
+    pub zone: Path,
+    pub call: Call,
+    pub path: Path,
+    pub params: String,
+    pub timestamp: u64
+}
+
+/// First message sent in both directions on a new Peer connection, the accepting side first. Each
+/// side then uses the features both support. Sent as `HANDSHAKE_MAGIC` and the serialized
+/// handshake as a byte array, so that later versions can add fields at the end.
+///
+/// Older versions send no handshake, and are sent none: connecting to them, nothing arrives within
+/// `LEGACY_TIMEOUT_MS`, and when they connect, their first message does not start with the magic.
+/// No features are used with them.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Handshake {
+    pub version: u32,
+    pub compression: Compression
 }
 
 /// Interface to Peer.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct Peer {
-    tx: Sender<Arc<ClusterMessage>>
+    addr: SocketAddr,
+    detector: Arc<Mutex<Detector>>,
+    full: Arc<AtomicBool>,                 // Channel was full on the last send
+    overflowed: Arc<Mutex<HashSet<Path>>>, // Shared with `PeerState`
+    queued: Arc<AtomicUsize>,              // Droppable messages in the channel, see `droppable`
+    stats: Arc<Stats>,
+    tx: Sender<PeerCall>,
+    version: Arc<AtomicUsize>              // Protocol version of the last connection
+}
+
+/// Health of a Peer, judged by connecting to it and by the acks of heartbeats, which it sends back
+/// on the same connection. Writes alone only show that the connection's buffers have room. Peers
+/// of protocol versions before `ACK_VERSION` do not ack, and are judged by successful writes
+/// instead. New Peers are suspect until the first ack or write.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum Health {
+    Alive,
+    Suspect,
+    Dead
+}
+
+/// Failure detector of a Peer, shared by `Peer` and `PeerState`.
+#[derive(Debug)]
+struct Detector {
+    health: Health,
+    failures: u64,                  // Consecutive failed connections or writes
+    first_failure: Option<Instant>, // Of consecutive failures
+    last_contact: Option<Instant>,  // Last ack, or successful write to Peers that do not ack
+    last_ack: Option<Instant>       // Set by the ack reader of the connection
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerStatus {
+    pub replica: Replica,
+    pub health: Health,
+    pub failures: u64,
+    pub last_contact_ms: Option<u64> // Time since
+}
+
+/// Used for dispatching calls to a Peer via message passing.
+#[derive(Debug)]
+pub enum PeerCall {
+    /// Replies once all previously sent messages are written
+    Flush(Sender<()>),
+    Send(Arc<ClusterMessage>)
 }
 
 /// Peer internal state.
 pub struct PeerState {
     addr: SocketAddr,
-    pending: Option<Arc<ClusterMessage>>,
+    backoff: Duration,        // Before next reconnection attempt
+    cluster: ClusterHandle,
+    compression: Compression, // Supported locally
+    acked_at: Instant,        // Last ack seen, or when connected
+    acks: bool,               // Current connection acks heartbeats
+    detector: Arc<Mutex<Detector>>,
+    last_heartbeat: Instant,
+    negotiated: Compression,  // Used on current connection
+    overflowed: Arc<Mutex<HashSet<Path>>>, // Zones with dropped changes, synced once reachable
+    queue: VecDeque<PeerCall>,
+    queued: Arc<AtomicUsize>, // Shared with `Peer`
+    queued_at: Option<Instant>, // When the oldest queued call was received
+    retry_at: Option<Instant>,
     stream: Option<TcpStream>,
-    rx: Receiver<Arc<ClusterMessage>>
+    rx: Receiver<PeerCall>,
+    stats: Arc<Stats>,
+    version: Arc<AtomicUsize>
 }
 
 pub struct Server {
@@ -64,20 +285,52 @@ pub struct Server {
 #[derive(Debug)]
 pub enum ClusterCall {
     Add(Replica),
+    Delegate(Vec<Replica>, Path, NodeTree, Vec<RListener>, Option<Hold>),
+    Drain(Duration, Sender<bool>),
+    Forward(Replica, Path, Command, ReplyTo),
     HandleClusterMessage(ClusterMessage),
+    Leave,
+    Members(Sender<Vec<Member>>),
+    Peers(Sender<Vec<PeerStatus>>),
+    Reconnected(SocketAddr),
+    RecoverZone(Path),
     Replicate(Path, NodeTree),
     Sync,
     SyncAll,
-    SyncZone(Path)
+    SyncPeer(SocketAddr, Vec<Path>),
+    SyncZone(Path),
+    SendTo(SocketAddr, ClusterMessage),
+    Tick
 }
 
 impl ClusterHandle {
-    /// Add a new Replica to cluster.
+    /// Add a new Replica to cluster. Other members are then learned from it by gossip.
     pub fn add(&self, replica: Replica) {
         self.send(ClusterCall::Add(replica));
     }
 
-    /// Syncs all Zones.
+    /// Announces to all Peers that this replica is leaving the Cluster.
+    pub fn leave(&self) {
+        self.send(ClusterCall::Leave);
+    }
+
+    /// Current view of Cluster membership.
+    pub fn members(&self) -> Vec<Member> {
+        let (tx, rx) = channel();
+
+        self.send(ClusterCall::Members(tx));
+        rx.recv().expect("Cluster process not running")
+    }
+
+    /// Health of connections to all Peers.
+    pub fn peers(&self) -> Vec<PeerStatus> {
+        let (tx, rx) = channel();
+
+        self.send(ClusterCall::Peers(tx));
+        rx.recv().expect("Cluster process not running")
+    }
+
+    /// Syncs all Zones with their other owners, see `anti_entropy`.
     pub fn sync(&self) {
         self.send(ClusterCall::Sync);
     }
@@ -87,21 +340,57 @@ impl ClusterHandle {
         self.send(ClusterCall::SyncAll);
     }
 
-    /// Syncs Zone to all Peers.
+    /// Syncs Zone to its owners.
     pub fn sync_zone(&self, path: Path) {
         self.send(ClusterCall::SyncZone(path));
     }
 
+    /// Syncs Zones to the Peer at `addr` only, e.g. after dropping its queued changes.
+    pub fn sync_peer(&self, addr: SocketAddr, paths: Vec<Path>) {
+        self.send(ClusterCall::SyncPeer(addr, paths));
+    }
+
+    /// Requests all Peers to send their data for Zone.
+    pub fn recover_zone(&self, path: Path) {
+        self.send(ClusterCall::RecoverZone(path));
+    }
+
     /// Replicate data to all replicas.
     pub fn replicate(&self, path: &Path, data: NodeTree) {
         self.send(ClusterCall::Replicate(path.clone(), data));
     }
 
+    /// Forwards `command`, relative to Zone at `path`, to its `owner`, see `router`. Updates of
+    /// binds are sent to `listener`. Receives the result, or is closed if the owner is unreachable.
+    pub fn forward(&self, owner: &Replica, path: &Path, command: Command, listener: &mpsc::Sender<String>) -> mpsc::Receiver<ZoneResult> {
+        let (tx, rx) = mpsc::channel();
+
+        self.send(ClusterCall::Forward(owner.clone(), path.clone(), command, ReplyTo { reply: tx, listener: listener.clone() }));
+
+        rx
+    }
+
+    /// Sends delegated data for Zone at `path` to the first reachable of its `owners`, binding
+    /// `listeners` there, and keeping `hold` until the owner saved the data. Merged locally if no
+    /// owner is reachable.
+    pub fn delegate(&self, owners: Vec<Replica>, path: &Path, tree: NodeTree, listeners: Vec<RListener>, hold: Option<Hold>) {
+        self.send(ClusterCall::Delegate(owners, path.clone(), tree, listeners, hold));
+    }
+
     /// Handles a message from the cluster.
     pub fn handle_cluster_message(&self, msg: ClusterMessage) {
         self.send(ClusterCall::HandleClusterMessage(msg));
     }
 
+    /// Waits up to `timeout` until messages queued for all Peers are sent. Returns false on
+    /// timeout, e.g. if a Peer is unreachable.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let (tx, rx) = channel();
+
+        self.send(ClusterCall::Drain(timeout, tx));
+        rx.recv_timeout(timeout).unwrap_or(false)
+    }
+
     fn send(&self, call: ClusterCall) {
         self.tx.send(call).expect("Cluster process not running");
     }
@@ -121,22 +410,40 @@ impl ClusterChannel {
 }
 
 impl Cluster {
-    pub fn new(app: &mut App) -> Cluster {
+    pub fn new(app: &mut App, compression: Compression) -> Cluster {
         let rx =  app.channels.cluster.take().expect("Receiver already taken");
 
-        Cluster {
+        // Replicas without a stable id are identified by address
+        let member_id = match app.replica_id.is_empty() {
+            true => app.id.to_string(),
+            false => app.replica_id.clone()
+        };
+
+        let cluster = Cluster {
             app: app.handle(),
+            binds: HashMap::new(),
+            compression: compression,
+            delegated: HashMap::new(),
+            forwarded: HashMap::new(),
             id: app.id.clone(),
+            last_anti_entropy: Instant::now(),
+            membership: Membership::new(member_id, app.id.clone()),
             handle: app.cluster.clone(),
+            next_id: 0,
             peers: HashMap::new(),
+            pool: ThreadPool::new(DISPATCH_THREADS),
+            relay: spawn_relay(app.cluster.clone()),
             replicas: vec![],
             rx: rx.rx
-        }
+        };
+
+        cluster.update_shards();
+        cluster
     }
 
-    /// Start the Cluster "process".
-    pub fn spawn(app: &mut App) {
-        let mut cluster = Cluster::new(app);
+    /// Start the Cluster "process". `compression` is used with Peers that support it.
+    pub fn spawn(app: &mut App, compression: Compression) {
+        let mut cluster = Cluster::new(app, compression);
 
         thread("Cluster").spawn(move || {
             cluster.run();
@@ -144,7 +451,17 @@ impl Cluster {
     }
 
     pub fn run(&mut self) {
-        Server::spawn(&self.id.peer_addr(), self.handle.clone());
+        Server::spawn(&self.id.peer_addr(), self.handle.clone(), self.compression);
+
+        let handle = self.handle.clone();
+
+        thread("Cluster.tick").spawn(move || {
+            loop {
+                thread::sleep(Duration::from_millis(GOSSIP_INTERVAL_MS));
+                handle.send(ClusterCall::Tick);
+            }
+        }).expect("Cluster tick spawn failed");
+
         self.message_loop();
     }
 
@@ -154,17 +471,50 @@ impl Cluster {
 
             match call {
                 ClusterCall::Add(replica) => self.add(replica),
+                ClusterCall::Delegate(owners, path, tree, listeners, hold) => self.delegate(owners, path, tree, listeners, hold),
+                ClusterCall::Drain(timeout, reply) => {
+                    reply.send(self.drain(timeout)).is_ok(); // ignore if caller gave up
+                },
+                ClusterCall::Forward(owner, path, command, reply_to) => self.forward(owner, path, command, reply_to),
                 ClusterCall::HandleClusterMessage(msg) => self.handle_cluster_message(msg),
+                ClusterCall::Leave => self.leave(),
+                ClusterCall::Members(reply) => {
+                    reply.send(self.membership.list()).is_ok(); // ignore if caller gave up
+                },
+                ClusterCall::Peers(reply) => {
+                    reply.send(self.peers()).is_ok(); // ignore if caller gave up
+                },
+                ClusterCall::Reconnected(addr) => self.anti_entropy(Some(addr)),
+                ClusterCall::RecoverZone(path) => self.recover_zone(path),
                 ClusterCall::Replicate(path, data) => self.replicate(path, data),
                 ClusterCall::Sync => self.sync(),
                 ClusterCall::SyncAll => self.sync_all(),
-                ClusterCall::SyncZone(path) => self.sync_zone(path)
+                ClusterCall::SyncPeer(addr, paths) => self.sync_peer(addr, paths),
+                ClusterCall::SyncZone(path) => self.sync_zone(path),
+                ClusterCall::SendTo(addr, msg) => {
+                    match self.peer_at(addr) {
+                        // Bind updates are never dropped, so they are not queued for dead Peers
+                        Some(peer) if peer.is_dead() && is_notify(&msg) => (),
+                        Some(peer) => peer.send(Arc::new(msg)),
+                        None => ()
+                    }
+                },
+                ClusterCall::Tick => {
+                    self.gossip();
+                    self.expire_forwarded();
+                    self.expire_delegated();
+
+                    if self.last_anti_entropy.elapsed() >= Duration::from_secs(anti_entropy::INTERVAL_SECS) {
+                        self.last_anti_entropy = Instant::now();
+                        self.sync();
+                    }
+                }
             }
         }
     }
 
     /// Handles a message from the cluster.
-    fn handle_cluster_message(&self, msg: ClusterMessage) {
+    fn handle_cluster_message(&mut self, msg: ClusterMessage) {
         self.app.stats.cluster.handle_cluster_message.increment();
 
         match msg {
@@ -174,11 +524,44 @@ impl Cluster {
 
                 zone.merge(data, false);
             },
-            ClusterMessage::Sync => self.sync()
+            ClusterMessage::Sync => self.sync(),
+            ClusterMessage::SyncZone(path) => self.sync_zone(path),
+            ClusterMessage::Gossip(members) => self.merge_members(members),
+            ClusterMessage::Heartbeat => (),
+            ClusterMessage::Dispatch(forward) => self.serve(forward),
+            ClusterMessage::Result(id, result) => self.forward_result(id, result),
+            ClusterMessage::Notify(id, update) => self.notify(id, update),
+            ClusterMessage::Unbind(from, id) => {
+                self.relay.send(RelayCall::Remove(from, id)).is_ok();
+            },
+            ClusterMessage::Delegate(path, tree) => self.app.manager.load(&path).merge(tree, true),
+            ClusterMessage::Digests(from, digests) => self.respond(from, move |app, local| {
+                anti_entropy::handle_digests(app, local, digests)
+            }),
+            ClusterMessage::Compare(from, summary) => self.respond(from, move |app, local| {
+                anti_entropy::handle_compare(app, local, summary)
+            }),
+            ClusterMessage::Fetch(from, zone, path, keys) => self.respond(from, move |app, _| {
+                anti_entropy::handle_fetch(app, zone, path, keys)
+            }),
+            ClusterMessage::DelegateHeld(from, id, path, tree) => {
+                let zone = self.app.manager.load(&path);
+                let handle = self.handle.clone();
+
+                zone.merge(tree, true);
+
+                // Acked once saved. Unless acked in time, the sender merges locally instead
+                zone.on_saved(move || {
+                    handle.tx.send(ClusterCall::SendTo(from, ClusterMessage::DelegateAck(id))).is_ok(); // ignore if stopped
+                });
+            },
+            ClusterMessage::DelegateAck(id) => {
+                self.delegated.remove(&id);
+            }
         }
     }
 
-    /// Add a new Replica to Cluster
+    /// Add a new Replica to Cluster, introducing ourselves to it.
     pub fn add(&mut self, replica: Replica) {
         if replica == self.id {
             return;
@@ -191,33 +574,145 @@ impl Cluster {
         self.replicas.push(replica.clone());
         self.app.stats.cluster.replicas.increment();
 
-        let peer = Peer::spawn(replica.peer_addr());
+        let peer = Peer::spawn(replica.peer_addr(), self.compression, self.handle.clone(), self.app.stats.clone());
+
+        peer.send(Arc::new(ClusterMessage::Gossip(self.membership.list())));
 
         self.peers.insert(replica, peer);
         // TODO: sync?
     }
 
-    /// Replicates data to all replicas.
+    /// Removes a Replica from Cluster, e.g. once it left.
+    pub fn remove(&mut self, replica: &Replica) {
+        if self.peers.remove(replica).is_some() {
+            self.relay.send(RelayCall::RemovePeer(replica.peer_addr())).is_ok();
+            self.replicas.retain(|r| r != replica);
+            self.app.stats.cluster.replicas.decrement();
+        }
+    }
+
+    /// Health of connections to all Peers, in the order they were added.
+    pub fn peers(&self) -> Vec<PeerStatus> {
+        self.replicas.iter().map(|replica| self.peers[replica].status(replica)).collect()
+    }
+
+    /// Sends the member list to a random Peer that can decode it.
+    fn gossip(&self) {
+        let peers: Vec<_> = self.peers.values().filter(|peer| peer.version() >= HANDSHAKE_VERSION).collect();
+
+        if ! peers.is_empty() {
+            let peer = peers[rand::random::<usize>() % peers.len()];
+
+            self.app.stats.cluster.gossip.increment();
+            peer.send(Arc::new(ClusterMessage::Gossip(self.membership.list())));
+        }
+    }
+
+    /// Applies membership changes learned by gossip. Zones whose owners changed are then synced
+    /// to them.
+    fn merge_members(&mut self, members: Vec<Member>) {
+        let changes = self.membership.merge(members);
+
+        if changes.is_empty() {
+            return;
+        }
+
+        let previous = self.app.shards.read().unwrap().clone();
+
+        for change in changes {
+            match change {
+                Change::Joined(member) => {
+                    println!("Member {} joined at {}", member.id, member.replica);
+                    self.add(member.replica);
+                },
+                Change::Left(member) => {
+                    println!("Member {} left", member.id);
+                    self.remove(&member.replica);
+                },
+                Change::Moved(previous, member) => {
+                    println!("Member {} moved from {} to {}", member.id, previous, member.replica);
+                    self.remove(&previous);
+                    self.add(member.replica);
+                }
+            }
+        }
+
+        self.update_shards();
+
+        let shards = self.app.shards.clone();
+
+        self.anti_entropy_where(None, move |path| previous.owners(path) != shards.read().unwrap().owners(path));
+    }
+
+    /// Assigns Zones to the current alive members.
+    fn update_shards(&self) {
+        self.app.shards.write().unwrap().update(self.membership.local(), self.membership.alive());
+    }
+
+    /// Tells all Peers that we are leaving. Replication continues until shut down.
+    fn leave(&mut self) {
+        self.membership.leave();
+        self.broadcast(ClusterMessage::Gossip(self.membership.list()));
+    }
+
+    /// Replicates data to the other owners of Zone at `path`.
     pub fn replicate(&self, path: Path, data: NodeTree) {
         self.app.stats.cluster.replicate.increment();
 
-        // TODO: shard
-        // for now, replicate to all replicas
+        let shards = self.app.shards.read().unwrap();
         let message = Arc::new(ClusterMessage::Merge(path.clone(), data));
 
-        for (_addr, peer) in &self.peers {
-            peer.send(message.clone());
+        for replica in shards.owners(&path) {
+            if let Some(peer) = self.peers.get(replica) {
+                peer.send(message.clone());
+            }
         }
     }
 
-    /// Synchronize each Zone to all Peers.
+    /// Synchronize each Zone with its other owners, transferring only differences.
     pub fn sync(&self) {
-        self.app.store.each_zone(|path| {
-            match self.app.store.load_data(path.clone()) {
-                None => println!("Could not sync {:?}", path),
-                Some(data) => self.replicate(path, data.tree)
+        self.anti_entropy(None);
+    }
+
+    /// Starts anti-entropy with all reachable Peers, or only the one at `only`.
+    fn anti_entropy(&self, only: Option<SocketAddr>) {
+        self.anti_entropy_where(only, |_| true);
+    }
+
+    /// Same as `anti_entropy`, for Zones accepted by `filter`.
+    fn anti_entropy_where<F>(&self, only: Option<SocketAddr>, filter: F) where F: Fn(&Path) -> bool + Send + 'static {
+        let peers: Vec<Replica> = self.peers.keys().filter(|replica| {
+            only.map_or(true, |addr| replica.peer_addr() == addr) && self.reachable(replica).is_some()
+        }).cloned().collect();
+
+        if peers.is_empty() {
+            return;
+        }
+
+        self.app.stats.cluster.anti_entropy.increment();
+
+        let app = self.app.clone();
+        let handle = self.handle.clone();
+        let local = self.id.peer_addr();
+
+        self.pool.execute(move || {
+            for (peer, digests) in anti_entropy::digests(&app, &peers, filter) {
+                handle.send(ClusterCall::SendTo(peer.peer_addr(), ClusterMessage::Digests(local, digests)));
             }
-        })
+        });
+    }
+
+    /// Answers the Peer at `to` from the pool, as answering may wait for Zones to load.
+    fn respond<F>(&self, to: SocketAddr, f: F) where F: FnOnce(&AppHandle, SocketAddr) -> Vec<ClusterMessage> + Send + 'static {
+        let app = self.app.clone();
+        let handle = self.handle.clone();
+        let local = self.id.peer_addr();
+
+        self.pool.execute(move || {
+            for msg in f(&app, local) {
+                handle.send(ClusterCall::SendTo(to, msg));
+            }
+        });
     }
 
     /// Request all peers to synchronize local data.
@@ -226,7 +721,7 @@ impl Cluster {
         self.sync();
     }
 
-    /// Synchronize Zone to all Peers.
+    /// Synchronize Zone to its owners.
     pub fn sync_zone(&self, path: Path) {
         // TODO: does not check for non-existent Zones
         match self.app.store.load_data(path.clone()) {
@@ -235,6 +730,201 @@ impl Cluster {
         }
     }
 
+    /// Synchronize current data of Zones to the Peer at `addr`, if still in the Cluster. Runs in
+    /// the pool, as Zones may need to load.
+    pub fn sync_peer(&self, addr: SocketAddr, paths: Vec<Path>) {
+        let peer = match self.peer_at(addr) {
+            Some(peer) => peer.clone(),
+            None => return
+        };
+
+        let app = self.app.clone();
+
+        self.pool.execute(move || {
+            for path in paths {
+                // Includes changes not saved yet
+                let data = app.manager.load(&path).dump();
+
+                peer.send(Arc::new(ClusterMessage::Merge(path, data)));
+            }
+        });
+    }
+
+    /// Request all peers to synchronize Zone, e.g. to replace local data lost to corruption.
+    pub fn recover_zone(&self, path: Path) {
+        self.broadcast(ClusterMessage::SyncZone(path));
+    }
+
+    /// Forwards a command to an owner, see `router`. `reply_to` is dropped if it is unreachable.
+    fn forward(&mut self, owner: Replica, zone: Path, command: Command, reply_to: ReplyTo) {
+        let peer = match self.reachable(&owner) {
+            Some(peer) => peer.clone(),
+            None => return
+        };
+
+        let id = self.next_id;
+
+        self.next_id += 1;
+        self.app.stats.cluster.commands_forwarded.increment();
+
+        if command.call == Call::Bind {
+            self.binds.insert(id, (owner.peer_addr(), reply_to.listener));
+        }
+
+        self.forwarded.insert(id, Forwarded { reply: reply_to.reply, since: Instant::now() });
+
+        peer.send(Arc::new(ClusterMessage::Dispatch(Forward {
+            from: self.id.peer_addr(),
+            id: id,
+            zone: zone,
+            call: command.call,
+            path: command.path,
+            params: serde_json::to_string(&command.params).unwrap(),
+            timestamp: command.timestamp
+        })));
+    }
+
+    fn forward_result(&mut self, id: u64, result: ZoneResult) {
+        if result.error.is_some() {
+            self.binds.remove(&id);
+        }
+
+        if let Some(forwarded) = self.forwarded.remove(&id) {
+            forwarded.reply.send(result).is_ok(); // ignore if caller gave up
+        }
+    }
+
+    /// Fails forwarded commands that were not replied to in time.
+    fn expire_forwarded(&mut self) {
+        let timeout = Duration::from_secs(FORWARD_TIMEOUT_SECS);
+        let expired: Vec<_> = self.forwarded.iter().filter(|&(_, f)| f.since.elapsed() >= timeout).map(|(&id, _)| id).collect();
+
+        for id in expired {
+            self.forward_result(id, ZoneResult { error: Some("Owner did not reply".into()), ..Default::default() });
+        }
+    }
+
+    /// Merges delegated data that was not acked in time locally, where the Zone saves it while held
+    /// and replicates it to the owners.
+    fn expire_delegated(&mut self) {
+        let timeout = Duration::from_secs(FORWARD_TIMEOUT_SECS);
+        let expired: Vec<_> = self.delegated.iter().filter(|&(_, d)| d.since.elapsed() >= timeout).map(|(&id, _)| id).collect();
+
+        for id in expired {
+            if let Some(delegated) = self.delegated.remove(&id) {
+                println!("Delegated data for {:?} was not acked, merging locally", delegated.path);
+
+                self.app.manager.load(&delegated.path).merge_held(delegated.tree, true, Some(delegated.hold));
+            }
+        }
+    }
+
+    /// Relays an update of a forwarded bind, unbinding once the client is gone.
+    fn notify(&mut self, id: u64, update: String) {
+        let owner = match self.binds.get(&id) {
+            Some(&(owner, ref listener)) if listener.send(update).is_err() => owner,
+            _ => return
+        };
+
+        self.binds.remove(&id);
+
+        if let Some(peer) = self.peer_at(owner) {
+            peer.send(Arc::new(ClusterMessage::Unbind(self.id.peer_addr(), id)));
+        }
+    }
+
+    /// Serves a command forwarded by a Peer, relaying updates of binds back to it. Writes are
+    /// logged to the WAL like client commands, see `client::process`.
+    fn serve(&mut self, forward: Forward) {
+        self.app.stats.cluster.commands_served.increment();
+
+        let (tx, rx) = mpsc::channel();
+
+        if forward.call == Call::Bind {
+            self.relay.send(RelayCall::Add(forward.from, forward.id, rx)).is_ok();
+        }
+
+        self.respond(forward.from, move |app, _| {
+            let command = Command {
+                id: 0,
+                call: forward.call,
+                path: forward.path,
+                params: serde_json::from_str(&forward.params).unwrap_or(Value::Null),
+                timestamp: forward.timestamp
+            };
+
+            vec![ClusterMessage::Result(forward.id, serve_command(app, &forward.zone, command, &tx))]
+        });
+    }
+
+    /// Sends delegated data to the first reachable of `owners`, binding listeners there. Merged
+    /// locally if none is reachable.
+    ///
+    /// Data held for the WAL is sent as `DelegateHeld`, keeping `hold` until the owner acks that
+    /// it saved the data, and merged locally if no ack arrives in time. Peers of protocol versions
+    /// before `DELEGATE_ACK_VERSION` are sent `Delegate`, releasing `hold` once sent.
+    fn delegate(&mut self, owners: Vec<Replica>, path: Path, tree: NodeTree, listeners: Vec<RListener>, hold: Option<Hold>) {
+        let (owner, peer) = match owners.iter().filter_map(|owner| self.reachable(owner).map(|peer| (owner, peer))).next() {
+            Some((owner, peer)) => (owner.clone(), peer.clone()),
+            None => {
+                let zone = self.app.manager.load(&path);
+
+                match listeners.is_empty() {
+                    true => zone.merge_held(tree, true, hold),
+                    false => zone.merge_with_listeners(tree, listeners, hold)
+                }
+
+                return;
+            }
+        };
+
+        match hold {
+            Some(hold) if peer.version() >= DELEGATE_ACK_VERSION => {
+                let id = self.next_id;
+
+                self.next_id += 1;
+
+                peer.send(Arc::new(ClusterMessage::DelegateHeld(self.id.peer_addr(), id, path.clone(), tree.clone())));
+                self.delegated.insert(id, Delegated { path: path.clone(), tree: tree, hold: hold, since: Instant::now() });
+            },
+            _ => peer.send(Arc::new(ClusterMessage::Delegate(path.clone(), tree)))
+        }
+
+        for listener in listeners {
+            // Listeners already have the data, so the result is not needed
+            let (reply, _) = mpsc::channel();
+            let command = Command::new(0, Call::Bind, listener.path, Value::Null);
+
+            self.forward(owner.clone(), path.clone(), command, ReplyTo { reply: reply, listener: listener.tx });
+        }
+    }
+
+    /// Peer of `replica`, unless unknown, dead, or too old for anything but replication.
+    fn reachable(&self, replica: &Replica) -> Option<&Peer> {
+        match self.peers.get(replica) {
+            Some(peer) if peer.version() < HANDSHAKE_VERSION => None,
+            Some(peer) if peer.status(replica).health != Health::Dead => Some(peer),
+            _ => None
+        }
+    }
+
+    /// Peer listening at `addr`.
+    fn peer_at(&self, addr: SocketAddr) -> Option<&Peer> {
+        self.peers.iter().find(|&(replica, _)| replica.peer_addr() == addr).map(|(_, peer)| peer)
+    }
+
+    /// Waits up to `timeout` for all Peers to send queued messages.
+    fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let flushing: Vec<_> = self.peers.values().map(|peer| peer.flush()).collect();
+
+        flushing.iter().all(|rx| {
+            let now = Instant::now();
+
+            now < deadline && rx.recv_timeout(deadline - now).is_ok()
+        })
+    }
+
     fn broadcast(&self, message: ClusterMessage) {
         self.app.stats.cluster.broadcast.increment();
 
@@ -250,95 +940,516 @@ impl Cluster {
 /// handled by Server
 impl Peer {
     /// Start a new Peer "process".
-    pub fn spawn(addr: SocketAddr) -> Peer {
+    pub fn spawn(addr: SocketAddr, compression: Compression, cluster: ClusterHandle, stats: Arc<Stats>) -> Peer {
         let (tx, rx) = channel();
+        let detector = Arc::new(Mutex::new(Detector::new()));
+        let overflowed = Arc::new(Mutex::new(HashSet::new()));
+        let queued = Arc::new(AtomicUsize::new(0));
+        let version = Arc::new(AtomicUsize::new(PROTOCOL_VERSION as usize));
+
+        stats.cluster.peers_suspect.increment();
 
         let mut state = PeerState {
             addr: addr,
-            pending: None,
+            backoff: Duration::from_millis(MIN_BACKOFF_MS),
+            cluster: cluster,
+            compression: compression,
+            acked_at: Instant::now(),
+            acks: false,
+            detector: detector.clone(),
+            last_heartbeat: Instant::now(),
+            negotiated: Compression::None,
+            overflowed: overflowed.clone(),
+            queue: VecDeque::new(),
+            queued: queued.clone(),
+            queued_at: None,
+            retry_at: None,
             stream: None,
-            rx: rx
+            rx: rx,
+            stats: stats.clone(),
+            version: version.clone()
         };
 
         thread("Peer").spawn(move || {
-            state.connect();
             state.message_loop();
+
+            // Peer was removed
+            let health = state.detector.lock().unwrap().health;
+
+            state.health_stat(health).decrement();
         }).expect("Peer spawn failed");
 
         Peer {
-            tx: tx
+            addr: addr,
+            detector: detector,
+            full: Arc::new(AtomicBool::new(false)),
+            overflowed: overflowed,
+            queued: queued,
+            stats: stats,
+            tx: tx,
+            version: version
         }
 
     }
 
-    /// Sends a message to this remote Peer
+    /// Sends a message to this remote Peer. Never blocks: while the Peer process does not keep up
+    /// and `MAX_QUEUE` droppable messages wait in its channel, further ones are dropped like in
+    /// `PeerState::check_overflow`. Other messages are always sent.
     pub fn send(&self, msg: Arc<ClusterMessage>) {
-        self.tx.send(msg).expect("Peer channel disconnected");
-    }
-}
+        if droppable(&msg) {
+            if self.queued.load(Ordering::SeqCst) >= MAX_QUEUE {
+                if let ClusterMessage::Merge(ref path, _) = *msg {
+                    self.overflowed.lock().unwrap().insert(path.clone());
+                }
 
-impl PeerState {
-    fn check_overflow(&self) {
-        // TODO
-    }
+                if ! self.full.swap(true, Ordering::SeqCst) {
+                    println!("Peer at {} overflowed, dropping messages", self.addr);
+                    self.stats.cluster.peer_overflows.increment();
+                }
 
-    fn connect(&mut self) {
-        if self.stream.is_none() {
-            println!("Connecting to peer at {}...", self.addr);
-            self.stream = TcpStream::connect(self.addr).ok();
+                self.stats.cluster.peer_dropped.increment();
+                return;
+            }
+
+            self.queued.fetch_add(1, Ordering::SeqCst);
+            self.full.store(false, Ordering::SeqCst);
         }
-    }
 
-    fn message_loop(&mut self) {
-        loop {
-            self.check_overflow();
+        self.tx.send(PeerCall::Send(msg)).expect("Peer channel disconnected");
+    }
 
-            let msg = match self.pending.take() {
-                Some(m) => m,
-                None => {
-                    match self.rx.recv() {
-                        Ok(m) => m,
-                        Err(_) => return
-                    }
-                }
-            };
+    /// Returns a channel that receives once all messages sent so far are written to the Peer.
+    pub fn flush(&self) -> Receiver<()> {
+        let (tx, rx) = channel();
 
-            self.connect();
+        self.tx.send(PeerCall::Flush(tx)).expect("Peer channel disconnected");
+        rx
+    }
 
-            self.stream = match self.stream {
-                Some(ref mut stream) => {
-                    let limit = bincode::Infinite;
+    /// Whether the Peer is judged dead, see `Health`.
+    pub fn is_dead(&self) -> bool {
+        self.detector.lock().unwrap().health == Health::Dead
+    }
 
-                    match bincode::serialize_into(stream, &*msg, limit) {
-                        Ok(_) => continue,
-                        Err(e) => println!("Peer outgoing serialization failed: {}", e)
-                    };
+    /// Protocol version of the Peer, assumed current until connected.
+    pub fn version(&self) -> u32 {
+        self.version.load(Ordering::SeqCst) as u32
+    }
 
-                    None
-                },
-                None => None
-            };
+    /// Current health of the connection to this Peer.
+    pub fn status(&self, replica: &Replica) -> PeerStatus {
+        let detector = self.detector.lock().unwrap();
 
-            self.pending = Some(msg); // Message not sent, retry later
+        PeerStatus {
+            replica: replica.clone(),
+            health: detector.health,
+            failures: detector.failures,
+            last_contact_ms: detector.last_contact.map(|t| millis(t.elapsed()))
         }
     }
+}
 
+impl fmt::Debug for ReplyTo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ReplyTo")
+    }
 }
 
-impl Server {
-    pub fn spawn(addr: &SocketAddr, cluster: ClusterHandle) -> Server {
-        let listener = TcpListener::bind(addr).expect("cluster::Server cannot bind");
+impl Detector {
+    fn new() -> Detector {
+        Detector {
+            health: Health::Suspect,
+            failures: 0,
+            first_failure: None,
+            last_contact: None,
+            last_ack: None
+        }
+    }
+}
 
-        println!("Cluster Listening on: {}", addr);
+impl PeerState {
+    /// Bounds memory used for an unreachable or slow Peer. Once more than `MAX_QUEUE` calls are
+    /// queued, queued droppable messages are dropped and the Zones they changed are remembered, to
+    /// be synced after the next successful write. Flushes and other messages stay queued, in order.
+    fn check_overflow(&mut self) {
+        if self.queue.len() <= MAX_QUEUE {
+            return;
+        }
 
-        thread("cluster::Server").spawn(move || {
-            Server::accept_loop(cluster, listener);
-        }).expect("Could not start cluster::Server");
+        let mut dropped = 0;
+        let mut overflowed = self.overflowed.lock().unwrap();
+
+        self.queue.retain(|call| {
+            match *call {
+                PeerCall::Send(ref msg) if droppable(msg) => if let ClusterMessage::Merge(ref path, _) = **msg {
+                    overflowed.insert(path.clone());
+                },
+                _ => return true
+            }
+
+            dropped += 1;
+            false
+        });
+
+        if dropped == 0 {
+            return;
+        }
+
+        println!("Peer at {} overflowed, dropped {} messages", self.addr, dropped);
+
+        self.stats.cluster.peer_overflows.increment();
+        self.stats.cluster.peer_dropped.add(dropped);
+    }
+
+    fn connect(&mut self) {
+        if self.stream.is_none() {
+            println!("Connecting to peer at {}...", self.addr);
+
+            let mut stream = match TcpStream::connect_timeout(&self.addr, Duration::from_millis(CONNECT_TIMEOUT_MS)) {
+                Ok(stream) => stream,
+                Err(_) => return
+            };
+
+            match handshake(&mut stream, self.compression) {
+                Ok((compression, version)) => {
+                    stream.set_write_timeout(Some(Duration::from_millis(WRITE_TIMEOUT_MS))).is_ok();
+
+                    self.acks = version >= ACK_VERSION;
+                    self.acked_at = Instant::now();
+
+                    if self.acks {
+                        match stream.try_clone() {
+                            Ok(reader) => read_acks(reader, self.detector.clone()),
+                            Err(e) => return println!("Peer ack reader failed: {}", e)
+                        }
+
+                        // Alive once acked
+                        self.heartbeat();
+                    }
+
+                    self.negotiated = compression;
+                    self.version.store(version as usize, Ordering::SeqCst);
+                    self.stream = Some(stream);
+                },
+                Err(e) => println!("Peer handshake failed: {}", e)
+            }
+        }
+    }
+
+    /// Sends queued messages, reconnecting with exponential backoff when the Peer is unreachable.
+    /// Heartbeats are sent at intervals, so that failures are noticed without traffic.
+    fn message_loop(&mut self) {
+        loop {
+            match self.rx.recv_timeout(self.wait_time()) {
+                Ok(call) => self.receive(call),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return
+            }
+
+            while let Ok(call) = self.rx.try_recv() {
+                self.receive(call);
+            }
+
+            self.check_overflow();
+
+            if self.retry_at.map_or(false, |t| Instant::now() < t) {
+                continue; // backing off
+            }
+
+            let now = Instant::now();
+
+            if ! self.queue.is_empty() && now < self.batch_at(now) {
+                continue; // collecting a batch
+            }
+
+            self.connect();
+
+            if self.stream.is_none() {
+                self.failed();
+                continue;
+            }
+
+            if self.acks && self.last_heartbeat.elapsed() >= Duration::from_millis(HEARTBEAT_INTERVAL_MS) {
+                self.heartbeat();
+            }
+
+            self.send_queued();
+            self.check_acks();
+        }
+    }
+
+    fn heartbeat(&mut self) {
+        self.stats.cluster.heartbeats.increment();
+        self.last_heartbeat = Instant::now();
+        self.push(PeerCall::Send(Arc::new(ClusterMessage::Heartbeat)));
+    }
+
+    /// Records acks that arrived since the last check, and fails the connection once none did for
+    /// `ACK_TIMEOUT_MS`.
+    fn check_acks(&mut self) {
+        if ! self.acks || self.stream.is_none() {
+            return;
+        }
+
+        let last_ack = self.detector.lock().unwrap().last_ack;
+
+        match last_ack {
+            Some(acked_at) if acked_at > self.acked_at => {
+                self.acked_at = acked_at;
+                self.contacted();
+            },
+            _ if self.acked_at.elapsed() >= Duration::from_millis(ACK_TIMEOUT_MS) => {
+                println!("Peer at {} did not ack heartbeats", self.addr);
+
+                self.disconnect();
+                self.failed();
+            },
+            _ => ()
+        }
+    }
+
+    /// Closes the connection, which also ends its ack reader.
+    fn disconnect(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            stream.shutdown(Shutdown::Both).is_ok();
+        }
+    }
+
+    /// Queues a call from the channel, see `Peer::send`.
+    fn receive(&mut self, call: PeerCall) {
+        if let PeerCall::Send(ref msg) = call {
+            if droppable(msg) {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        self.push(call);
+    }
+
+    fn push(&mut self, call: PeerCall) {
+        if self.queue.is_empty() {
+            self.queued_at = Some(Instant::now());
+        }
+
+        self.queue.push_back(call);
+    }
+
+    /// Time when queued messages are sent as a batch, `now` if they are due.
+    fn batch_at(&self, now: Instant) -> Instant {
+        match self.queued_at {
+            Some(_) if self.queue.len() >= MAX_BATCH => now,
+            Some(queued_at) => queued_at + Duration::from_millis(BATCH_WINDOW_MS),
+            None => now
+        }
+    }
+
+    /// Time until the next reconnection attempt, batch of queued messages, heartbeat or check for
+    /// an ack.
+    fn wait_time(&self) -> Duration {
+        let now = Instant::now();
+
+        let until = match self.retry_at {
+            Some(retry_at) => retry_at,
+            None if ! self.queue.is_empty() => self.batch_at(now),
+            None => self.last_heartbeat + Duration::from_millis(HEARTBEAT_INTERVAL_MS)
+        };
+
+        let until = match self.acks && self.stream.is_some() && self.last_heartbeat > self.acked_at {
+            true => cmp::min(until, now + Duration::from_millis(ACK_POLL_MS)),
+            false => until
+        };
+
+        match until > now {
+            true => until - now,
+            false => Duration::from_secs(0)
+        }
+    }
+
+    /// Sends queued messages in batches, each serialized into one buffer and written at once.
+    fn send_queued(&mut self) {
+        while ! self.queue.is_empty() {
+            let batch = self.next_batch();
+
+            if ! batch.is_empty() {
+                let mut buffer = vec![];
+                let mut result = Ok(());
+
+                for msg in &batch {
+                    result = write_message(&mut buffer, msg, self.negotiated, &self.stats);
+
+                    if result.is_err() {
+                        break;
+                    }
+                }
+
+                let result = result.and_then(|_| match self.stream {
+                    Some(ref mut stream) => stream.write_all(&buffer).map_err(|e| e.into()),
+                    None => unreachable!()
+                });
+
+                if let Err(e) = result {
+                    println!("Peer outgoing serialization failed: {}", e);
+
+                    // Batch not sent, retry later. Merges may arrive twice, which is harmless.
+                    for msg in batch.into_iter().rev() {
+                        self.queue.push_front(PeerCall::Send(msg));
+                    }
+
+                    self.disconnect();
+                    self.failed();
+
+                    return;
+                }
+
+                self.stats.cluster.peer_batches.increment();
+                self.stats.cluster.peer_batch_messages.add(batch.len());
+                self.stats.cluster.peer_batch_max.max(batch.len());
+
+                if ! self.acks {
+                    self.contacted();
+                }
+            }
+
+            // All earlier messages were written
+            while let Some(&PeerCall::Flush(_)) = self.queue.front() {
+                if let Some(PeerCall::Flush(reply)) = self.queue.pop_front() {
+                    reply.send(()).is_ok(); // ignore if caller gave up
+                }
+            }
+        }
+
+        self.queued_at = None;
+    }
+
+    /// Takes up to `MAX_BATCH` queued messages, until the next flush. Merges for the same Zone
+    /// are combined into the first, as merging is commutative. Messages the Peer cannot decode are
+    /// skipped, e.g. those queued before connecting showed it is of an older version.
+    fn next_batch(&mut self) -> Vec<Arc<ClusterMessage>> {
+        let mut batch: Vec<Arc<ClusterMessage>> = vec![];
+        let mut merges: HashMap<Path, usize> = HashMap::new(); // Zone path to index in batch
+        let version = self.version.load(Ordering::SeqCst) as u32;
+
+        while batch.len() < MAX_BATCH {
+            let msg = match self.queue.pop_front() {
+                Some(PeerCall::Send(msg)) => msg,
+                Some(flush) => {
+                    self.queue.push_front(flush);
+                    break;
+                },
+                None => break
+            };
+
+            if min_version(&msg) > version {
+                continue;
+            }
+
+            if let ClusterMessage::Merge(ref path, ref diff) = *msg {
+                if let Some(&i) = merges.get(path) {
+                    let mut tree = match *batch[i] {
+                        ClusterMessage::Merge(_, ref tree) => tree.clone(),
+                        _ => unreachable!()
+                    };
+
+                    tree.merge(&mut diff.clone());
+                    batch[i] = Arc::new(ClusterMessage::Merge(path.clone(), tree));
+
+                    self.stats.cluster.peer_coalesced.increment();
+                    continue;
+                }
+
+                merges.insert(path.clone(), batch.len());
+            }
+
+            batch.push(msg.clone());
+        }
+
+        batch
+    }
+
+    /// Records an ack, or a successful write to a Peer that does not ack. Anti-entropy is started
+    /// once reachable again.
+    fn contacted(&mut self) {
+        let reconnected = {
+            let mut detector = self.detector.lock().unwrap();
+
+            detector.failures = 0;
+            detector.first_failure = None;
+            detector.last_contact = Some(Instant::now());
+
+            detector.health != Health::Alive
+        };
+
+        self.backoff = Duration::from_millis(MIN_BACKOFF_MS);
+        self.retry_at = None;
+        self.set_health(Health::Alive);
+
+        if reconnected {
+            self.cluster.send(ClusterCall::Reconnected(self.addr));
+        }
+
+        let overflowed: Vec<_> = self.overflowed.lock().unwrap().drain().collect();
+
+        if ! overflowed.is_empty() {
+            self.cluster.sync_peer(self.addr, overflowed);
+        }
+    }
+
+    /// Records a failed connection or write, and schedules a reconnection attempt. The Peer is
+    /// suspected at first, and declared dead once failing for `DEAD_AFTER_MS`.
+    fn failed(&mut self) {
+        let dead = {
+            let mut detector = self.detector.lock().unwrap();
+            let first_failure = *detector.first_failure.get_or_insert(Instant::now());
+
+            detector.failures += 1;
+
+            first_failure.elapsed() >= Duration::from_millis(DEAD_AFTER_MS)
+        };
+
+        self.stats.cluster.peer_failures.increment();
+        self.set_health(if dead { Health::Dead } else { Health::Suspect });
+
+        self.retry_at = Some(Instant::now() + self.backoff);
+        self.backoff = cmp::min(self.backoff * 2, Duration::from_millis(MAX_BACKOFF_MS));
+    }
+
+    /// Updates health, keeping counts in `ClusterStats` current.
+    fn set_health(&self, health: Health) {
+        let mut detector = self.detector.lock().unwrap();
+
+        if detector.health != health {
+            println!("Peer at {} is {:?}", self.addr, health);
+
+            self.health_stat(detector.health).decrement();
+            self.health_stat(health).increment();
+
+            detector.health = health;
+        }
+    }
+
+    fn health_stat(&self, health: Health) -> &Stat {
+        match health {
+            Health::Alive => &self.stats.cluster.peers_alive,
+            Health::Suspect => &self.stats.cluster.peers_suspect,
+            Health::Dead => &self.stats.cluster.peers_dead
+        }
+    }
+}
+
+impl Server {
+    pub fn spawn(addr: &SocketAddr, cluster: ClusterHandle, compression: Compression) -> Server {
+        let listener = TcpListener::bind(addr).expect("cluster::Server cannot bind");
+
+        println!("Cluster Listening on: {}", addr);
+
+        thread("cluster::Server").spawn(move || {
+            Server::accept_loop(cluster, listener, compression);
+        }).expect("Could not start cluster::Server");
 
         Server {}
     }
 
-    fn accept_loop(cluster: ClusterHandle, listener: TcpListener) {
+    fn accept_loop(cluster: ClusterHandle, listener: TcpListener, compression: Compression) {
         loop {
             let stream = listener.accept();
 
@@ -350,7 +1461,7 @@ impl Server {
                     let cluster = cluster.clone();
 
                     thread("cluster::Peer.incoming").spawn(move || {
-                        Server::handle_peer(cluster, stream);
+                        Server::handle_peer(cluster, stream, compression);
                     }).expect("Could not start cluster::Peer.incoming");
                 },
                 Err(e) => {
@@ -361,21 +1472,286 @@ impl Server {
         }
     }
 
-    fn handle_peer(cluster: ClusterHandle, mut stream: TcpStream) {
-        loop {
-            let limit = bincode::Bounded(10 * 1024 * 1024);
+    fn handle_peer(cluster: ClusterHandle, mut stream: TcpStream, compression: Compression) {
+        let (compression, version) = match accept_handshake(&mut stream, compression) {
+            Err(e) => {
+                println!("Peer handshake failed: {}", e);
+                return;
+            },
+            Ok((compression, version, first)) => {
+                if let Some(msg) = first {
+                    cluster.handle_cluster_message(msg);
+                }
 
-            match bincode::deserialize_from(&mut stream, limit) {
+                (compression, version)
+            }
+        };
+
+        stream.set_write_timeout(Some(Duration::from_millis(WRITE_TIMEOUT_MS))).is_ok();
+
+        loop {
+            match read_message(&mut stream, compression) {
                 Err(e) => {
                     println!("Bad message {:?}", e);
                     return;
                 },
+                Ok(ClusterMessage::Heartbeat) if version >= ACK_VERSION => {
+                    // Acked on the same connection, uncompressed
+                    if let Err(e) = bincode::serialize_into(&mut stream, &ClusterMessage::Heartbeat, bincode::Infinite) {
+                        println!("Peer ack failed: {}", e);
+                        return;
+                    }
+
+                    cluster.handle_cluster_message(ClusterMessage::Heartbeat);
+                },
                 Ok(msg) => cluster.handle_cluster_message(msg)
             };
         }
     }
 }
 
+/// Exchanges `Handshake`s on a new outgoing Peer connection, returning the compression both sides
+/// support and the remote protocol version, 0 without a handshake.
+fn handshake(stream: &mut TcpStream, compression: Compression) -> bincode::Result<(Compression, u32)> {
+    let mut magic = [0; 4];
+
+    try!(stream.set_read_timeout(Some(Duration::from_millis(LEGACY_TIMEOUT_MS))));
+
+    match stream.read_exact(&mut magic) {
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            println!("Peer sent no handshake, assuming an older version");
+            try!(stream.set_read_timeout(None));
+
+            return Ok((Compression::None, 0));
+        },
+        result => try!(result)
+    }
+
+    if magic != HANDSHAKE_MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "unexpected handshake").into());
+    }
+
+    try!(stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS))));
+
+    let remote = try!(read_handshake(stream));
+
+    try!(write_handshake(stream, compression));
+    try!(stream.set_read_timeout(None));
+
+    Ok((compression.negotiate(remote.compression), remote.version))
+}
+
+/// Exchanges `Handshake`s on a new incoming Peer connection, returning the compression both sides
+/// support and the remote protocol version. For Peers without handshakes, the version is 0 and
+/// their first message is returned too.
+fn accept_handshake(stream: &mut TcpStream, compression: Compression) -> bincode::Result<(Compression, u32, Option<ClusterMessage>)> {
+    let mut magic = [0; 4];
+
+    try!(write_handshake(stream, compression));
+    try!(stream.read_exact(&mut magic));
+
+    if magic != HANDSHAKE_MAGIC {
+        let first = try!(bincode::deserialize_from(&mut (&magic[..]).chain(&mut *stream), bincode::Bounded(MAX_MESSAGE as u64)));
+
+        return Ok((Compression::None, 0, Some(first)));
+    }
+
+    try!(stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS))));
+
+    let remote = try!(read_handshake(stream));
+
+    try!(stream.set_read_timeout(None));
+
+    Ok((compression.negotiate(remote.compression), remote.version, None))
+}
+
+fn write_handshake(stream: &mut TcpStream, compression: Compression) -> bincode::Result<()> {
+    let handshake = Handshake { version: PROTOCOL_VERSION, compression: compression };
+    let serialized = try!(bincode::serialize(&handshake, bincode::Infinite));
+
+    try!(stream.write_all(&HANDSHAKE_MAGIC));
+
+    bincode::serialize_into(stream, &serialized, bincode::Infinite)
+}
+
+fn read_handshake(stream: &mut TcpStream) -> bincode::Result<Handshake> {
+    let serialized: Vec<u8> = try!(bincode::deserialize_from(stream, bincode::Bounded(1024)));
+
+    bincode::deserialize(&serialized)
+}
+
+/// Writes `msg` for a Peer. Compressed messages are sent as serialized byte arrays.
+fn write_message<W: Write>(writer: &mut W, msg: &ClusterMessage, compression: Compression, stats: &Stats) -> bincode::Result<()> {
+    let limit = bincode::Infinite;
+
+    match compression {
+        Compression::None => bincode::serialize_into(writer, msg, limit),
+        Compression::Deflate => {
+            let serialized = try!(bincode::serialize(msg, limit));
+            let compressed = compression::compress(&serialized);
+
+            stats.cluster.compress_in_bytes.add(serialized.len());
+            stats.cluster.compress_out_bytes.add(compressed.len());
+
+            bincode::serialize_into(writer, &compressed, limit)
+        }
+    }
+}
+
+fn read_message(stream: &mut TcpStream, compression: Compression) -> bincode::Result<ClusterMessage> {
+    let limit = bincode::Bounded(MAX_MESSAGE as u64);
+
+    match compression {
+        Compression::None => bincode::deserialize_from(stream, limit),
+        Compression::Deflate => {
+            let compressed: Vec<u8> = try!(bincode::deserialize_from(stream, limit));
+            let serialized = try!(compression::decompress(&compressed, MAX_MESSAGE));
+
+            bincode::deserialize(&serialized)
+        }
+    }
+}
+
+/// Records acks of heartbeats arriving on an outgoing Peer connection, until it is closed.
+fn read_acks(mut stream: TcpStream, detector: Arc<Mutex<Detector>>) {
+    thread("Peer.acks").spawn(move || {
+        while let Ok(ClusterMessage::Heartbeat) = read_message(&mut stream, Compression::None) {
+            detector.lock().unwrap().last_ack = Some(Instant::now());
+        }
+    }).expect("Peer ack reader spawn failed");
+}
+
+/// Dispatches a forwarded `command` to Zone at `zone`, logging writes to the WAL first.
+fn serve_command(app: &AppHandle, zone: &Path, command: Command, listener: &mpsc::Sender<String>) -> ZoneResult {
+    let write = command.call == Call::Write || command.call == Call::Kill;
+
+    // Zones may already be saved for the last time
+    if write && app.stopping.load(Ordering::SeqCst) {
+        return ZoneResult { error: Some("Shutting down".into()), ..Default::default() };
+    }
+
+    // Logged with the absolute path, as replayed by `wal::replay`
+    let logged = match app.wal {
+        Some(ref wal) if write => {
+            let mut path = zone.clone();
+            path.append(&mut command.path.clone());
+
+            match wal.append(&Command { path: path, ..command.clone() }) {
+                None => return ZoneResult { error: Some("Write failed".into()), ..Default::default() },
+                seq => seq
+            }
+        },
+        _ => None
+    };
+
+    let result = app.manager.load(zone).dispatch(command, listener);
+
+    if let (&Some(ref wal), Some(seq)) = (&app.wal, logged) {
+        match result.error {
+            Some(_) => wal.failed(seq),
+            None => wal.done(seq)
+        }
+    }
+
+    result
+}
+
+/// Used for dispatching calls to the relay via message passing.
+enum RelayCall {
+    /// Relays updates of the bind with id served for the Peer at address
+    Add(SocketAddr, u64, mpsc::Receiver<String>),
+    /// Stops relaying a bind
+    Remove(SocketAddr, u64),
+    /// Stops relaying all binds served for the Peer at address, e.g. once it left
+    RemovePeer(SocketAddr)
+}
+
+/// Spawns one thread relaying updates of all binds served for Peers, polling them every
+/// `RELAY_POLL_MS`. Dropping a bind's receiver removes its listener from the Zone on the next
+/// update. Stops once the returned sender is dropped.
+fn spawn_relay(cluster: ClusterHandle) -> Sender<RelayCall> {
+    let (tx, rx) = channel();
+
+    thread("Cluster.relay").spawn(move || {
+        let mut relays: HashMap<(SocketAddr, u64), mpsc::Receiver<String>> = HashMap::new();
+
+        loop {
+            match rx.recv_timeout(Duration::from_millis(RELAY_POLL_MS)) {
+                Ok(call) => {
+                    update_relays(&mut relays, call);
+
+                    while let Ok(call) = rx.try_recv() {
+                        update_relays(&mut relays, call);
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return
+            }
+
+            relays.retain(|&(to, id), updates| loop {
+                match updates.try_recv() {
+                    Ok(update) => cluster.send(ClusterCall::SendTo(to, ClusterMessage::Notify(id, update))),
+                    Err(mpsc::TryRecvError::Empty) => return true,
+                    Err(mpsc::TryRecvError::Disconnected) => return false
+                }
+            });
+        }
+    }).expect("Cluster relay spawn failed");
+
+    tx
+}
+
+fn update_relays(relays: &mut HashMap<(SocketAddr, u64), mpsc::Receiver<String>>, call: RelayCall) {
+    match call {
+        RelayCall::Add(to, id, updates) => {
+            relays.insert((to, id), updates);
+        },
+        RelayCall::Remove(to, id) => {
+            relays.remove(&(to, id));
+        },
+        RelayCall::RemovePeer(peer) => relays.retain(|&(to, _), _| to != peer)
+    }
+}
+
+/// Whether `msg` may be dropped when a Peer overflows. Changes to Zones are synced to the Peer
+/// afterwards, and gossip, heartbeats and anti-entropy are repeated. Delegated data, forwarded
+/// commands and replies to the Peer's own requests could not be recovered, so they are never
+/// dropped; they are only sent to reachable Peers, or in proportion to requests from the Peer.
+fn droppable(msg: &ClusterMessage) -> bool {
+    match *msg {
+        ClusterMessage::Delegate(..) |
+        ClusterMessage::DelegateHeld(..) |
+        ClusterMessage::DelegateAck(_) |
+        ClusterMessage::Dispatch(_) |
+        ClusterMessage::Notify(..) |
+        ClusterMessage::Result(..) |
+        ClusterMessage::Unbind(..) => false,
+        _ => true
+    }
+}
+
+/// First protocol version that can decode `msg`. Variants are encoded by index, so Peers of
+/// older versions cannot decode later ones.
+fn min_version(msg: &ClusterMessage) -> u32 {
+    match *msg {
+        ClusterMessage::Merge(..) | ClusterMessage::Sync => 0,
+        ClusterMessage::Heartbeat => ACK_VERSION,
+        ClusterMessage::DelegateHeld(..) | ClusterMessage::DelegateAck(_) => DELEGATE_ACK_VERSION,
+        _ => HANDSHAKE_VERSION
+    }
+}
+
+fn is_notify(msg: &ClusterMessage) -> bool {
+    match *msg {
+        ClusterMessage::Notify(..) => true,
+        _ => false
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1000000
+}
+
 fn thread(name: &str) -> Builder {
     Builder::new().name(name.into())
 }
@@ -391,11 +1767,322 @@ fn test_cluster() {
 
     let id = "127.0.0.1:1000".parse().unwrap();
     let mut app = app::App::new(id);
-    let mut cluster = Cluster::new(&mut app);
+    let mut cluster = Cluster::new(&mut app, Compression::None);
 
     cluster.add("127.0.0.1:1000".parse().unwrap());
     cluster.add("127.0.0.1:1001".parse().unwrap());
     cluster.add("127.0.0.1:1002".parse().unwrap());
 
     assert_eq!(cluster.replicas, replicas);
+
+    // Members learned by gossip are added, and removed once they leave
+    use membership::MemberState;
+
+    let mut member = Member::new("b".into(), "127.0.0.1:1003".parse().unwrap());
+
+    cluster.handle_cluster_message(ClusterMessage::Gossip(vec![member.clone()]));
+
+    assert!(cluster.replicas.contains(&member.replica));
+
+    member.state = MemberState::Left;
+    cluster.handle_cluster_message(ClusterMessage::Gossip(vec![member.clone()]));
+
+    assert_eq!(cluster.replicas, replicas);
+    assert_eq!(cluster.membership.list().len(), 2);
+}
+
+#[test]
+fn test_peer_compression() {
+    let addr: SocketAddr = "127.0.0.1:14001".parse().unwrap();
+    let channel = ClusterChannel::new();
+    let stats: Arc<Stats> = Default::default();
+
+    Server::spawn(&addr, channel.handle(), Compression::Deflate);
+
+    let peer = Peer::spawn(addr, Compression::Deflate, channel.handle(), stats.clone());
+    let data = NodeTree::default();
+
+    peer.send(Arc::new(ClusterMessage::Merge(path![moo], data)));
+
+    loop {
+        match channel.rx.recv().unwrap() {
+            ClusterCall::HandleClusterMessage(ClusterMessage::Merge(path, _)) => {
+                assert_eq!(path, path![moo]);
+                break;
+            },
+            ClusterCall::Reconnected(_) => (),
+            call => panic!("Unexpected {:?}", call)
+        }
+    }
+
+    assert!(stats.cluster.compress_in_bytes.value() > 0);
+}
+
+#[test]
+fn test_peer_health() {
+    use std::thread;
+
+    let addr: SocketAddr = "127.0.0.1:14031".parse().unwrap();
+    let replica: Replica = "127.0.0.1:13931".parse().unwrap();
+    let channel = ClusterChannel::new();
+    let stats: Arc<Stats> = Default::default();
+
+    // Nothing listening yet
+    let peer = Peer::spawn(addr, Compression::None, channel.handle(), stats.clone());
+    let start = Instant::now();
+
+    while peer.status(&replica).failures == 0 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(peer.status(&replica).health, Health::Suspect);
+    assert_eq!(peer.status(&replica).last_contact_ms, None);
+
+    // Reconnects once listening, and sends heartbeats while idle
+    Server::spawn(&addr, channel.handle(), Compression::None);
+
+    while peer.status(&replica).health != Health::Alive {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(stats.cluster.peers_alive.value(), 1);
+    assert_eq!(stats.cluster.peers_suspect.value(), 0);
+
+    loop {
+        match channel.rx.recv().unwrap() {
+            ClusterCall::HandleClusterMessage(ClusterMessage::Heartbeat) => break,
+            ClusterCall::Reconnected(to) => assert_eq!(to, addr),
+            call => panic!("Unexpected {:?}", call)
+        }
+    }
+}
+
+#[test]
+fn test_peer_overflow() {
+    let addr: SocketAddr = "127.0.0.1:14041".parse().unwrap();
+    let channel = ClusterChannel::new();
+    let stats: Arc<Stats> = Default::default();
+    let message = Arc::new(ClusterMessage::Merge(path![moo], NodeTree::default()));
+
+    // Senders drop messages once the channel is full, without blocking
+    let (tx, rx) = ::std::sync::mpsc::channel();
+
+    let peer = Peer {
+        addr: addr,
+        detector: Arc::new(Mutex::new(Detector::new())),
+        full: Arc::new(AtomicBool::new(false)),
+        overflowed: Arc::new(Mutex::new(HashSet::new())),
+        queued: Arc::new(AtomicUsize::new(0)),
+        stats: stats.clone(),
+        tx: tx,
+        version: Arc::new(AtomicUsize::new(PROTOCOL_VERSION as usize))
+    };
+
+    for _ in 0..MAX_QUEUE {
+        peer.send(message.clone());
+    }
+
+    peer.send(Arc::new(ClusterMessage::Merge(path![cow], NodeTree::default())));
+    peer.send(Arc::new(ClusterMessage::Merge(path![cow], NodeTree::default())));
+
+    assert_eq!(stats.cluster.peer_overflows.value(), 1);
+    assert_eq!(stats.cluster.peer_dropped.value(), 2);
+    assert_eq!(peer.overflowed.lock().unwrap().iter().collect::<Vec<_>>(), vec![&path![cow]]);
+
+    // Delegated data and flushes are never dropped
+    peer.send(Arc::new(ClusterMessage::Delegate(path![cow], NodeTree::default())));
+    let _flushed = peer.flush();
+
+    let calls: Vec<_> = rx.try_iter().skip(MAX_QUEUE).collect();
+
+    assert_eq!(calls.len(), 2);
+    assert!(match calls[0] { PeerCall::Send(ref msg) => match **msg { ClusterMessage::Delegate(ref path, _) => *path == path![cow], _ => false }, _ => false });
+    assert!(match calls[1] { PeerCall::Flush(_) => true, _ => false });
+
+    // Nothing listening yet, so queued messages are dropped too, except delegated data
+    let stats: Arc<Stats> = Default::default();
+    let peer = Peer::spawn(addr, Compression::None, channel.handle(), stats.clone());
+
+    peer.send(Arc::new(ClusterMessage::Delegate(path![cow], NodeTree::default())));
+
+    for _ in 0..MAX_QUEUE {
+        peer.send(message.clone());
+        peer.send(message.clone());
+    }
+
+    let start = Instant::now();
+
+    while stats.cluster.peer_overflows.value() == 0 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert!(stats.cluster.peer_dropped.value() > 0);
+
+    // Delegated data arrives, and dropped Zones are synced once reachable
+    Server::spawn(&addr, channel.handle(), Compression::None);
+
+    let mut delegated = false;
+    let mut synced = false;
+
+    while ! (delegated && synced) {
+        match channel.rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ClusterCall::SyncPeer(to, paths) => {
+                assert_eq!(to, addr);
+                assert_eq!(paths, vec![path![moo]]);
+                synced = true;
+            },
+            ClusterCall::HandleClusterMessage(ClusterMessage::Delegate(path, _)) => {
+                assert_eq!(path, path![cow]);
+                delegated = true;
+            },
+            ClusterCall::HandleClusterMessage(ClusterMessage::Heartbeat) | ClusterCall::Reconnected(_) => (),
+            ClusterCall::HandleClusterMessage(ClusterMessage::Merge(..)) => (),
+            call => panic!("Unexpected {:?}", call)
+        }
+    }
+}
+
+#[test]
+fn test_peer_batching() {
+    use serde_json;
+
+    use node::Node;
+
+    let addr: SocketAddr = "127.0.0.1:14061".parse().unwrap();
+    let channel = ClusterChannel::new();
+    let stats: Arc<Stats> = Default::default();
+
+    // Queued while nothing is listening, then sent as one batch
+    let peer = Peer::spawn(addr, Compression::None, channel.handle(), stats.clone());
+
+    for i in 0..100 {
+        let data = serde_json::from_str(&format!(r#"{{ "cow{}": {} }}"#, i, i)).unwrap();
+
+        peer.send(Arc::new(ClusterMessage::Merge(path![moo], Node::expand(data, 1).noop_vis())));
+    }
+
+    peer.send(Arc::new(ClusterMessage::Merge(path![cow], NodeTree::default())));
+
+    Server::spawn(&addr, channel.handle(), Compression::None);
+
+    let mut merges = vec![];
+
+    while merges.len() < 2 {
+        match channel.rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ClusterCall::HandleClusterMessage(ClusterMessage::Merge(path, tree)) => merges.push((path, tree)),
+            ClusterCall::HandleClusterMessage(ClusterMessage::Heartbeat) | ClusterCall::Reconnected(_) => (),
+            call => panic!("Unexpected {:?}", call)
+        }
+    }
+
+    // Merges for the same Zone were combined, keeping order otherwise
+    assert_eq!(merges[0].0, path![moo]);
+    assert_eq!(merges[0].1.node.len(), 100);
+    assert_eq!(merges[1].0, path![cow]);
+    assert!(peer.flush().recv_timeout(Duration::from_secs(5)).is_ok());
+
+    // Along with the first heartbeat of the connection
+    assert_eq!(stats.cluster.peer_coalesced.value(), 99);
+    assert_eq!(stats.cluster.peer_batch_max.value(), 3);
+}
+
+#[test]
+fn test_peer_legacy() {
+    let addr: SocketAddr = "127.0.0.1:14071".parse().unwrap();
+    let legacy_addr: SocketAddr = "127.0.0.1:14072".parse().unwrap();
+    let channel = ClusterChannel::new();
+    let stats: Arc<Stats> = Default::default();
+
+    // Accepts Peers without a handshake
+    Server::spawn(&addr, channel.handle(), Compression::Deflate);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+
+    bincode::serialize_into(&mut stream, &ClusterMessage::Sync, bincode::Infinite).unwrap();
+
+    match channel.rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+        ClusterCall::HandleClusterMessage(ClusterMessage::Sync) => (),
+        call => panic!("Unexpected {:?}", call)
+    }
+
+    // Sends Peers without a handshake only uncompressed messages they can decode
+    let listener = TcpListener::bind(legacy_addr).unwrap();
+    let peer = Peer::spawn(legacy_addr, Compression::Deflate, channel.handle(), stats.clone());
+    let forward = Forward {
+        from: addr,
+        id: 1,
+        zone: path![moo],
+        call: Call::Read,
+        path: Path::empty(),
+        params: "null".into(),
+        timestamp: 0
+    };
+
+    peer.send(Arc::new(ClusterMessage::Gossip(vec![])));
+    peer.send(Arc::new(ClusterMessage::Digests(addr, vec![])));
+    peer.send(Arc::new(ClusterMessage::Dispatch(forward)));
+    peer.send(Arc::new(ClusterMessage::Sync));
+
+    let (mut stream, _) = listener.accept().unwrap();
+
+    // Heartbeats would be due meanwhile
+    thread::sleep(Duration::from_millis(LEGACY_TIMEOUT_MS + 2 * HEARTBEAT_INTERVAL_MS));
+    peer.send(Arc::new(ClusterMessage::Sync));
+
+    for _ in 0..2 {
+        match bincode::deserialize_from(&mut stream, bincode::Infinite).unwrap() {
+            ClusterMessage::Sync => (),
+            msg => panic!("Unexpected {:?}", msg)
+        }
+    }
+
+    stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+
+    let next: Result<ClusterMessage, _> = bincode::deserialize_from(&mut stream, bincode::Infinite);
+
+    assert!(next.is_err());
+    assert_eq!(stats.cluster.compress_in_bytes.value(), 0);
+}
+
+#[test]
+fn test_peer_acks() {
+    let addr: SocketAddr = "127.0.0.1:14081".parse().unwrap();
+    let replica: Replica = "127.0.0.1:13981".parse().unwrap();
+    let channel = ClusterChannel::new();
+    let stats: Arc<Stats> = Default::default();
+
+    // Takes messages, but never acks
+    let listener = TcpListener::bind(addr).unwrap();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+
+            thread::spawn(move || {
+                let mut magic = [0; 4];
+
+                write_handshake(&mut stream, Compression::None).unwrap();
+                stream.read_exact(&mut magic).unwrap();
+                read_handshake(&mut stream).unwrap();
+
+                while read_message(&mut stream, Compression::None).is_ok() {}
+            });
+        }
+    });
+
+    let peer = Peer::spawn(addr, Compression::None, channel.handle(), stats.clone());
+    let start = Instant::now();
+
+    while peer.status(&replica).failures == 0 {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(peer.status(&replica).health != Health::Alive);
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert!(start.elapsed() >= Duration::from_millis(ACK_TIMEOUT_MS));
+    assert_eq!(peer.status(&replica).health, Health::Suspect);
+    assert!(stats.cluster.heartbeats.value() >= 2);
 }
@@ -1,5 +1,8 @@
 //! Represents a command sent by a client
 
+use std::fmt;
+use std::str::FromStr;
+
 use serde_json;
 use serde_json::Value;
 use time;
@@ -15,8 +18,9 @@ pub struct Command {
     pub timestamp: u64
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 pub enum Call {
+    Auth,
     Bind,
     Kill,
     Read,
@@ -24,6 +28,17 @@ pub enum Call {
 }
 
 impl Command {
+    /// Creates a command timestamped now.
+    pub fn new(id: u64, call: Call, path: Path, params: Value) -> Command {
+        Command {
+            id: id,
+            call: call,
+            path: path,
+            params: params,
+            timestamp: time::precise_time_ns()
+        }
+    }
+
     pub fn from_json(json: &str) -> Result<Command, String> {
         let data: Value = try!(serde_json::from_str(json).or(Err("Bad JSON")));
         let data = try!(data.as_array().ok_or("Not array"));
@@ -44,21 +59,9 @@ impl Command {
 
         let params = data[3].clone();
 
-        let call = match call {
-            "bind" => Call::Bind,
-            "kill" => Call::Kill,
-            "read" => Call::Read,
-            "write" => Call::Write,
-            _ => return Err("Bad call".to_string())
-        };
+        let call = try!(call.parse());
 
-        Ok(Command {
-            id: id,
-            call: call,
-            path: Path { path: path_string },
-            params: params,
-            timestamp: time::precise_time_ns()
-        })
+        Ok(Command::new(id, call, Path { path: path_string }, params))
     }
 
     /// Returns true if delegated data requires separate calls.
@@ -72,6 +75,33 @@ impl Command {
     }
 }
 
+impl FromStr for Call {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Call, String> {
+        match s {
+            "auth" => Ok(Call::Auth),
+            "bind" => Ok(Call::Bind),
+            "kill" => Ok(Call::Kill),
+            "read" => Ok(Call::Read),
+            "write" => Ok(Call::Write),
+            _ => Err("Bad call".to_string())
+        }
+    }
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Call::Auth => "auth",
+            Call::Bind => "bind",
+            Call::Kill => "kill",
+            Call::Read => "read",
+            Call::Write => "write"
+        })
+    }
+}
+
 #[test]
 fn test_from_json() {
     let result = Command::from_json("[ 42, [], 42 ]");
@@ -0,0 +1,86 @@
+//! Optional compression of persisted Zones and Peer traffic.
+//!
+//! Serialized `NodeTree`s repeat keys and strings heavily, so deflate typically shrinks them
+//! several times over at little CPU cost.
+
+use std::io;
+use std::io::prelude::*;
+use std::str::FromStr;
+
+use flate2;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Compression {
+    None,
+    Deflate
+}
+
+impl Compression {
+    pub fn enabled(&self) -> bool {
+        *self != Compression::None
+    }
+
+    /// Compression to use with a Peer that supports `other`.
+    pub fn negotiate(&self, other: Compression) -> Compression {
+        match *self == other {
+            true => *self,
+            false => Compression::None
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::None
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Compression, String> {
+        match s {
+            "none" => Ok(Compression::None),
+            "deflate" => Ok(Compression::Deflate),
+            _ => Err(format!("Unknown compression: {}", s))
+        }
+    }
+}
+
+/// Deflates `bytes`.
+pub fn compress(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(bytes.len() / 2), flate2::Compression::fast());
+
+    // Writing to a Vec cannot fail
+    encoder.write_all(bytes).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Inflates `bytes`, failing if the result would exceed `limit` bytes.
+pub fn decompress(bytes: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    let mut decompressed = vec![];
+
+    try!(DeflateDecoder::new(bytes).take(limit as u64 + 1).read_to_end(&mut decompressed));
+
+    if decompressed.len() > limit {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Decompressed data too large"));
+    }
+
+    Ok(decompressed)
+}
+
+#[test]
+fn test_compression() {
+    let bytes = "moo cow ".repeat(100).into_bytes();
+    let compressed = compress(&bytes);
+
+    assert!(compressed.len() < bytes.len() / 10);
+    assert_eq!(decompress(&compressed, bytes.len()).unwrap(), bytes);
+    assert!(decompress(&compressed, bytes.len() - 1).is_err());
+
+    assert_eq!(Compression::Deflate.negotiate(Compression::Deflate), Compression::Deflate);
+    assert_eq!(Compression::Deflate.negotiate(Compression::None), Compression::None);
+    assert_eq!("deflate".parse(), Ok(Compression::Deflate));
+}
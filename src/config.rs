@@ -0,0 +1,211 @@
+//! Startup configuration from a JSON file and command line flags.
+//!
+//! ```text
+//! {
+//!     "address": "127.0.0.1:9000",
+//!     "api": "0.0.0.0:9000",
+//!     "peer": "10.0.0.1:9100",
+//!     "monitor": "127.0.0.1:9200",
+//!     "data_dir": "/var/lib/qumulus",
+//!     "cluster": ["10.0.0.2:9000"],
+//!     "owners": 3,
+//!     "headless": true,
+//!     "admin": "/run/qumulus/admin.sock",
+//!     "pid_file": "/run/qumulus/qumulus.pid"
+//! }
+//! ```
+//!
+//! `address` sets all listening addresses at the usual offsets; `api`, `peer` and `monitor`
+//! override them individually. Flags override the file.
+//!
+//! `owners` is the number of replicas storing each Zone, 0 for all; see `shard`.
+//!
+//! `headless` disables the shell on stdin, e.g. under a service manager; see `admin` for the
+//! admin socket and pid file.
+
+use std::fs::File;
+use std::net::SocketAddr;
+
+use serde_json;
+
+use admin::AdminAddr;
+use replica::Replica;
+use store;
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub address: Option<String>,
+    pub api: Option<SocketAddr>,
+    pub peer: Option<SocketAddr>,
+    pub monitor: Option<SocketAddr>,
+    pub data_dir: Option<String>,
+    pub cluster: Vec<String>,
+    pub owners: Option<usize>,
+    pub headless: bool,
+    pub admin: Option<AdminAddr>,
+    pub pid_file: Option<String>
+}
+
+pub const USAGE: &'static str = "\
+Usage: qumulus [<address>] [options]
+
+Options:
+  --config <file>     JSON configuration file
+  --address <addr>    Base address, API at <addr>, peer at port + 100, monitor at port + 200
+  --api <addr>        API listening address
+  --peer <addr>       Peer listening address
+  --monitor <addr>    Monitor listening address
+  --data-dir <dir>    Data directory, defaults to data_<API address>
+  --owners <n>        Replicas storing each zone, 0 for all, defaults to 3
+  --headless          No shell on stdin
+  --admin <path>      Shell on a Unix socket
+  --pid-file <file>   Write process id to <file>";
+
+impl Config {
+    /// Loads a configuration file.
+    pub fn load(filename: &str) -> Result<Config, String> {
+        let file = try!(File::open(filename).map_err(|e| format!("Cannot open {}: {}", filename, e)));
+
+        serde_json::from_reader(file).map_err(|e| format!("Bad config file {}: {}", filename, e))
+    }
+
+    /// Parses command line arguments, excluding the program name. A configuration file given by
+    /// `--config` is loaded, with other flags overriding it.
+    pub fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut flags = Config::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            if ! arg.starts_with("--") {
+                if flags.address.is_some() {
+                    return Err(format!("Unexpected argument: {}", arg));
+                }
+
+                flags.address = Some(arg.clone());
+                continue;
+            }
+
+            if arg == "--headless" {
+                flags.headless = true;
+                continue;
+            }
+
+            let value = try!(args.next().ok_or(format!("Missing value for {}", arg)));
+
+            match &arg[..] {
+                "--config" => config = try!(Config::load(value)),
+                "--address" => flags.address = Some(value.clone()),
+                "--api" => flags.api = Some(try!(parse_addr(value))),
+                "--peer" => flags.peer = Some(try!(parse_addr(value))),
+                "--monitor" => flags.monitor = Some(try!(parse_addr(value))),
+                "--data-dir" => flags.data_dir = Some(value.clone()),
+                "--owners" => flags.owners = Some(try!(value.parse().map_err(|_| format!("Bad owners: {}", value)))),
+                "--admin" => flags.admin = Some(try!(value.parse())),
+                "--pid-file" => flags.pid_file = Some(value.clone()),
+                _ => return Err(format!("Unknown option: {}", arg))
+            }
+        }
+
+        Ok(config.merge(flags))
+    }
+
+    /// Settings of `other` override those of `self`.
+    pub fn merge(self, other: Config) -> Config {
+        Config {
+            address: other.address.or(self.address),
+            api: other.api.or(self.api),
+            peer: other.peer.or(self.peer),
+            monitor: other.monitor.or(self.monitor),
+            data_dir: other.data_dir.or(self.data_dir),
+            cluster: match other.cluster.is_empty() {
+                true => self.cluster,
+                false => other.cluster
+            },
+            owners: other.owners.or(self.owners),
+            headless: self.headless || other.headless,
+            admin: other.admin.or(self.admin),
+            pid_file: other.pid_file.or(self.pid_file)
+        }
+    }
+
+    /// Listening addresses. Either `address` or all individual addresses must be set.
+    pub fn replica(&self) -> Result<Replica, String> {
+        let mut replica: Replica = match (&self.address, self.api, self.peer, self.monitor) {
+            (&Some(ref address), _, _, _) => try!(address.parse().map_err(|_| format!("Bad address: {}", address))),
+            (&None, Some(api), Some(peer), Some(monitor)) => Replica::new(api, peer, monitor),
+            _ => return Err("Missing address".into())
+        };
+
+        if let Some(addr) = self.api {
+            replica.set_api_addr(addr);
+        }
+
+        if let Some(addr) = self.peer {
+            replica.set_peer_addr(addr);
+        }
+
+        if let Some(addr) = self.monitor {
+            replica.set_monitor_addr(addr);
+        }
+
+        Ok(replica)
+    }
+
+    /// Replicas to join, from `cluster`.
+    pub fn replicas(&self) -> Result<Vec<Replica>, String> {
+        self.cluster.iter().map(|r| r.parse().map_err(|_| format!("Bad cluster address: {}", r))).collect()
+    }
+
+    pub fn data_dir(&self, replica: &Replica) -> String {
+        self.data_dir.clone().unwrap_or_else(|| store::data_dir(replica))
+    }
+}
+
+fn parse_addr(s: &str) -> Result<SocketAddr, String> {
+    s.parse().map_err(|_| format!("Bad address: {}", s))
+}
+
+#[test]
+fn test_config() {
+    let args = |s: &str| -> Vec<String> { s.split(' ').map(|s| s.into()).collect() };
+
+    // Legacy single address
+    let config = Config::from_args(&args("127.0.0.1:1000")).unwrap();
+    let replica = config.replica().unwrap();
+
+    assert_eq!(replica, "127.0.0.1:1000".parse().unwrap());
+    assert_eq!(config.data_dir(&replica), "data_127.0.0.1:1000");
+
+    let config = Config::from_args(&args("127.0.0.1:1000 --peer 10.0.0.1:7000 --data-dir /tmp/q")).unwrap();
+    let replica = config.replica().unwrap();
+
+    assert_eq!(replica.api_addr(), "127.0.0.1:1000".parse().unwrap());
+    assert_eq!(replica.peer_addr(), "10.0.0.1:7000".parse().unwrap());
+    assert_eq!(replica.monitor_addr(), "127.0.0.1:1200".parse().unwrap());
+    assert_eq!(config.data_dir(&replica), "/tmp/q");
+
+    let file: Config = serde_json::from_str(r#"{
+        "api": "127.0.0.1:1", "peer": "127.0.0.1:2", "monitor": "127.0.0.1:3", "data_dir": "moo"
+    }"#).unwrap();
+
+    let config = file.merge(Config::from_args(&args("--data-dir cow")).unwrap());
+
+    assert_eq!(config.replica().unwrap().monitor_addr(), "127.0.0.1:3".parse().unwrap());
+    assert_eq!(config.data_dir.unwrap(), "cow");
+
+    let config = Config::from_args(&args("127.0.0.1:1000 --headless --admin admin.sock --owners 2")).unwrap();
+
+    assert!(config.headless);
+    assert_eq!(config.owners, Some(2));
+    assert_eq!(config.admin, Some(AdminAddr("admin.sock".into())));
+
+    let file: Config = serde_json::from_str(r#"{ "cluster": ["127.0.0.1:1", "moo"] }"#).unwrap();
+
+    assert!(file.replicas().is_err());
+
+    assert!(Config::from_args(&args("--api 127.0.0.1:1")).unwrap().replica().is_err());
+    assert!(Config::from_args(&args("--moo 1")).is_err());
+    assert!(serde_json::from_str::<Config>(r#"{ "moo": 1 }"#).is_err());
+}
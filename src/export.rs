@@ -0,0 +1,144 @@
+//! Export of the entire tree to a single JSON document, and import of such documents.
+//!
+//! Data of all locally stored Zones is stitched back together at their delegation points. The
+//! plain format contains only visible values. The lossless format preserves `Vis` timestamps and
+//! tombstones, so importing it merges exactly as replication would:
+//!
+//! ```text
+//! { "version": 1, "tree": <serialized Node> }
+//! ```
+//!
+//! Data is exported from the Store, so unsaved changes in active Zones are not included.
+
+use serde_json;
+use serde_json::Value as JSON;
+use time;
+
+use app::AppHandle;
+use node::Node;
+use path::Path;
+use store::StoreHandle;
+
+/// Version of the lossless format.
+pub const VERSION: u64 = 1;
+
+/// Exports all stored Zones as one JSON document.
+pub fn export(store: &StoreHandle, lossless: bool) -> JSON {
+    let mut paths = vec![];
+
+    store.each_zone(|path| paths.push(path));
+
+    // Parents first, so delegated Zones are stitched into them
+    paths.sort_by_key(|path| path.len());
+
+    let mut root = Node::default();
+    let mut json = JSON::Null;
+
+    for path in paths {
+        let data = match store.load_data(path.clone()) {
+            None => {
+                error!("Could not export {:?}", path);
+                continue;
+            },
+            Some(data) => data
+        };
+
+        if lossless {
+            root.graft(&path.path, data.tree.node);
+        }
+        else if let Some(value) = data.tree.node.to_json(data.tree.vis) {
+            insert(&mut json, &path.path, value);
+        }
+    }
+
+    if lossless {
+        let mut document = serde_json::Map::new();
+
+        document.insert("version".into(), VERSION.into());
+        document.insert("tree".into(), serde_json::to_value(&root).unwrap());
+
+        JSON::Object(document)
+    }
+    else {
+        json
+    }
+}
+
+/// Imports a JSON document from `export`, merging it into the root Zone.
+pub fn import(app: &AppHandle, data: JSON, lossless: bool) -> Result<(), String> {
+    let node = if lossless {
+        if data["version"] != VERSION {
+            return Err(format!("Unsupported version: {}", data["version"]));
+        }
+
+        try!(serde_json::from_value(data["tree"].clone()).map_err(|e| format!("Bad tree: {}", e)))
+    }
+    else {
+        Node::expand(data, time::precise_time_ns())
+    };
+
+    app.manager.load(&Path::empty()).merge(node.noop_vis(), true);
+
+    Ok(())
+}
+
+/// Sets `value` at `path` in `json`, creating objects as needed.
+fn insert(json: &mut JSON, path: &[String], value: JSON) {
+    match path.split_first() {
+        None => *json = value,
+        Some((first, rest)) => {
+            if ! json.is_object() {
+                *json = JSON::Object(serde_json::Map::new());
+            }
+
+            let child = json.as_object_mut().unwrap().entry(first.clone()).or_insert(JSON::Null);
+
+            insert(child, rest, value);
+        }
+    }
+}
+
+#[test]
+fn test_export() {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use app::App;
+    use node::Vis;
+    use store;
+    use store::memory::{Faults, Memory};
+    use zone::{ZoneData, ZoneHandle};
+
+    let parse = |s: &str| -> JSON { serde_json::from_str(s).unwrap() };
+    let mut app = App::new("127.0.0.1:1000".parse().unwrap());
+
+    store::start(Memory::new(app.handle(), Faults::new()), app.channels.store.take().unwrap());
+
+    let mut root = Node::expand(parse(r#"{ "a": 1 }"#), 10);
+
+    root.add_child("moo".into(), Node::delegate(20));
+
+    let zones = vec![
+        ZoneData::new(path![], root.noop_vis()),
+        ZoneData::new(path![moo], Node::expand(parse(r#"{ "cow": 42 }"#), 10).noop_vis())
+    ];
+
+    let noop_zone = ZoneHandle::test_handle(Arc::new(path![]));
+
+    for mut data in zones {
+        data.tree.vis = Vis::update(10);
+        app.store.write(&noop_zone, &data.path.clone(), &data, &Default::default());
+    }
+
+    thread::sleep(Duration::from_millis(100));
+
+    let plain = export(&app.store, false);
+
+    assert_eq!(plain, parse(r#"{ "a": 1.0, "moo": { "cow": 42.0 } }"#));
+
+    let lossless = export(&app.store, true);
+    let tree: Node = serde_json::from_value(lossless["tree"].clone()).unwrap();
+
+    assert_eq!(tree.to_json(Vis::permanent()), Some(plain));
+}
@@ -0,0 +1,43 @@
+//! Stable 64-bit hashing, for values compared between replicas. Unlike `DefaultHasher`, results
+//! do not depend on the build or platform.
+
+use std::hash::Hasher;
+
+/// FNV-1a, mixed by the MurmurHash3 finalizer as results are compared as a whole.
+pub struct Fnv {
+    hash: u64
+}
+
+impl Fnv {
+    pub fn new() -> Fnv {
+        Fnv { hash: 0xcbf29ce484222325 }
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash ^= *byte as u64;
+            self.hash = self.hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    /// Little endian on all platforms.
+    fn write_u64(&mut self, value: u64) {
+        for i in 0..8 {
+            self.write(&[(value >> (i * 8)) as u8]);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        let mut hash = self.hash;
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+        hash ^= hash >> 33;
+
+        hash
+    }
+}
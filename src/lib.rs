@@ -0,0 +1,75 @@
+//! A distributed hierarchical data distribution thingy, as a library for embedding a node.
+//!
+//! ```no_run
+//! extern crate qumulus;
+//!
+//! use qumulus::api::Api;
+//! use qumulus::app::App;
+//! use qumulus::cluster::Cluster;
+//! use qumulus::compression::Compression;
+//! use qumulus::manager::Manager;
+//! use qumulus::path::Path;
+//! use qumulus::store;
+//!
+//! fn main() {
+//!     let mut app = App::new("127.0.0.1:8888".parse().unwrap());
+//!
+//!     store::spawn(&mut app, store::Backend::FS, store::Durability::File, Compression::None, Default::default());
+//!     Manager::spawn(&mut app);
+//!     Cluster::spawn(&mut app, Compression::None);
+//!
+//!     // Commands are routed to the nearest Zone, starting from the root
+//!     app.manager.load(&Path::empty());
+//!
+//!     let api = Api::new(&app.handle());
+//!
+//!     api.write(&Path::new(vec!["moo".into()]), 42.into()).unwrap();
+//! }
+//! ```
+
+#![recursion_limit="128"]
+
+extern crate bincode;
+extern crate chacha20poly1305;
+extern crate ctrlc;
+extern crate flate2;
+#[macro_use] extern crate log;
+extern crate mioco;
+extern crate rand;
+extern crate serde;
+extern crate serde_json;
+#[macro_use] extern crate serde_derive;
+extern crate threadpool;
+extern crate time;
+
+#[macro_use] pub mod path;
+
+pub mod acl;
+pub mod anti_entropy;
+pub mod api;
+pub mod admin;
+pub mod app;
+pub mod client;
+pub mod cluster;
+pub mod command;
+pub mod compression;
+pub mod config;
+pub mod delegate;
+pub mod export;
+pub mod hash;
+pub mod listener;
+pub mod manager;
+pub mod membership;
+pub mod monitor;
+pub mod node;
+pub mod replica;
+pub mod router;
+pub mod shell;
+pub mod server;
+pub mod shard;
+pub mod shutdown;
+pub mod snapshot;
+pub mod store;
+pub mod value;
+pub mod wal;
+pub mod zone;
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::sync::Arc;
 use std::sync::mpsc::SendError;
 
@@ -53,6 +54,12 @@ impl Listener {
     }
 }
 
+impl fmt::Debug for RListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RListener({:?})", self.path)
+    }
+}
+
 impl RListener {
     pub fn new(path: Path, tx: &Sender<String>) -> RListener {
         RListener {
@@ -1,60 +1,114 @@
 //! A distributed hierarchical data distribution thingy
 
-#![recursion_limit="128"]
-
-extern crate bincode;
 extern crate env_logger;
-#[macro_use] extern crate log;
-extern crate mioco;
-extern crate rand;
-extern crate serde;
-extern crate serde_json;
-#[macro_use] extern crate serde_derive;
-extern crate threadpool;
-extern crate time;
-
-pub mod app;
-pub mod client;
-pub mod cluster;
-pub mod command;
-pub mod delegate;
-pub mod listener;
-pub mod manager;
-pub mod monitor;
-pub mod node;
-#[macro_use] pub mod path;
-pub mod replica;
-pub mod shell;
-pub mod server;
-pub mod store;
-pub mod value;
-pub mod zone;
+extern crate qumulus;
+
+use qumulus::{acl, admin, app, cluster, compression, config, manager, monitor, path, replica, server, shard,
+              shell, shutdown, snapshot, store, wal};
 
 fn main() {
     env_logger::init().unwrap();
 
     println!("Qumulus v0.0.1");
 
-    let args: Vec<_> = std::env::args().collect();
+    let args: Vec<_> = std::env::args().skip(1).collect();
+
+    let parsed = config::Config::from_args(&args)
+        .and_then(|c| c.replica().map(|r| (c, r)))
+        .and_then(|(c, r)| c.replicas().map(|replicas| (c, r, replicas)));
+
+    let (config, id, mut replicas) = match parsed {
+        Ok(result) => result,
+        Err(err) => {
+            println!("{}", err);
+            println!("{}", config::USAGE);
+
+            return;
+        }
+    };
+
+    let mut app = app::App::new(id.clone());
+
+    app.data_dir = config.data_dir(&id);
+    app.replica_id = replica::load_id(&app.data_dir).unwrap();
+
+    println!("  Replica ID: {}", app.replica_id);
+    println!("  Data directory: {}", app.data_dir);
+
+    let owners = config.owners.unwrap_or(shard::DEFAULT_OWNERS);
+
+    println!("  Owners per zone: {}", owners);
+    app.shards = std::sync::Arc::new(std::sync::RwLock::new(shard::Shards::new(owners)));
+
+    if let Some(ref filename) = config.pid_file {
+        println!("  PID file: {}", filename);
+        admin::write_pid_file(filename).unwrap();
+        app.pid_file = Some(std::sync::Arc::new(filename.clone()));
+    }
+
+    if let Ok(filename) = std::env::var("ACL") {
+        println!("  ACL: {}", filename);
+        app.acl = std::sync::Arc::new(acl::Acl::load(&filename).unwrap());
+    }
+
+    let backend: store::Backend = match std::env::var("STORE") {
+        Ok(b) => b.parse().unwrap(),
+        Err(_) => store::Backend::FS
+    };
+
+    let durability: store::Durability = match std::env::var("STORE_SYNC") {
+        Ok(d) => d.parse().unwrap(),
+        Err(_) => store::Durability::None
+    };
 
-    if args.len() != 2 {
-        println!("Usage: {} <ID>", &args[0]);
-        println!("Missing ID. ID must be provided as an IP:port string.");
-        println!("This is used as the listening address as well as the data directory.");
+    let compression: compression::Compression = match std::env::var("COMPRESSION") {
+        Ok(c) => c.parse().unwrap(),
+        Err(_) => compression::Compression::None
+    };
 
+    let keys = match (std::env::var("STORE_KEY_FILE"), std::env::var("STORE_KEY")) {
+        (Ok(filename), _) => store::encryption::Keyring::load(&filename).unwrap(),
+        (_, Ok(key)) => store::encryption::Keyring::parse(&key).unwrap(),
+        _ => Default::default()
+    };
+
+    // Only the fs backend encrypts, other files would hold data in plaintext
+    if keys.current().is_some() && (backend == store::Backend::Log || std::env::var("WAL").is_ok()) {
+        println!("Encryption keys are set, but the log store and WAL cannot encrypt. Use STORE=fs without WAL.");
         return;
     }
 
-    let id_str = &args[1];
-    let id: replica::Replica = id_str.parse().unwrap();
+    println!("  Store: {:?}, sync: {:?}, compression: {:?}", backend, durability, compression);
+
+    if let Some(key) = keys.current() {
+        println!("  Encryption keys: {:?}, writing with {}", keys, key.id);
+    }
 
-    println!("  ID / address: {:?}", &id);
+    if let Ok(dir) = std::env::var("RESTORE") {
+        println!("  Restoring snapshot {} to {}", dir, app.data_dir);
 
-    let mut app = app::App::new(id.clone());
+        match snapshot::restore(&dir, &app.data_dir).unwrap() {
+            Some(files) => println!("    Restored {} files", files),
+            None => println!("    Already restored, unset RESTORE to silence this")
+        }
+    }
 
-    store::fs::FS::spawn(&mut app);
+    let replay = match std::env::var("WAL") {
+        Ok(dir) => {
+            println!("  WAL: {}", dir);
+            wal::Wal::spawn(&mut app, &dir, durability)
+        },
+        Err(_) => vec![]
+    };
+
+    store::spawn(&mut app, backend, durability, compression, std::sync::Arc::new(keys));
     manager::Manager::spawn(&mut app);
-    cluster::Cluster::spawn(&mut app);
+    cluster::Cluster::spawn(&mut app, compression);
+
+    if ! replay.is_empty() {
+        println!("Replaying {} WAL entries...", replay.len());
+        wal::replay(&app.handle(), replay);
+    }
 
     app.manager.load(&path::Path::empty());
 
@@ -66,10 +120,18 @@ fn main() {
     let server = server::Server::new(&app, id.api_addr());
     server.listen();
 
-    let replicas: Vec<replica::Replica> = match std::env::var("CLUSTER") {
-        Ok(r) => r.split(' ').map(|r| r.parse().unwrap()).collect(),
-        Err(_) => vec![]
-    };
+    if let Ok(r) = std::env::var("CLUSTER") {
+        for addr in r.split(' ') {
+            match addr.parse() {
+                Ok(replica) => replicas.push(replica),
+                Err(_) => {
+                    println!("Bad CLUSTER address: {}", addr);
+
+                    return;
+                }
+            }
+        }
+    }
 
     println!("Adding replicas:");
 
@@ -79,10 +141,18 @@ fn main() {
     }
 
     monitor::Monitor::spawn(&app);
+    shutdown::handle_signals(&app.handle());
+
+    if let Some(ref addr) = config.admin {
+        println!("Admin listening on: {}", addr);
+        admin::spawn(&app.handle(), addr).unwrap();
+    }
 
-    let stdin = std::io::stdin();
+    if ! config.headless {
+        let stdin = std::io::stdin();
 
-    shell::start(app, stdin.lock(), std::io::stdout());
+        shell::start(app, stdin.lock(), std::io::stdout());
+    }
 
     loop {
         std::thread::park();
@@ -12,6 +12,7 @@ use app::{App, AppHandle};
 use listener::RListener;
 use node::External;
 use path::Path;
+use wal::Hold;
 use zone::{Zone, ZoneHandle};
 
 const MAX_LOADED_SOFT: usize = 600;
@@ -64,12 +65,10 @@ impl ManagerHandle {
         self.call(ManagerCall::Load(path.clone()))
     }
 
-    /// Routes delegated data to the correct `Zone`
-    pub fn send_external(&self, prefix: &Path, external: External, replicate: bool) {
+    /// Routes delegated data to the correct local `Zone`, see `router` for remote Zones.
+    pub fn send_external(&self, prefix: &Path, external: External, replicate: bool, hold: Option<Hold>) {
         let mut path = prefix.clone();
 
-        // TODO: zone may be remote
-
         // Borrow checker doesn't like:
         //   path.append(&mut external.path);
         let mut p = external.path;
@@ -77,15 +76,13 @@ impl ManagerHandle {
 
         let zone = self.load(&path);
 
-        zone.merge(external.tree, replicate); // TODO flow control
+        zone.merge_held(external.tree, replicate, hold); // TODO flow control
     }
 
-    /// Routes delegated data to the correct `Zone` with a list of listeners.
-    pub fn send_external_with_listeners(&self, prefix: &Path, external: External, listeners: Vec<RListener>) {
+    /// Routes delegated data to the correct local `Zone` with a list of listeners.
+    pub fn send_external_with_listeners(&self, prefix: &Path, external: External, listeners: Vec<RListener>, hold: Option<Hold>) {
         let mut path = prefix.clone();
 
-        // TODO: zone may be remote
-
         // Borrow checker doesn't like:
         //   path.append(&mut external.path);
         let mut p = external.path;
@@ -93,7 +90,7 @@ impl ManagerHandle {
 
         let zone = self.load(&path);
 
-        zone.merge_with_listeners(external.tree, listeners); // TODO flow control
+        zone.merge_with_listeners(external.tree, listeners, hold); // TODO flow control
     }
 
     pub fn send_externals(&self, prefix: &Path, externals: Vec<External>) {
@@ -102,8 +99,7 @@ impl ManagerHandle {
         let len = path.len();
 
         for mut external in externals {
-            // TODO: zone may be remote
-
+            // Local Zones only, see `router`
             path.append(&mut external.path);
 
             let zone = self.load(&path);
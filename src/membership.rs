@@ -0,0 +1,207 @@
+//! Cluster membership, spread by gossip.
+//!
+//! Each member is identified by its stable replica id and announces its addresses along with an
+//! incarnation number that only it increments. Members periodically send their list to a random
+//! Peer, which merges it: for each member the entry with the higher incarnation wins, and at the
+//! same incarnation `Left` wins over `Alive`. A running member told that it left, or of stale
+//! addresses, refutes it by announcing itself with a higher incarnation.
+//!
+//! The local member starts at an incarnation taken from the clock, so that after a restart it
+//! supersedes whatever it announced before, including having left.
+//!
+//! Members that left are remembered, so that older gossip cannot bring them back.
+
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use replica::Replica;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MemberState {
+    Alive,
+    Left
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Member {
+    pub id: String,
+    pub replica: Replica,
+    pub incarnation: u64,
+    pub state: MemberState
+}
+
+/// Changes to remote members resulting from a merge.
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Joined(Member),
+    Left(Member),
+    /// Member is now at different addresses, previously `Replica`
+    Moved(Replica, Member)
+}
+
+/// View of the Cluster from the local member.
+pub struct Membership {
+    local: Member,
+    members: BTreeMap<String, Member>  // Remote members by id
+}
+
+impl Member {
+    pub fn new(id: String, replica: Replica) -> Member {
+        Member {
+            id: id,
+            replica: replica,
+            incarnation: 0,
+            state: MemberState::Alive
+        }
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.state == MemberState::Alive
+    }
+
+    /// Returns true if `self` is newer information about the same member than `other`.
+    fn supersedes(&self, other: &Member) -> bool {
+        self.incarnation > other.incarnation ||
+            (self.incarnation == other.incarnation && ! self.is_alive() && other.is_alive())
+    }
+}
+
+impl Membership {
+    pub fn new(id: String, replica: Replica) -> Membership {
+        let mut local = Member::new(id, replica);
+
+        local.incarnation = startup_incarnation();
+
+        Membership {
+            local: local,
+            members: BTreeMap::new()
+        }
+    }
+
+    pub fn local(&self) -> &Member {
+        &self.local
+    }
+
+    /// All known members, including the local member and those that left.
+    pub fn list(&self) -> Vec<Member> {
+        let mut members: Vec<_> = self.members.values().cloned().collect();
+
+        members.push(self.local.clone());
+        members.sort_by(|a, b| a.id.cmp(&b.id));
+
+        members
+    }
+
+    /// Remote members that are alive.
+    pub fn alive(&self) -> Vec<&Member> {
+        self.members.values().filter(|m| m.is_alive()).collect()
+    }
+
+    /// Announces that the local member is leaving.
+    pub fn leave(&mut self) {
+        self.local.incarnation += 1;
+        self.local.state = MemberState::Left;
+    }
+
+    /// Merges a member list received by gossip, returning changes to remote members.
+    pub fn merge(&mut self, members: Vec<Member>) -> Vec<Change> {
+        let mut changes = vec![];
+
+        for member in members {
+            if member.id == self.local.id {
+                self.refute(&member);
+                continue;
+            }
+
+            let change = match self.members.get(&member.id) {
+                None if member.is_alive() => Some(Change::Joined(member.clone())),
+                None => None,
+                Some(current) if ! member.supersedes(current) => continue,
+                Some(current) => match (current.is_alive(), member.is_alive()) {
+                    (true, false) => Some(Change::Left(member.clone())),
+                    (false, true) => Some(Change::Joined(member.clone())),
+                    (true, true) if current.replica != member.replica => {
+                        Some(Change::Moved(current.replica.clone(), member.clone()))
+                    },
+                    _ => None
+                }
+            };
+
+            changes.extend(change);
+            self.members.insert(member.id.clone(), member);
+        }
+
+        changes
+    }
+
+    /// Outdoes gossip about the local member that is wrong, unless leaving.
+    fn refute(&mut self, member: &Member) {
+        let wrong = member.state != self.local.state || member.replica != self.local.replica;
+
+        if self.local.is_alive() && wrong && member.incarnation >= self.local.incarnation {
+            self.local.incarnation = member.incarnation + 1;
+        }
+    }
+}
+
+/// Milliseconds since the epoch, well above the few increments of a previous run.
+fn startup_incarnation() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000)
+        .unwrap_or(0)
+}
+
+#[test]
+fn test_membership() {
+    let replica = |s: &str| -> Replica { s.parse().unwrap() };
+    let member = |id: &str, addr: &str, incarnation, state| {
+        Member { id: id.into(), replica: replica(addr), incarnation: incarnation, state: state }
+    };
+
+    let mut membership = Membership::new("a".into(), replica("127.0.0.1:1000"));
+
+    let changes = membership.merge(vec![member("b", "127.0.0.1:2000", 0, MemberState::Alive)]);
+
+    assert_eq!(changes, vec![Change::Joined(member("b", "127.0.0.1:2000", 0, MemberState::Alive))]);
+    assert_eq!(membership.alive().len(), 1);
+
+    // Same information is not a change
+    assert!(membership.merge(vec![member("b", "127.0.0.1:2000", 0, MemberState::Alive)]).is_empty());
+
+    // New addresses need a new incarnation
+    assert!(membership.merge(vec![member("b", "127.0.0.1:3000", 0, MemberState::Alive)]).is_empty());
+    assert_eq!(
+        membership.merge(vec![member("b", "127.0.0.1:3000", 1, MemberState::Alive)]),
+        vec![Change::Moved(replica("127.0.0.1:2000"), member("b", "127.0.0.1:3000", 1, MemberState::Alive))]
+    );
+
+    // Leaving wins at the same incarnation, and older gossip does not revive
+    assert_eq!(membership.merge(vec![member("b", "127.0.0.1:3000", 1, MemberState::Left)]).len(), 1);
+    assert!(membership.merge(vec![member("b", "127.0.0.1:3000", 1, MemberState::Alive)]).is_empty());
+    assert!(membership.alive().is_empty());
+    assert_eq!(membership.list().len(), 2);
+
+    // Wrong gossip about the local member is refuted
+    let incarnation = membership.local().incarnation;
+
+    membership.merge(vec![member("a", "127.0.0.1:1000", incarnation + 4, MemberState::Left)]);
+
+    assert_eq!(membership.local().incarnation, incarnation + 5);
+    assert!(membership.local().is_alive());
+
+    membership.leave();
+
+    assert_eq!(membership.local().state, MemberState::Left);
+
+    // After a restart, peers take the member back
+    let left = membership.local().clone();
+
+    std::thread::sleep(std::time::Duration::from_millis(10));
+
+    let restarted = Membership::new("a".into(), replica("127.0.0.1:1000"));
+    let mut peer = Membership::new("b".into(), replica("127.0.0.1:2000"));
+
+    peer.merge(vec![left]);
+
+    assert_eq!(peer.merge(vec![restarted.local().clone()]), vec![Change::Joined(restarted.local().clone())]);
+}
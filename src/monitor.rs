@@ -1,6 +1,7 @@
-//! Simple monitor, allows a single REST call to retrieve stats
+//! Simple monitor, allows a single REST call to retrieve stats, or Cluster members at `/members`
+//! and Peer health at `/peers`
 
-use std::io::{Read, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::thread::Builder;
 
@@ -75,9 +76,17 @@ impl Server {
     fn handle(&self, mut stream: TcpStream) {
         use serde_json;
 
-        println!("insert HTTP response here");
+        let mut request = String::new();
 
-        let stats = serde_json::to_string_pretty(&*self.app.stats).unwrap();
+        if let Ok(s) = stream.try_clone() {
+            let _ = BufReader::new(s).read_line(&mut request);
+        }
+
+        let stats = match request.split(' ').nth(1) {
+            Some("/members") => serde_json::to_string_pretty(&self.app.cluster.members()).unwrap(),
+            Some("/peers") => serde_json::to_string_pretty(&self.app.cluster.peers()).unwrap(),
+            _ => serde_json::to_string_pretty(&*self.app.stats).unwrap()
+        };
 
         stream.write("HTTP/1.1 200 OK\r
 Access-Control-Allow-Origin: *\r
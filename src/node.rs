@@ -10,11 +10,13 @@
 
 use std::collections::BTreeMap;
 use std::collections::btree_map::Entry;
+use std::hash::Hasher;
 use std::mem;
 
 use serde_json;
 use serde_json::Value as JSON;
 
+use hash::Fnv;
 use path::Path;
 use value::Value;
 
@@ -30,7 +32,20 @@ pub struct Node {
     vis: Vis,
     value: Value,
     keys: Option<BTreeMap<String, Node>>,
-    delegated: u64
+    delegated: u64,
+    #[serde(skip)]
+    digest: CachedDigest // See `cache_digests`
+}
+
+/// Digest of a node cached by `Node::cache_digests`. Not part of the data, so ignored when
+/// comparing nodes.
+#[derive(Clone, Copy, Debug, Default)]
+struct CachedDigest(Option<u64>);
+
+impl PartialEq for CachedDigest {
+    fn eq(&self, _: &CachedDigest) -> bool {
+        true
+    }
 }
 
 /// Node structure that includes ancestor visibility information
@@ -41,7 +56,7 @@ pub struct NodeTree {
 }
 
 /// Tracks effective changes (includes visibility changes)
-#[derive(Debug, Default, PartialEq)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct Update {
     changed: bool,
     old: Option<Value>,
@@ -62,7 +77,7 @@ pub struct External {
     pub initial: bool
 }
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct DelegatedMatch {
     /// Path to delegated data
     pub path: Path,
@@ -209,7 +224,8 @@ impl Node {
             vis: mem::replace(&mut self.vis, Default::default()),
             value: mem::replace(&mut self.value, Value::Null),
             keys: mem::replace(&mut self.keys, None),
-            delegated: self.delegated
+            delegated: self.delegated,
+            digest: mem::replace(&mut self.digest, Default::default())
         }
     }
 
@@ -270,8 +286,136 @@ impl Node {
         total_size
     }
 
+    /// Returns the descendant at relative `path`, if any.
+    pub fn descendant(&self, path: &[String]) -> Option<&Node> {
+        match path.split_first() {
+            None => Some(self),
+            Some((first, rest)) => self.keys.as_ref().and_then(|keys| keys.get(first)).and_then(|n| n.descendant(rest))
+        }
+    }
+
+    /// Returns a copy of this node without children.
+    pub fn shallow(&self) -> Node {
+        Node {
+            vis: self.vis,
+            value: self.value.clone(),
+            keys: None,
+            delegated: self.delegated,
+            digest: Default::default()
+        }
+    }
+
+    /// Returns a hash of this node including children, equal on replicas with the same data.
+    /// Digests cached by `cache_digests` are used instead of hashing again.
+    pub fn digest(&self) -> u64 {
+        match self.digest.0 {
+            Some(digest) => digest,
+            None => self.hash(self.child_digests())
+        }
+    }
+
+    /// Caches digests of this node and all descendants, returning `digest`. Merging clears the
+    /// cached digests of changed nodes and their ancestors, so only those are hashed again.
+    pub fn cache_digests(&mut self) -> u64 {
+        if let Some(digest) = self.digest.0 {
+            return digest;
+        }
+
+        let mut child_digests = vec![];
+
+        if let Some(ref mut keys) = self.keys {
+            for (k, child) in keys.iter_mut() {
+                child_digests.push((k.clone(), child.cache_digests()));
+            }
+        }
+
+        let digest = self.hash(child_digests);
+
+        self.digest = CachedDigest(Some(digest));
+        digest
+    }
+
+    /// Returns digests of children, see `digest`.
+    pub fn child_digests(&self) -> Vec<(String, u64)> {
+        let mut digests = vec![];
+
+        self.each_child(|k, child| digests.push((k.clone(), child.digest())));
+
+        digests
+    }
+
+    fn hash(&self, child_digests: Vec<(String, u64)>) -> u64 {
+        let mut hasher = Fnv::new();
+
+        hasher.write_u64(self.vis.updated);
+        hasher.write_u64(self.vis.deleted);
+        hasher.write_u64(self.delegated);
+
+        match self.value {
+            Value::Null => hasher.write(&[0]),
+            Value::Bool(v) => hasher.write(&[1, v as u8]),
+            Value::I64(v) => { hasher.write(&[2]); hasher.write_u64(v as u64) },
+            Value::U64(v) => { hasher.write(&[3]); hasher.write_u64(v) },
+            Value::F64(v) => { hasher.write(&[4]); hasher.write_u64(v.to_bits()) },
+            Value::String(ref s) => { hasher.write(&[5]); hasher.write_u64(s.len() as u64); hasher.write(s.as_bytes()) }
+        }
+
+        for (k, digest) in child_digests {
+            hasher.write_u64(k.len() as u64);
+            hasher.write(k.as_bytes());
+            hasher.write_u64(digest);
+        }
+
+        hasher.finish()
+    }
+
+    /// Converts visible data to plain JSON, given effective visibility of the parent. Nodes with
+    /// visible children become objects (dropping their own value). Delegated children are skipped.
+    /// Returns `None` if nothing is visible.
+    pub fn to_json(&self, mut vis: Vis) -> Option<JSON> {
+        vis.descend(&self.vis);
+
+        let mut keys = serde_json::Map::new();
+
+        self.each_child(|k, child| {
+            if child.delegated & 1 == 0 {
+                if let Some(v) = child.to_json(vis) {
+                    keys.insert(k.clone(), v);
+                }
+            }
+        });
+
+        if ! keys.is_empty() {
+            return Some(JSON::Object(keys));
+        }
+
+        match vis.is_visible() {
+            true => Some(self.value.to_json()),
+            false => None
+        }
+    }
+
+    /// Replaces the node at `path` with `node`, which is no longer delegated. Used to stitch data
+    /// of delegated Zones back into their parent.
+    pub fn graft(&mut self, path: &[String], node: Node) {
+        self.digest = Default::default();
+
+        match path.split_first() {
+            None => *self = Node { delegated: 0, digest: Default::default(), ..node },
+            Some((first, rest)) => {
+                if self.keys.as_ref().map_or(true, |keys| ! keys.contains_key(first)) {
+                    self.add_child(first.clone(), Default::default());
+                }
+
+                self.keys.as_mut().unwrap().get_mut(first).unwrap().graft(rest, node);
+            }
+        }
+    }
+
     /// Adds a child Node with given key.
     pub fn add_child(&mut self, k: String, child: Node) {
+        self.digest = Default::default();
+
         match self.keys {
             None => {
                 let mut keys = BTreeMap::new();
@@ -504,6 +648,9 @@ fn merge(
     mut vis_new: Vis, // New visibility of parent node
     externals: &mut Vec<External>)
 -> Option<Update> {
+    // Changes below clear digests along the path, see `Node::cache_digests`
+    node.digest = Default::default();
+
     // "Previous" effective visibility of this node
     vis_old.descend(&node.vis);
 
@@ -846,10 +993,12 @@ fn test_expand() {
                 vis: Vis::new(1000, 0),
                 value: Value::F64(42.0),
                 keys: None,
-                delegated: 0
+                delegated: 0,
+                ..Default::default()
             }
         }),
-        delegated: 0
+        delegated: 0,
+        ..Default::default()
     };
 
     assert_eq!(node, expected);
@@ -866,34 +1015,40 @@ fn test_merge() {
                     vis: Vis { updated: 1201575625873458, deleted: 0 },
                     value: Value::String("test".into()),
                     keys: None,
-                    delegated: 0
+                    delegated: 0,
+                    ..Default::default()
                 },
                 "#I".into() => Node {
                     vis: Vis { updated: 1201575640647792, deleted: 0 },
                     value: Value::String("test".into()),
                     keys: None,
-                    delegated: 0
+                    delegated: 0,
+                    ..Default::default()
                 },
                 "#K".into() => Node {
                     vis: Vis { updated: 1201575709365982, deleted: 0 },
                     value: Value::String("test".into()),
                     keys: None,
-                    delegated: 0
+                    delegated: 0,
+                    ..Default::default()
                 },
                 "#S".into() => Node {
                     vis: Vis { updated: 1201575313136481, deleted: 0 },
                     value: Value::String("test".into()),
                     keys: None,
-                    delegated: 0
+                    delegated: 0,
+                    ..Default::default()
                 },
                 "#W".into() => Node {
                     vis: Vis { updated: 1201575709650540, deleted: 0 },
                     value: Value::String("test".into()),
                     keys: None,
-                    delegated: 0
+                    delegated: 0,
+                    ..Default::default()
                 }
             }),
-            delegated: 1201576002005307
+            delegated: 1201576002005307,
+            ..Default::default()
         },
         vis: Vis { updated: 1201575709650540, deleted: 0 }
     };
@@ -907,7 +1062,7 @@ fn test_merge() {
 #[test]
 fn test_merge_noop() {
     let mut tree = NodeTree {
-        node: Node { vis: Vis { updated: 1, deleted: 0 }, value: Value::Null, keys: None, delegated: 0 },
+        node: Node { vis: Vis { updated: 1, deleted: 0 }, value: Value::Null, keys: None, delegated: 0, ..Default::default() },
         vis: Vis { updated: 1, deleted: 0 }
     };
 
@@ -918,3 +1073,25 @@ fn test_merge_noop() {
     assert_eq!(update, None);
     assert_eq!(externals.len(), 0);
 }
+
+#[test]
+fn test_cache_digests() {
+    let data: JSON = serde_json::from_str(r#"{ "moo": { "cow": 1 }, "foo": 2 }"#).unwrap();
+    let mut tree = Node::expand(data, 1000).noop_vis();
+    let digest = tree.node.digest();
+
+    assert_eq!(tree.node.cache_digests(), digest);
+    assert_eq!(tree.node.digest(), digest);
+
+    let path = ["moo".to_string(), "cow".to_string()];
+    let mut diff = Node::expand_from(&path, JSON::from(3), 2000).noop_vis();
+
+    tree.merge(&mut diff);
+
+    // Cached digests are not serialized, so this hashes everything again
+    let uncached: Node = serde_json::from_str(&serde_json::to_string(&tree.node).unwrap()).unwrap();
+
+    assert!(tree.node.digest() != digest);
+    assert_eq!(tree.node.digest(), uncached.digest());
+    assert_eq!(tree.node.cache_digests(), uncached.digest());
+}
@@ -1,61 +1,130 @@
 //! Replica handling.
 
+use std;
 use std::fmt;
+use std::fs::{DirBuilder, File};
+use std::io;
+use std::io::prelude::*;
 use std::net::{AddrParseError,SocketAddr};
 use std::str::FromStr;
 
-/// Represents a Replica.
+use rand;
+
+/// File in the data directory holding the stable replica id.
+pub const ID_FILE: &'static str = "replica_id";
+
+/// Represents a Replica by its listening addresses.
 ///
-/// Replicas are identified by an IP/port combination
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+/// Replicas are parsed from a single IP/port combination, used as the API address, with the peer
+/// and monitor addresses at fixed offsets. Use `Replica::new` to set each address independently.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct Replica {
-    addr: SocketAddr
+    api: SocketAddr,
+    peer: SocketAddr,
+    monitor: SocketAddr
 }
 
 impl Replica {
-    pub fn api_addr(&self) -> SocketAddr {
-        let addr = self.addr.clone();
+    pub fn new(api: SocketAddr, peer: SocketAddr, monitor: SocketAddr) -> Replica {
+        Replica {
+            api: api,
+            peer: peer,
+            monitor: monitor
+        }
+    }
 
-        addr
+    pub fn api_addr(&self) -> SocketAddr {
+        self.api
     }
 
     pub fn peer_addr(&self) -> SocketAddr {
-        let mut addr = self.addr.clone();
-        let port = addr.port() + 100;
-
-        addr.set_port(port);
-
-        addr
+        self.peer
     }
 
     pub fn monitor_addr(&self) -> SocketAddr {
-        let mut addr = self.addr.clone();
-        let port = addr.port() + 200;
+        self.monitor
+    }
+
+    pub fn set_api_addr(&mut self, addr: SocketAddr) {
+        self.api = addr;
+    }
 
-        addr.set_port(port);
+    pub fn set_peer_addr(&mut self, addr: SocketAddr) {
+        self.peer = addr;
+    }
 
-        addr
+    pub fn set_monitor_addr(&mut self, addr: SocketAddr) {
+        self.monitor = addr;
     }
 }
 
 impl fmt::Display for Replica {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.addr)
+        write!(f, "{}", self.api)
     }
 }
 
 impl FromStr for Replica {
     type Err = AddrParseError;
     fn from_str(s: &str) -> Result<Replica, AddrParseError> {
-        s.parse().map(|addr| Replica {
-            addr: addr
+        s.parse().map(|addr: SocketAddr| {
+            let mut peer = addr.clone();
+            let mut monitor = addr.clone();
+
+            peer.set_port(addr.port() + 100);
+            monitor.set_port(addr.port() + 200);
+
+            Replica::new(addr, peer, monitor)
         })
     }
 }
 
+/// Loads the stable id of the replica owning `data_dir`, generating one on first use. The id is
+/// kept when listening addresses change.
+pub fn load_id(data_dir: &str) -> io::Result<String> {
+    let filepath = std::path::Path::new(data_dir).join(ID_FILE);
+    let mut id = String::new();
+
+    match File::open(&filepath) {
+        Ok(mut file) => {
+            try!(file.read_to_string(&mut id));
+
+            return Ok(id.trim().into());
+        },
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err)
+    }
+
+    id = format!("{:016x}", rand::random::<u64>());
+
+    try!(DirBuilder::new().recursive(true).create(data_dir));
+    try!(File::create(&filepath).and_then(|mut f| {
+        try!(f.write_all(id.as_bytes()));
+        f.sync_all()
+    }));
+
+    Ok(id)
+}
+
 #[test]
 fn test_replica_parse() {
     let replica: Replica = "127.0.0.1:1000".parse().unwrap();
 
-    assert_eq!(replica.addr, "127.0.0.1:1000".parse().unwrap());
+    assert_eq!(replica.api, "127.0.0.1:1000".parse().unwrap());
+    assert_eq!(replica.peer_addr(), "127.0.0.1:1100".parse().unwrap());
+    assert_eq!(replica.monitor_addr(), "127.0.0.1:1200".parse().unwrap());
+}
+
+#[test]
+fn test_load_id() {
+    let dir = "test_data/replica_id";
+
+    if std::path::Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    let id = load_id(dir).unwrap();
+
+    assert_eq!(id.len(), 16);
+    assert_eq!(load_id(dir).unwrap(), id);
 }
@@ -0,0 +1,170 @@
+//! Routes commands and delegated data to the owners of Zones, see `shard`.
+//!
+//! Zones owned locally are served by `Manager`. For other Zones, `Cluster` forwards commands over
+//! the peer connection to an owner and relays its replies and bind updates back, and sends
+//! delegated data to the owner to be merged and replicated there. Owners are tried in score
+//! order. When none is reachable, commands fail, and delegated data is merged locally, see
+//! `Cluster::delegate`.
+
+use mioco::sync::mpsc::Sender;
+
+use app::AppHandle;
+use command::Command;
+use listener::RListener;
+use node::External;
+use path::Path;
+use replica::Replica;
+use wal::Hold;
+use zone::ZoneResult;
+
+/// Dispatches `command`, relative to Zone at `path`, to the Zone or the first reachable owner.
+pub fn dispatch(app: &AppHandle, path: &Path, command: Command, listener: &Sender<String>) -> ZoneResult {
+    let owners = remote_owners(app, path);
+
+    if owners.is_empty() {
+        return app.manager.load(path).dispatch(command, listener);
+    }
+
+    for owner in &owners {
+        // Closed if the owner is unreachable
+        if let Ok(result) = app.cluster.forward(owner, path, command.clone(), listener).recv() {
+            return result;
+        }
+    }
+
+    ZoneResult { error: Some("Zone owners unreachable".into()), ..Default::default() }
+}
+
+/// Routes delegated data to the correct `Zone`, keeping `hold` until merged.
+pub fn send_external(app: &AppHandle, prefix: &Path, external: External, replicate: bool, hold: Option<Hold>) {
+    let path = external_path(prefix, &external);
+    let owners = remote_owners(app, &path);
+
+    match owners.is_empty() {
+        true => app.manager.send_external(prefix, external, replicate, hold),
+        false => app.cluster.delegate(owners, &path, external.tree, vec![], hold)
+    }
+}
+
+/// Routes delegated data to the correct `Zone` with a list of listeners, which are then bound on
+/// the owner if remote.
+pub fn send_external_with_listeners(app: &AppHandle, prefix: &Path, external: External, listeners: Vec<RListener>, hold: Option<Hold>) {
+    let path = external_path(prefix, &external);
+    let owners = remote_owners(app, &path);
+
+    match owners.is_empty() {
+        true => app.manager.send_external_with_listeners(prefix, external, listeners, hold),
+        false => app.cluster.delegate(owners, &path, external.tree, listeners, hold)
+    }
+}
+
+fn external_path(prefix: &Path, external: &External) -> Path {
+    let mut path = prefix.clone();
+
+    path.append(&mut external.path.clone());
+    path
+}
+
+/// Owners of Zone at `path` in score order, none if owned locally.
+fn remote_owners(app: &AppHandle, path: &Path) -> Vec<Replica> {
+    let shards = app.shards.read().unwrap();
+
+    match shards.is_owner(path) {
+        true => vec![],
+        false => shards.owners(path).into_iter().cloned().collect()
+    }
+}
+
+#[test]
+fn test_router() {
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use serde_json;
+    use serde_json::Value;
+
+    use api::Api;
+    use app::App;
+    use cluster::Cluster;
+    use compression::Compression;
+    use manager::Manager;
+    use shard::Shards;
+    use store;
+    use store::memory::{Faults, Memory};
+
+    let start = |addr: &str| {
+        let mut app = App::new(addr.parse().unwrap());
+
+        app.shards = Arc::new(RwLock::new(Shards::new(1)));
+
+        store::start(Memory::new(app.handle(), Faults::new()), app.channels.store.take().unwrap());
+        Manager::spawn(&mut app);
+        Cluster::spawn(&mut app, Compression::None);
+        app.manager.load(&Path::empty());
+
+        app
+    };
+
+    let a = start("127.0.0.1:14051");
+    let b = start("127.0.0.1:14052");
+
+    a.cluster.add(b.id.clone());
+
+    let started = Instant::now();
+
+    while a.cluster.members().len() < 2 || b.cluster.members().len() < 2 {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Commands sent to the replica not owning the root Zone
+    let (origin, owner) = match a.shards.read().unwrap().is_owner(&Path::empty()) {
+        true => (&b, &a),
+        false => (&a, &b)
+    };
+
+    let api = Api::new(&origin.handle());
+    let (replies, subscription) = api.bind(&path![moo.cow]);
+
+    assert!(replies[0].result.is_ok());
+
+    api.write(&Path::empty(), serde_json::from_str(r#"{ "moo": { "cow": 42 } }"#).unwrap()).unwrap();
+
+    // Update relayed from the owner
+    let (path, update) = subscription.recv().unwrap();
+
+    assert_eq!(path, Path::empty());
+    assert_eq!(update[0]["moo"][0]["cow"][2], Value::from(42.0));
+
+    let replies = api.read(&path![moo.cow]);
+    let update = replies[0].result.as_ref().unwrap().as_ref().unwrap();
+
+    assert_eq!(update.to_json()[0]["moo"][0]["cow"][2], Value::from(42.0));
+
+    // Only the owner has the data
+    assert!(owner.manager.load(&Path::empty()).dump().node.len() > 0);
+    assert_eq!(origin.manager.load(&Path::empty()).dump().node.len(), 0);
+    assert!(origin.stats.cluster.commands_forwarded.value() >= 3);
+    assert!(owner.stats.cluster.commands_served.value() >= 3);
+
+    // Commands fail while no owner is reachable
+    use membership::Member;
+
+    let c = start("127.0.0.1:14053");
+    let local = Member::new(c.id.to_string(), c.id.clone());
+
+    let gone = (0..).map(|i| Member::new(format!("gone{}", i), "127.0.0.1:14054".parse().unwrap())).find(|gone| {
+        let mut shards = Shards::new(1);
+
+        shards.update(&local, vec![gone]);
+        ! shards.is_owner(&Path::empty())
+    }).unwrap();
+
+    c.shards.write().unwrap().update(&local, vec![&gone]);
+
+    let api = Api::new(&c.handle());
+
+    assert!(api.write(&Path::empty(), serde_json::from_str(r#"{ "moo": 1 }"#).unwrap()).is_err());
+    assert!(api.read(&path![moo])[0].result.is_err());
+}
@@ -1,4 +1,5 @@
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::thread;
 
 use mioco::tcp::TcpListener;
@@ -39,6 +40,9 @@ fn accept_loop(app: AppHandle, listener: TcpListener) {
         let stream = listener.accept();
 
         match stream {
+            Ok(ref stream) if app.stopping.load(Ordering::SeqCst) => {
+                println!("Refusing connection from {}, shutting down", stream.peer_addr().unwrap());
+            },
             Ok(stream) => {
                 // connection succeeded
                 println!("Connection from: {}", stream.peer_addr().unwrap());
@@ -0,0 +1,140 @@
+//! Assignment of Zones to owner replicas by rendezvous hashing.
+//!
+//! Each alive member scores every Zone `Path` by hashing its member id with the path, and the
+//! `owners` highest scoring members own the Zone. All members compute the same owners from the
+//! same membership, and a member joining or leaving only moves the Zones it gains or loses.
+//!
+//! Only owners store a Zone; other replicas keep it in memory while loaded. Changes are
+//! replicated to owners only.
+
+use std::hash::Hasher;
+
+use hash::Fnv;
+use membership::Member;
+use path::Path;
+use replica::Replica;
+
+/// Owners of each Zone unless configured.
+pub const DEFAULT_OWNERS: usize = 3;
+
+/// Shard assignment for the current membership, shared by Cluster and Zones.
+#[derive(Clone)]
+pub struct Shards {
+    owners: usize,                   // Per Zone, 0 for all members
+    local: Option<String>,           // Set once Cluster starts
+    members: Vec<(String, Replica)>  // Alive, including local, by id
+}
+
+impl Shards {
+    pub fn new(owners: usize) -> Shards {
+        Shards {
+            owners: owners,
+            local: None,
+            members: vec![]
+        }
+    }
+
+    /// Replaces the membership that Zones are assigned to.
+    pub fn update(&mut self, local: &Member, alive: Vec<&Member>) {
+        let mut members: Vec<_> = alive.iter().map(|m| (m.id.clone(), m.replica.clone())).collect();
+
+        members.push((local.id.clone(), local.replica.clone()));
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+
+        self.local = Some(local.id.clone());
+        self.members = members;
+    }
+
+    /// Replicas owning Zone at `path`, highest score first.
+    pub fn owners(&self, path: &Path) -> Vec<&Replica> {
+        let mut scored: Vec<_> = self.members.iter().map(|&(ref id, ref replica)| {
+            (score(id, path), id, replica)
+        }).collect();
+
+        scored.sort_by(|a, b| (b.0, b.1).cmp(&(a.0, a.1)));
+
+        if self.owners > 0 {
+            scored.truncate(self.owners);
+        }
+
+        scored.into_iter().map(|(_, _, replica)| replica).collect()
+    }
+
+    /// Returns true if the local replica owns Zone at `path`. Everything is owned locally until
+    /// membership is known.
+    pub fn is_owner(&self, path: &Path) -> bool {
+        let local = match self.local {
+            Some(ref local) => local,
+            None => return true
+        };
+
+        if self.owners == 0 || self.members.len() <= self.owners {
+            return true;
+        }
+
+        let local_score = score(local, path);
+        let higher = self.members.iter().filter(|&&(ref id, _)| (score(id, path), id) > (local_score, local)).count();
+
+        higher < self.owners
+    }
+}
+
+/// Hash of member id and path, the same on all members.
+fn score(id: &str, path: &Path) -> u64 {
+    let mut hasher = Fnv::new();
+
+    hasher.write(id.as_bytes());
+
+    for component in &path.path {
+        hasher.write(&[0]);
+        hasher.write(component.as_bytes());
+    }
+
+    hasher.finish()
+}
+
+#[test]
+fn test_shards() {
+    let member = |id: &str, port: u16| Member::new(id.into(), format!("127.0.0.1:{}", port).parse().unwrap());
+
+    let mut shards = Shards::new(2);
+    let local = member("a", 1000);
+
+    // Single replica until membership is known
+    assert!(shards.is_owner(&path![moo]));
+
+    let members = vec![member("b", 1001), member("c", 1002), member("d", 1003)];
+
+    shards.update(&local, members.iter().collect());
+
+    let paths: Vec<Path> = (0..100).map(|i| Path::new(vec![i.to_string()])).collect();
+    let owned = paths.iter().filter(|p| shards.is_owner(p)).count();
+
+    for path in &paths {
+        let owners = shards.owners(path);
+
+        assert_eq!(owners.len(), 2);
+        assert_eq!(shards.is_owner(path), owners.contains(&&local.replica));
+    }
+
+    // Roughly half of the Zones
+    assert!(owned > 20 && owned < 80);
+
+    // Zones not owned by a leaving member keep their owners
+    let before: Vec<Vec<Replica>> = paths.iter().map(|p| shards.owners(p).into_iter().cloned().collect()).collect();
+
+    shards.update(&local, members[..2].iter().collect());
+
+    for (path, before) in paths.iter().zip(before) {
+        if ! before.contains(&members[2].replica) {
+            assert_eq!(shards.owners(path), before.iter().collect::<Vec<_>>());
+        }
+    }
+
+    // Everything is owned by all
+    let mut shards = Shards::new(0);
+
+    shards.update(&local, members.iter().collect());
+
+    assert!(paths.iter().all(|p| shards.is_owner(p) && shards.owners(p).len() == 4));
+}
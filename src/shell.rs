@@ -1,17 +1,38 @@
+use std::fs::{self, File};
+use std::io;
 use std::io::prelude::*;
-use std::process;
+
+use serde_json;
 
 use app::{App, AppHandle};
+use export;
+use shutdown;
+use snapshot;
 use path::Path;
+use store;
+use store::encryption::Keyring;
 
 struct Shell<W> {
     app: AppHandle,
+    remote: bool,  // `exit` and `quit` end the session instead of shutting down
     writer: W
 }
 
 pub fn start<R: BufRead, W: Write>(app: App, reader: R, writer: W) {
     let mut s = Shell {
         app: app.handle(),
+        remote: false,
+        writer: writer
+    };
+
+    s.command_loop(reader);
+}
+
+/// Runs a remote session, e.g. from the admin socket.
+pub fn session<R: BufRead, W: Write>(app: AppHandle, reader: R, writer: W) {
+    let mut s = Shell {
+        app: app,
+        remote: true,
         writer: writer
     };
 
@@ -19,9 +40,12 @@ pub fn start<R: BufRead, W: Write>(app: App, reader: R, writer: W) {
 }
 
 impl<W: Write> Shell<W> {
+    /// Reads commands until the input ends, or output fails, e.g. when a remote session
+    /// disconnects.
     pub fn command_loop<R: BufRead>(&mut self, reader: R) {
-        self.writer.write(b"> ").unwrap();
-        self.writer.flush().unwrap();
+        if let Err(err) = self.prompt() {
+            return println!("Shell output failed: {}", err);
+        }
 
         // Read loop
         for line in reader.lines() {
@@ -29,66 +53,169 @@ impl<W: Write> Shell<W> {
 
                 let mut line = line.splitn(2, ' ');
 
-                match line.next() {
+                let result = match line.next() {
                     Some("active") => self.active(),
+                    Some("cluster.members") => self.members(),
+                    Some("cluster.peers") => self.peers(),
                     Some("cluster.sync") => self.sync(),
                     Some("cluster.sync_all") => self.sync_all(),
+                    Some("export") => self.export(line.next().unwrap_or_default()),
+                    Some("import") => self.import(line.next().unwrap_or_default()),
                     Some("store.dump") => self.store_dump(line.next().unwrap_or_default()),
+                    Some("store.migrate") => self.store_migrate(line.next().unwrap_or_default()),
+                    Some("store.reencrypt") => self.store_reencrypt(line.next().unwrap_or_default()),
+                    Some("snapshot") => self.snapshot(line.next().unwrap_or_default()),
                     Some("stats") => self.stats(),
+                    Some("zone.clear") => self.zone_clear(line.next().unwrap_or_default()),
                     Some("zone.dump") => self.zone_dump(line.next().unwrap_or_default()),
+                    Some("zone.errors") => self.zone_errors(),
                     Some("zone.sync") => self.zone_sync(line.next().unwrap_or_default()),
+                    Some("exit") | Some("quit") if self.remote => return,
                     Some("exit") | Some("quit") | Some("shutdown") => self.shutdown(),
-                    Some("") => (),
-                    _ => writeln!(self.writer, "Bad command").unwrap()
-                }
+                    Some("") => Ok(()),
+                    _ => writeln!(self.writer, "Bad command")
+                };
 
-                self.writer.write(b"> ").unwrap();
-                self.writer.flush().unwrap();
+                if let Err(err) = result.and_then(|_| self.prompt()) {
+                    return println!("Shell output failed: {}", err);
+                }
             }
         }
     }
 
-    fn active(&mut self) {
+    fn prompt(&mut self) -> io::Result<()> {
+        try!(self.writer.write_all(b"> "));
+        self.writer.flush()
+    }
+
+    fn active(&mut self) -> io::Result<()> {
         let active_zones = self.app.manager.list();
         let len = active_zones.len();
 
-        writeln!(self.writer, "Active Zones:").unwrap();
+        try!(writeln!(self.writer, "Active Zones:"));
 
         for z in active_zones {
             let path = z.path().path.join(".");
             let size = z.size();
             let state = z.state();
 
-            writeln!(self.writer, "{:>8} {:?} {:?}", size, state, path).unwrap();
+            try!(writeln!(self.writer, "{:>8} {:?} {:?}", size, state, path));
+        }
+
+        writeln!(self.writer, "Total: {} active zones", len)
+    }
+
+    fn members(&mut self) -> io::Result<()> {
+        let members = self.app.cluster.members();
+
+        try!(writeln!(self.writer, "Members:"));
+
+        for m in &members {
+            try!(writeln!(self.writer, "  {} {} {:?} incarnation {}", m.id, m.replica, m.state, m.incarnation));
+        }
+
+        writeln!(self.writer, "Total: {} members", members.len())
+    }
+
+    fn peers(&mut self) -> io::Result<()> {
+        let peers = self.app.cluster.peers();
+
+        try!(writeln!(self.writer, "Peers:"));
+
+        for p in &peers {
+            let contact = p.last_contact_ms.map_or("never".into(), |ms| format!("{}ms ago", ms));
+
+            try!(writeln!(self.writer, "  {} {:?} failures {} last contact {}", p.replica.peer_addr(), p.health, p.failures, contact));
         }
 
-        writeln!(self.writer, "Total: {} active zones", len).unwrap();
+        writeln!(self.writer, "Total: {} peers", peers.len())
     }
 
-    fn shutdown(&mut self) {
-        writeln!(self.writer, "Shutting down...").unwrap();
+    /// Exports stored data to a JSON file: `export <file> [lossless]`
+    fn export(&mut self, args: &str) -> io::Result<()> {
+        let mut args = args.split(' ');
+        let filename = args.next().unwrap_or_default();
+        let lossless = args.next() == Some("lossless");
+
+        if filename.is_empty() {
+            return writeln!(self.writer, "Usage: export <file> [lossless]");
+        }
+
+        let data = export::export(&self.app.store, lossless);
 
-        // TODO: exit is not clean, destructors not called, files/sockets not flushed
-        process::exit(0);
+        match File::create(filename).map_err(|e| e.to_string()).and_then(|f| {
+            serde_json::to_writer(f, &data).map_err(|e| e.to_string())
+        }) {
+            Err(err) => writeln!(self.writer, "Export failed: {}", err),
+            Ok(_) => writeln!(self.writer, "Exported to {}", filename)
+        }
     }
 
-    fn stats(&mut self) {
+    /// Imports data from a JSON file: `import <file> [lossless]`
+    fn import(&mut self, args: &str) -> io::Result<()> {
+        let mut args = args.split(' ');
+        let filename = args.next().unwrap_or_default();
+        let lossless = args.next() == Some("lossless");
+
+        if filename.is_empty() {
+            return writeln!(self.writer, "Usage: import <file> [lossless]");
+        }
+
+        let result = File::open(filename)
+            .map_err(|e| e.to_string())
+            .and_then(|f| serde_json::from_reader(f).map_err(|e| e.to_string()))
+            .and_then(|data| export::import(&self.app, data, lossless));
+
+        match result {
+            Err(err) => writeln!(self.writer, "Import failed: {}", err),
+            Ok(_) => writeln!(self.writer, "Imported {}", filename)
+        }
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        try!(writeln!(self.writer, "Shutting down..."));
+        try!(self.writer.flush());
+
+        shutdown::shutdown(&self.app);
+
+        Ok(())
+    }
+
+    /// Snapshots stored data into an empty directory: `snapshot <dir>`
+    fn snapshot(&mut self, dir: &str) -> io::Result<()> {
+        if dir.is_empty() {
+            return writeln!(self.writer, "Usage: snapshot <dir>");
+        }
+
+        try!(writeln!(self.writer, "Snapshotting to {}...", dir));
+
+        match snapshot::create(&self.app, dir) {
+            Err(err) => writeln!(self.writer, "Snapshot failed: {}", err),
+            Ok(manifest) => writeln!(self.writer, "Snapshot of {} files created", manifest.files.len())
+        }
+    }
+
+    fn stats(&mut self) -> io::Result<()> {
         use serde_json;
 
-        writeln!(self.writer, "{}", serde_json::to_string_pretty(&*self.app.stats).unwrap()).unwrap();
+        writeln!(self.writer, "{}", serde_json::to_string_pretty(&*self.app.stats).unwrap())
     }
 
-    fn sync(&mut self) {
-        writeln!(self.writer, "Synchronizing local data with cluster...").unwrap();
+    fn sync(&mut self) -> io::Result<()> {
+        try!(writeln!(self.writer, "Synchronizing local data with cluster..."));
         self.app.cluster.sync();
+
+        Ok(())
     }
 
-    fn sync_all(&mut self) {
-        writeln!(self.writer, "Synchronizing cluster data...").unwrap();
+    fn sync_all(&mut self) -> io::Result<()> {
+        try!(writeln!(self.writer, "Synchronizing cluster data..."));
         self.app.cluster.sync_all();
+
+        Ok(())
     }
 
-    fn store_dump(&mut self, path: &str) {
+    fn store_dump(&mut self, path: &str) -> io::Result<()> {
         let path = match path {
             "" => Path::new(vec![]),
             _ => Path::new(path.split('.').map(|s| s.into()).collect())
@@ -97,10 +224,106 @@ impl<W: Write> Shell<W> {
         match self.app.store.load_data(path.clone()) {
             None => writeln!(self.writer, "Could not load {:?}", path),
             Some(data) => writeln!(self.writer, "Store data: {:?}", data)
-        }.unwrap();
+        }
     }
 
-    fn zone_dump(&mut self, path: &str) {
+    /// Upgrades an offline FS data directory to the current format version:
+    /// `store.migrate <dir> [keyfile]`
+    fn store_migrate(&mut self, args: &str) -> io::Result<()> {
+        let mut args = args.split(' ');
+        let dir = args.next().unwrap_or_default();
+
+        if dir.is_empty() {
+            return writeln!(self.writer, "Usage: store.migrate <dir> [keyfile]");
+        }
+
+        if self.is_data_dir(dir) {
+            return writeln!(self.writer, "{} is in use by this node, migrate it while stopped", dir);
+        }
+
+        let keys = match args.next().map_or(Ok(Default::default()), Keyring::load) {
+            Err(err) => return writeln!(self.writer, "{}", err),
+            Ok(keys) => keys
+        };
+
+        try!(writeln!(self.writer, "Migrating {} to format version {}...", dir, store::format::VERSION));
+
+        match store::fs::migrate_dir(dir, &keys) {
+            Err(err) => writeln!(self.writer, "Migration failed: {}", err),
+            Ok(result) => writeln!(self.writer, "Migrated {} zone files, quarantined {} corrupted, skipped {} unreadable",
+                result.rewritten, result.quarantined, result.skipped)
+        }
+    }
+
+    /// Rewrites an offline FS data directory with the last key in a key file, or decrypted without
+    /// one: `store.reencrypt <dir> [keyfile]`
+    fn store_reencrypt(&mut self, args: &str) -> io::Result<()> {
+        let mut args = args.split(' ');
+        let dir = args.next().unwrap_or_default();
+
+        if dir.is_empty() {
+            return writeln!(self.writer, "Usage: store.reencrypt <dir> [keyfile]");
+        }
+
+        if self.is_data_dir(dir) {
+            return writeln!(self.writer, "{} is in use by this node, re-encrypt it while stopped", dir);
+        }
+
+        let keys = match args.next().map_or(Ok(Default::default()), Keyring::load) {
+            Err(err) => return writeln!(self.writer, "{}", err),
+            Ok(keys) => keys
+        };
+
+        try!(match keys.current() {
+            None => writeln!(self.writer, "Decrypting {}...", dir),
+            Some(key) => writeln!(self.writer, "Encrypting {} with key {}...", dir, key.id)
+        });
+
+        match store::fs::reencrypt_dir(dir, &keys) {
+            Err(err) => writeln!(self.writer, "Re-encryption failed: {}", err),
+            Ok(result) => writeln!(self.writer, "Rewrote {} zone files, quarantined {} corrupted, skipped {} unreadable",
+                result.rewritten, result.quarantined, result.skipped)
+        }
+    }
+
+    /// Whether `dir` is the data directory of the running Store, which must not be rewritten.
+    fn is_data_dir(&self, dir: &str) -> bool {
+        match (fs::canonicalize(dir), fs::canonicalize(&*self.app.data_dir)) {
+            (Ok(dir), Ok(data_dir)) => dir == data_dir,
+            _ => false
+        }
+    }
+
+    /// Retries loading an errored zone now: `zone.clear <path>`
+    fn zone_clear(&mut self, path: &str) -> io::Result<()> {
+        let path = match path {
+            "" => Path::new(vec![]),
+            _ => Path::new(path.split('.').map(|s| s.into()).collect())
+        };
+
+        match self.app.manager.list().into_iter().find(|z| z.path() == path && z.state().is_error()) {
+            None => writeln!(self.writer, "Zone {:?} is not in error state", path),
+            Some(zone) => {
+                zone.clear_error();
+                writeln!(self.writer, "Retrying zone {:?}", path)
+            }
+        }
+    }
+
+    fn zone_errors(&mut self) -> io::Result<()> {
+        let mut count = 0;
+
+        for z in self.app.manager.list() {
+            if let Some(error) = z.error() {
+                try!(writeln!(self.writer, "{:?}: {}", z.path().path.join("."), error));
+                count += 1;
+            }
+        }
+
+        writeln!(self.writer, "Total: {} errored zones", count)
+    }
+
+    fn zone_dump(&mut self, path: &str) -> io::Result<()> {
         let path = match path {
             "" => Path::new(vec![]),
             _ => Path::new(path.split('.').map(|s| s.into()).collect())
@@ -110,16 +333,18 @@ impl<W: Write> Shell<W> {
 
         let data = zone.dump();
 
-        writeln!(self.writer, "Zone data: {:#?}", data).unwrap();
+        writeln!(self.writer, "Zone data: {:#?}", data)
     }
 
-    fn zone_sync(&mut self, path: &str) {
+    fn zone_sync(&mut self, path: &str) -> io::Result<()> {
         let path = match path {
             "" => Path::new(vec![]),
             _ => Path::new(path.split('.').map(|s| s.into()).collect())
         };
 
-        writeln!(self.writer, "Synchronizing zone {:#?}...", &path).unwrap();
+        try!(writeln!(self.writer, "Synchronizing zone {:#?}...", &path));
         self.app.cluster.sync_zone(path);
+
+        Ok(())
     }
 }
@@ -0,0 +1,134 @@
+//! Coordinated shutdown, from the shell or on SIGINT/SIGTERM.
+//!
+//! New clients and writes are refused, Peers are told we are leaving, messages queued for them are
+//! sent and dirty Zones are saved before the process exits. Waiting is bounded by `TIMEOUT_SECS` so that an unreachable
+//! Peer cannot prevent shutdown; a second signal exits immediately.
+
+use std;
+use std::process;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ctrlc;
+
+use app::AppHandle;
+
+const TIMEOUT_SECS: u64 = 30;
+
+/// Shuts down `app` cleanly and exits, with status 1 if anything could not be saved or sent in
+/// time. Exits immediately if shutdown is already in progress.
+pub fn shutdown(app: &AppHandle) -> ! {
+    if app.stopping.swap(true, Ordering::SeqCst) {
+        println!("Shutdown already in progress, exiting now");
+        process::exit(1);
+    }
+
+    let clean = drain(app, Duration::from_secs(TIMEOUT_SECS));
+
+    if let Some(ref filename) = app.pid_file {
+        std::fs::remove_file(&**filename).is_ok(); // ignore if already gone
+    }
+
+    println!("Shutdown {}", if clean { "complete" } else { "incomplete" });
+    process::exit(if clean { 0 } else { 1 });
+}
+
+/// Stops accepting clients and writes, then waits up to `timeout` for Peer queues to drain and
+/// all Zones to be saved. Store writes are paused afterwards. Returns false on timeout.
+pub fn drain(app: &AppHandle, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+
+    app.stopping.store(true, Ordering::SeqCst);
+    app.cluster.leave();
+
+    println!("Draining peer queues...");
+
+    let mut clean = app.cluster.drain(remaining(deadline));
+
+    if ! clean {
+        println!("  Timed out, some changes were not replicated");
+    }
+
+    let zones = app.manager.list();
+
+    println!("Saving {} zones...", zones.len());
+
+    let unsaved = zones.iter().filter(|zone| ! zone.flush(remaining(deadline))).count();
+
+    if unsaved > 0 {
+        println!("  Timed out, {} zones not saved", unsaved);
+        clean = false;
+    }
+
+    // Waits for writes in progress
+    app.store.pause();
+
+    clean
+}
+
+/// Shuts down on SIGINT or SIGTERM.
+pub fn handle_signals(app: &AppHandle) {
+    let app = app.clone();
+
+    ctrlc::set_handler(move || {
+        let app = app.clone();
+
+        println!("Signal received, shutting down...");
+
+        // Handler is not called again until it returns, so shut down elsewhere
+        thread::spawn(move || shutdown(&app));
+    }).expect("Cannot handle signals");
+}
+
+fn remaining(deadline: Instant) -> Duration {
+    let now = Instant::now();
+
+    match now < deadline {
+        true => deadline - now,
+        false => Duration::from_secs(0)
+    }
+}
+
+#[test]
+fn test_drain() {
+    use mioco::sync::mpsc::channel;
+
+    use app::App;
+    use cluster::Cluster;
+    use command::Command;
+    use compression::Compression;
+    use manager::Manager;
+    use path::Path;
+    use store;
+    use store::memory::{Faults, Memory};
+
+    let mut app = App::new("127.0.0.1:13911".parse().unwrap());
+    let faults = Faults::new();
+
+    // Slow writes, so the Zone is still being saved when draining
+    faults.set_latency(Duration::from_millis(200));
+
+    store::start(Memory::new(app.handle(), faults), app.channels.store.take().unwrap());
+    Manager::spawn(&mut app);
+    Cluster::spawn(&mut app, Compression::None);
+
+    let (tx, _rx) = channel();
+    let zone = app.manager.load(&Path::empty());
+    let start = Instant::now();
+
+    zone.dispatch(Command::from_json(r#"[ 1, "write", [], { "cow": 42 } ]"#).unwrap(), &tx);
+
+    while ! zone.state().is_ready() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert!(zone.state().is_dirty() || zone.state().is_writing());
+    assert!(drain(&app.handle(), Duration::from_secs(5)));
+    assert!(app.stopping.load(Ordering::SeqCst));
+
+    let state = zone.state();
+
+    assert!(! state.is_dirty() && ! state.is_writing());
+}
@@ -0,0 +1,252 @@
+//! Consistent point-in-time snapshots of stored data.
+//!
+//! A snapshot is taken when all Zones are clean: dirty Zones are flushed, then Store writes are
+//! paused. If any Zone changed in between, writes are resumed and the process retried. While
+//! paused, stored files are hard linked (or copied) into the snapshot directory along with a
+//! manifest listing each file's size and checksum.
+//!
+//! Snapshots are restored at startup, before the Store is spawned. A marker in the data directory
+//! names the restored snapshot, so that it is not restored again on the next start.
+
+use std;
+use std::fs::{DirBuilder, File};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+use app::AppHandle;
+use replica;
+use store;
+use store::format::{self, crc32};
+
+pub const MANIFEST: &'static str = "manifest.json";
+
+/// Written to the data directory once a snapshot is restored, see `restore`.
+pub const RESTORED_FILE: &'static str = "restored_snapshot";
+
+/// Version of the manifest.
+pub const VERSION: u64 = 1;
+
+/// Times to retry if Zones change before writes are paused.
+const ATTEMPTS: usize = 10;
+
+const FLUSH_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Manifest {
+    pub version: u64,
+    pub created: u64, // Unix time
+    pub format: u16,  // Zone file format version
+    pub files: Vec<ManifestFile>
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct ManifestFile {
+    pub name: String,
+    pub size: u64,
+    pub checksum: u32
+}
+
+/// Snapshots all stored data into `dir`, which must be empty or not exist.
+pub fn create(app: &AppHandle, dir: &str) -> Result<Manifest, String> {
+    let dir = PathBuf::from(dir);
+
+    if dir.is_dir() {
+        let mut entries = try!(std::fs::read_dir(&dir).map_err(|e| e.to_string()));
+
+        if entries.next().is_some() {
+            return Err(format!("{} is not empty", dir.display()));
+        }
+    }
+
+    try!(DirBuilder::new().recursive(true).create(&dir).map_err(|e| e.to_string()));
+
+    for _ in 0..ATTEMPTS {
+        for zone in app.manager.list() {
+            if ! zone.flush(Duration::from_secs(FLUSH_TIMEOUT_SECS)) {
+                return Err(format!("Timed out flushing {:?}", zone.path()));
+            }
+        }
+
+        app.store.pause();
+
+        let clean = app.manager.list().iter().all(|zone| {
+            let state = zone.state();
+
+            ! state.is_dirty() && ! state.is_writing()
+        });
+
+        if clean {
+            let result = app.store.snapshot(&dir).and_then(|files| write_manifest(&dir, files));
+
+            app.store.resume();
+
+            return result;
+        }
+
+        app.store.resume();
+    }
+
+    Err("Zones kept changing, try again later".into())
+}
+
+/// Verifies snapshot in `dir` and restores it to `data_dir`. Existing data is moved aside, keeping
+/// the replica id. Returns the number of files restored, or `None` if this snapshot was restored
+/// to `data_dir` before.
+pub fn restore(dir: &str, data_dir: &str) -> Result<Option<usize>, String> {
+    let dir = PathBuf::from(dir);
+    let data_dir = PathBuf::from(data_dir);
+    let manifest = try!(read_manifest(&dir));
+    let marker = format!("{} {}\n", dir.display(), manifest.created);
+
+    if read(&data_dir.join(RESTORED_FILE)).ok().map_or(false, |m| m == marker.as_bytes()) {
+        return Ok(None);
+    }
+
+    for file in &manifest.files {
+        try!(verify(&dir, file));
+    }
+
+    let backup = PathBuf::from(format!("{}.before_restore_{}", data_dir.display(), now()));
+
+    if data_dir.exists() {
+        println!("Moving {} to {}", data_dir.display(), backup.display());
+        try!(std::fs::rename(&data_dir, &backup).map_err(|e| e.to_string()));
+    }
+
+    try!(DirBuilder::new().recursive(true).create(&data_dir).map_err(|e| e.to_string()));
+
+    if backup.join(replica::ID_FILE).exists() {
+        try!(std::fs::copy(backup.join(replica::ID_FILE), data_dir.join(replica::ID_FILE)).map_err(|e| e.to_string()));
+    }
+
+    for file in &manifest.files {
+        try!(std::fs::copy(dir.join(&file.name), data_dir.join(&file.name)).map_err(|e| e.to_string()));
+    }
+
+    let file = try!(File::create(data_dir.join(RESTORED_FILE)).map_err(|e| e.to_string()));
+
+    try!((&file).write_all(marker.as_bytes()).and_then(|_| file.sync_all()).map_err(|e| e.to_string()));
+    try!(store::sync_dir(&data_dir).map_err(|e| e.to_string()));
+
+    Ok(Some(manifest.files.len()))
+}
+
+fn write_manifest(dir: &std::path::Path, files: Vec<String>) -> Result<Manifest, String> {
+    let mut manifest = Manifest {
+        version: VERSION,
+        created: now(),
+        format: format::VERSION,
+        files: vec![]
+    };
+
+    for name in files {
+        let bytes = try!(read(&dir.join(&name)));
+
+        manifest.files.push(ManifestFile {
+            name: name,
+            size: bytes.len() as u64,
+            checksum: crc32(&bytes)
+        });
+    }
+
+    let file = try!(File::create(dir.join(MANIFEST)).map_err(|e| e.to_string()));
+
+    try!(serde_json::to_writer_pretty(&file, &manifest).map_err(|e| e.to_string()));
+    try!(file.sync_all().map_err(|e| e.to_string()));
+    try!(store::sync_dir(dir).map_err(|e| e.to_string()));
+
+    Ok(manifest)
+}
+
+fn read_manifest(dir: &std::path::Path) -> Result<Manifest, String> {
+    let file = try!(File::open(dir.join(MANIFEST)).map_err(|e| format!("Cannot open manifest: {}", e)));
+    let manifest: Manifest = try!(serde_json::from_reader(file).map_err(|e| format!("Bad manifest: {}", e)));
+
+    if manifest.version > VERSION {
+        return Err(format!("Unsupported manifest version {}", manifest.version));
+    }
+
+    Ok(manifest)
+}
+
+fn verify(dir: &std::path::Path, file: &ManifestFile) -> Result<(), String> {
+    let bytes = try!(read(&dir.join(&file.name)));
+
+    if bytes.len() as u64 != file.size || crc32(&bytes) != file.checksum {
+        return Err(format!("{} does not match manifest", file.name));
+    }
+
+    Ok(())
+}
+
+fn read(filepath: &std::path::Path) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+
+    try!(File::open(filepath).and_then(|mut f| f.read_to_end(&mut bytes)).map_err(|e| {
+        format!("Cannot read {}: {}", filepath.display(), e)
+    }));
+
+    Ok(bytes)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[test]
+fn test_snapshot_restore() {
+    use std::thread;
+    use std::time::Instant;
+
+    use mioco::sync::mpsc::channel;
+
+    use app::App;
+    use command::Command;
+    use compression::Compression;
+    use manager::Manager;
+    use path::Path;
+    use store::Durability;
+    use store::fs::FS;
+
+    let dir = "test_data/snapshot";
+
+    if std::path::Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    let mut app = App::new("127.0.0.1:1000".parse().unwrap());
+
+    store::start(FS::new(app.handle(), "test_data/snapshot/data", Durability::None, Compression::None, Default::default()), app.channels.store.take().unwrap());
+    Manager::spawn(&mut app);
+
+    let (tx, _rx) = channel();
+    let zone = app.manager.load(&Path::empty());
+
+    zone.dispatch(Command::from_json(r#"[ 1, "write", [], { "cow": 42 } ]"#).unwrap(), &tx);
+
+    let start = Instant::now();
+
+    while ! zone.state().is_ready() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Zone is flushed before snapshotting
+    let manifest = create(&app.handle(), "test_data/snapshot/snap").unwrap();
+
+    // Index and Zone file
+    assert_eq!(manifest.files.len(), 2);
+    assert!(create(&app.handle(), "test_data/snapshot/snap").is_err());
+
+    assert_eq!(restore("test_data/snapshot/snap", "test_data/snapshot/restored").unwrap(), Some(2));
+
+    // Only once
+    assert_eq!(restore("test_data/snapshot/snap", "test_data/snapshot/restored").unwrap(), None);
+
+    let restored = read(&PathBuf::from("test_data/snapshot/restored").join(&manifest.files[0].name)).unwrap();
+
+    assert_eq!(crc32(&restored), manifest.files[0].checksum);
+}
@@ -0,0 +1,167 @@
+//! Authenticated encryption of Zone files at rest.
+//!
+//! Keys are 256 bit ChaCha20-Poly1305 keys, identified by a number stored in each file's header.
+//! A key file lists one key per line as `<id> <64 hex digits>`; the last key is used for writing,
+//! while all keys can be read. To rotate, append a new key, restart, and re-encrypt the data
+//! directory before removing the old key.
+
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+
+use chacha20poly1305;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, Payload};
+use rand::{OsRng, Rng};
+
+const NONCE_LEN: usize = 12;
+
+pub struct Key {
+    pub id: u32,
+    secret: [u8; 32]
+}
+
+/// Keys available for reading, the last of which is used for writing.
+#[derive(Default)]
+pub struct Keyring {
+    keys: Vec<Key>
+}
+
+impl Key {
+    pub fn new(id: u32, secret: [u8; 32]) -> Key {
+        Key { id: id, secret: secret }
+    }
+
+    /// Encrypts `plaintext`, returning a random nonce followed by ciphertext. `aad` is
+    /// authenticated but not encrypted.
+    pub fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+
+        OsRng::new().expect("No OS random number generator").fill_bytes(&mut nonce);
+
+        let payload = Payload { msg: plaintext, aad: aad };
+        let ciphertext = self.cipher().encrypt(Nonce::from_slice(&nonce), payload).expect("Encryption failed");
+
+        let mut bytes = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&ciphertext);
+
+        bytes
+    }
+
+    /// Decrypts output of `encrypt`. Fails if data or `aad` were modified, or the key differs.
+    pub fn decrypt(&self, bytes: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        if bytes.len() < NONCE_LEN {
+            return None;
+        }
+
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let payload = Payload { msg: ciphertext, aad: aad };
+
+        self.cipher().decrypt(Nonce::from_slice(nonce), payload).ok()
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&self.secret))
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key({})", self.id)
+    }
+}
+
+impl Keyring {
+    pub fn new(keys: Vec<Key>) -> Keyring {
+        Keyring { keys: keys }
+    }
+
+    /// Loads keys from a key file.
+    pub fn load(filename: &str) -> Result<Keyring, String> {
+        let mut contents = String::new();
+
+        try!(File::open(filename).and_then(|mut f| f.read_to_string(&mut contents)).map_err(|e| {
+            format!("Cannot read key file {}: {}", filename, e)
+        }));
+
+        Keyring::parse(&contents)
+    }
+
+    /// Parses keys, one `<id> <hex secret>` per line. Empty lines and `#` comments are ignored.
+    pub fn parse(s: &str) -> Result<Keyring, String> {
+        let mut keys: Vec<Key> = vec![];
+
+        for line in s.lines().map(|l| l.trim()).filter(|l| ! l.is_empty() && ! l.starts_with('#')) {
+            let mut parts = line.split_whitespace();
+
+            let id: u32 = try!(parts.next().unwrap_or_default().parse().map_err(|_| format!("Bad key id in {:?}", line)));
+            let secret = try!(parse_hex(parts.next().unwrap_or_default()).ok_or(format!("Bad secret for key {}", id)));
+
+            if keys.iter().any(|k| k.id == id) {
+                return Err(format!("Duplicate key id {}", id));
+            }
+
+            keys.push(Key::new(id, secret));
+        }
+
+        Ok(Keyring::new(keys))
+    }
+
+    /// Key used for writing, if encryption is enabled.
+    pub fn current(&self) -> Option<&Key> {
+        self.keys.last()
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Key> {
+        self.keys.iter().find(|k| k.id == id)
+    }
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.keys.iter().map(|k| k.id)).finish()
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<[u8; 32]> {
+    let mut secret = [0u8; 32];
+
+    if hex.len() != 64 || ! hex.is_ascii() {
+        return None;
+    }
+
+    for (i, byte) in secret.iter_mut().enumerate() {
+        *byte = match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+            Ok(b) => b,
+            Err(_) => return None
+        };
+    }
+
+    Some(secret)
+}
+
+#[test]
+fn test_encryption() {
+    let keys = Keyring::parse("
+        # old
+        1 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f
+        7 1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100
+    ").unwrap();
+
+    assert_eq!(keys.current().unwrap().id, 7);
+    assert_eq!(format!("{:?}", keys), "[1, 7]");
+
+    let encrypted = keys.current().unwrap().encrypt(b"moo cow", b"header");
+
+    assert_eq!(keys.get(7).unwrap().decrypt(&encrypted, b"header"), Some(b"moo cow".to_vec()));
+    assert_eq!(keys.get(7).unwrap().decrypt(&encrypted, b"other"), None);
+    assert_eq!(keys.get(1).unwrap().decrypt(&encrypted, b"header"), None);
+
+    // Nonces are random
+    assert!(keys.current().unwrap().encrypt(b"moo cow", b"header") != encrypted);
+
+    assert!(Keyring::parse("1 abc").is_err());
+    assert!(Keyring::parse("x 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").is_err());
+}
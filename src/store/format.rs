@@ -0,0 +1,329 @@
+//! On-disk format of persisted Zone files.
+//!
+//! Each file starts with a header, followed by the payload (serialized `ZoneData`):
+//!
+//! ```text
+//! magic     4 bytes  "QZON"
+//! version   u16 LE   format version
+//! flags     u16 LE   `COMPRESSED` and/or `ENCRYPTED` (since version 2)
+//! key       u32 LE   id of the encryption key (since version 3)
+//! length    u64 LE   stored payload length
+//! checksum  u32 LE   CRC-32 of stored payload
+//! ```
+//!
+//! Payloads are compressed before being encrypted. The header up to the key id is authenticated
+//! by encryption.
+//!
+//! Files without the magic number predate the header and are treated as version 0, i.e. the
+//! entire file is the payload and cannot be verified.
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+
+use compression::{self, Compression};
+use super::encryption::{Key, Keyring};
+
+pub const MAGIC: &'static [u8; 4] = b"QZON";
+
+/// Current format version.
+pub const VERSION: u16 = 3;
+
+pub const HEADER_LEN: usize = 24;
+
+/// Length of the header prefix authenticated by encryption.
+const AAD_LEN: usize = 12;
+
+/// Payload is deflated.
+pub const COMPRESSED: u16 = 0x01;
+
+/// Payload is encrypted with the key in the header.
+pub const ENCRYPTED: u16 = 0x02;
+
+/// Largest payload accepted when decompressing.
+const MAX_PAYLOAD: usize = 1 << 30;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub version: u16,
+    pub flags: u16,
+    pub key: u32,
+    pub length: u64,
+    pub checksum: u32
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FormatError {
+    /// File is shorter than its header claims, e.g. from a torn write.
+    Truncated { expected: u64, actual: u64 },
+    /// Payload does not match its checksum.
+    Checksum { expected: u32, actual: u32 },
+    /// Written by a newer version.
+    Version(u16),
+    /// Compressed payload could not be inflated.
+    Compression(String),
+    /// Encrypted with a key that is not available.
+    UnknownKey(u32),
+    /// Encrypted payload failed authentication, i.e. the key is wrong or data was tampered with.
+    Decryption(u32)
+}
+
+/// Prepends a header to `payload`, compressed if requested and smaller, and encrypted with `key`.
+pub fn encode(payload: &[u8], compression: Compression, key: Option<&Key>) -> Vec<u8> {
+    let mut header = Header {
+        version: VERSION,
+        flags: 0,
+        key: key.map_or(0, |k| k.id),
+        length: 0,
+        checksum: 0
+    };
+
+    let mut payload = Cow::Borrowed(payload);
+
+    if compression.enabled() {
+        let compressed = compression::compress(&payload);
+
+        if compressed.len() < payload.len() {
+            header.flags |= COMPRESSED;
+            payload = Cow::Owned(compressed);
+        }
+    }
+
+    if let Some(key) = key {
+        header.flags |= ENCRYPTED;
+        payload = Cow::Owned(key.encrypt(&payload, &header.aad()));
+    }
+
+    header.length = payload.len() as u64;
+    header.checksum = crc32(&payload);
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+
+    header.write(&mut bytes);
+    bytes.extend_from_slice(&payload);
+
+    bytes
+}
+
+/// Verifies `bytes` against its header, returning the header and decrypted, decompressed payload.
+pub fn decode<'a>(bytes: &'a [u8], keys: &Keyring) -> Result<(Header, Cow<'a, [u8]>), FormatError> {
+    if ! bytes.starts_with(MAGIC) {
+        let header = Header { version: 0, flags: 0, key: 0, length: bytes.len() as u64, checksum: 0 };
+
+        return Ok((header, Cow::Borrowed(bytes)));
+    }
+
+    if bytes.len() < 6 {
+        return Err(FormatError::Truncated { expected: 6, actual: bytes.len() as u64 });
+    }
+
+    let version = read_le(&bytes[4..6]) as u16;
+
+    if version > VERSION {
+        return Err(FormatError::Version(version));
+    }
+
+    let header_len = Header::len(version);
+
+    if bytes.len() < header_len {
+        return Err(FormatError::Truncated { expected: header_len as u64, actual: bytes.len() as u64 });
+    }
+
+    let header = Header::read(bytes);
+    let payload = &bytes[header_len..];
+
+    if payload.len() as u64 != header.length {
+        return Err(FormatError::Truncated { expected: header.length, actual: payload.len() as u64 });
+    }
+
+    let checksum = crc32(payload);
+
+    if checksum != header.checksum {
+        return Err(FormatError::Checksum { expected: header.checksum, actual: checksum });
+    }
+
+    let mut payload = Cow::Borrowed(payload);
+
+    if let Some(id) = header.encryption_key() {
+        let key = try!(keys.get(id).ok_or(FormatError::UnknownKey(id)));
+
+        payload = Cow::Owned(try!(key.decrypt(&payload, &header.aad()).ok_or(FormatError::Decryption(id))));
+    }
+
+    if header.flags & COMPRESSED != 0 {
+        match compression::decompress(&payload, MAX_PAYLOAD) {
+            Err(err) => return Err(FormatError::Compression(err.to_string())),
+            Ok(decompressed) => payload = Cow::Owned(decompressed)
+        }
+    }
+
+    Ok((header, payload))
+}
+
+impl Header {
+    /// Id of the key the payload is encrypted with, if any.
+    pub fn encryption_key(&self) -> Option<u32> {
+        match self.flags & ENCRYPTED {
+            0 => None,
+            _ => Some(self.key)
+        }
+    }
+
+    pub fn compression(&self) -> Compression {
+        match self.flags & COMPRESSED {
+            0 => Compression::None,
+            _ => Compression::Deflate
+        }
+    }
+
+    /// Header length of `version`. Version 1 has no flags, version 2 no key.
+    fn len(version: u16) -> usize {
+        match version {
+            1 => 18,
+            2 => 20,
+            _ => HEADER_LEN
+        }
+    }
+
+    fn read(bytes: &[u8]) -> Header {
+        let version = read_le(&bytes[4..6]) as u16;
+
+        let (flags, key, rest) = match version {
+            1 => (0, 0, &bytes[6..]),
+            2 => (read_le(&bytes[6..8]) as u16, 0, &bytes[8..]),
+            _ => (read_le(&bytes[6..8]) as u16, read_le(&bytes[8..12]) as u32, &bytes[12..])
+        };
+
+        Header {
+            version: version,
+            flags: flags,
+            key: key,
+            length: read_le(&rest[0..8]),
+            checksum: read_le(&rest[8..12]) as u32
+        }
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(MAGIC);
+        write_le(bytes, self.version as u64, 2);
+        write_le(bytes, self.flags as u64, 2);
+        write_le(bytes, self.key as u64, 4);
+        write_le(bytes, self.length, 8);
+        write_le(bytes, self.checksum as u64, 4);
+    }
+
+    /// Header fields known before the payload is encrypted.
+    fn aad(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+
+        self.write(&mut bytes);
+        bytes.truncate(AAD_LEN);
+
+        bytes
+    }
+}
+
+/// CRC-32 (IEEE 802.3) of `bytes`.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in bytes {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+
+    !crc
+}
+
+fn read_le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |n, &b| n << 8 | b as u64)
+}
+
+fn write_le(bytes: &mut Vec<u8>, n: u64, len: usize) {
+    for i in 0..len {
+        bytes.push((n >> (i * 8)) as u8);
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FormatError::Truncated { expected, actual } => write!(f, "Truncated: expected {} bytes, found {}", expected, actual),
+            FormatError::Checksum { expected, actual } => write!(f, "Checksum mismatch: expected {:08X}, found {:08X}", expected, actual),
+            FormatError::Version(version) => write!(f, "Unsupported format version {}", version),
+            FormatError::Compression(ref err) => write!(f, "Bad compressed payload: {}", err),
+            FormatError::UnknownKey(id) => write!(f, "Encryption key {} not available", id),
+            FormatError::Decryption(id) => write!(f, "Decryption with key {} failed", id)
+        }
+    }
+}
+
+impl Error for FormatError {
+    fn description(&self) -> &str {
+        match *self {
+            FormatError::Truncated { .. } => "truncated file",
+            FormatError::Checksum { .. } => "checksum mismatch",
+            FormatError::Version(_) => "unsupported format version",
+            FormatError::Compression(_) => "bad compressed payload",
+            FormatError::UnknownKey(_) => "encryption key not available",
+            FormatError::Decryption(_) => "decryption failed"
+        }
+    }
+}
+
+#[test]
+fn test_encode_decode() {
+    assert_eq!(crc32(b"123456789"), 0xCBF43926);
+
+    let keys = Keyring::default();
+    let decoded = |bytes: &[u8], keys: &Keyring| decode(bytes, keys).map(|(h, p)| (h.version, p.into_owned()));
+
+    let payload = b"moo cow";
+    let mut bytes = encode(payload, Compression::None, None);
+
+    assert_eq!(decoded(&bytes, &keys), Ok((VERSION, payload.to_vec())));
+
+    // Legacy files have no header
+    assert_eq!(decoded(payload, &keys), Ok((0, payload.to_vec())));
+
+    // Version 1 header has no flags
+    let mut v1 = vec![];
+
+    v1.extend_from_slice(MAGIC);
+    write_le(&mut v1, 1, 2);
+    write_le(&mut v1, payload.len() as u64, 8);
+    write_le(&mut v1, crc32(payload) as u64, 4);
+    v1.extend_from_slice(payload);
+
+    assert_eq!(decoded(&v1, &keys), Ok((1, payload.to_vec())));
+
+    // Compressed only if smaller
+    let repetitive = "moo cow ".repeat(100).into_bytes();
+    let compressed = encode(&repetitive, Compression::Deflate, None);
+
+    assert!(compressed.len() < repetitive.len() / 10);
+    assert_eq!(decoded(&compressed, &keys), Ok((VERSION, repetitive.clone())));
+    assert_eq!(encode(payload, Compression::None, None), bytes);
+
+    // Encrypted after compression, and only readable with the key
+    let keys = Keyring::parse("3 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").unwrap();
+    let mut encrypted = encode(&repetitive, Compression::Deflate, keys.current());
+
+    assert!(encrypted.len() < repetitive.len() / 4);
+    assert_eq!(decoded(&encrypted, &keys), Ok((VERSION, repetitive.clone())));
+    assert_eq!(decoded(&encrypted, &Keyring::default()), Err(FormatError::UnknownKey(3)));
+
+    // Tampering with the header is detected, even with a valid checksum
+    encrypted[6] &= ! (COMPRESSED as u8);
+    assert_eq!(decoded(&encrypted, &keys), Err(FormatError::Decryption(3)));
+
+    bytes[HEADER_LEN + 1] ^= 0x10;
+    assert!(match decode(&bytes, &keys) { Err(FormatError::Checksum { .. }) => true, _ => false });
+
+    assert!(match decode(&bytes[..HEADER_LEN + 3], &keys) { Err(FormatError::Truncated { .. }) => true, _ => false });
+}
@@ -1,123 +1,179 @@
 //! A simple filesystem based zone store. For test use only.
+//!
+//! Each Zone is stored in its own file, named after its Path with a stable hash. Paths of stored
+//! Zones are listed one per line in an index file, so they can be listed without reading every
+//! Zone file. Directories written before the index existed are indexed, and their files renamed,
+//! when opened.
 
 use std;
-use std::collections::VecDeque;
-use std::collections::hash_map::DefaultHasher;
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
 use std::error::Error;
-use std::fs::{DirBuilder, File};
-use std::hash::{Hash, Hasher};
+use std::fs::{DirBuilder, File, OpenOptions};
 use std::io::ErrorKind;
 use std::io::prelude::*;
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{Receiver, Sender};
-use std::thread;
+use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use bincode;
+use serde_json;
+use time;
 use threadpool::ThreadPool;
 
 use super::*;
-use app::{App, AppHandle};
+use super::{format, migrate};
+use super::encryption::{Key, Keyring};
+use super::format::{FormatError, Header};
+use app::AppHandle;
+use compression::Compression;
 use path::Path;
+use replica;
+use snapshot;
 use zone::{ZoneData, ZoneHandle};
 
 const NUM_THREADS: usize = 50;
 
+/// Subdirectory corrupted Zone files are moved to.
+const QUARANTINE_DIR: &'static str = "quarantine";
+
+/// File listing the Paths of stored Zones.
+const INDEX: &'static str = "index";
+
+/// Extension of Zone files.
+const EXTENSION: &'static str = "zone";
+
 pub struct FS {
     app: AppHandle,
 
     dir: std::path::PathBuf,
-    rx: Receiver<StoreCall>,
+    durability: Durability,
+    compression: Compression,
+    keys: Arc<Keyring>,
 
     read_pool: ThreadPool,
     write_pool: ThreadPool,
 
-    write_queue: Arc<Mutex<VecDeque<ZoneHandle>>>
+    write_queue: Arc<Mutex<VecDeque<ZoneHandle>>>,
+
+    index: Arc<Mutex<Index>>
 }
 
 impl FS {
-    /// Start the Store "process".
-    pub fn spawn(app: &mut App) {
-        // TODO: take serializer as parameter?
-        let dir = format!("data_{}", app.id);
-        let channel = app.channels.store.take().expect("Receiver already taken");
-        let store = FS::new(app.handle(), &dir, channel);
-
-        thread::spawn(move|| {
-            store.message_loop();
-        });
-    }
-
-    pub fn new(app: AppHandle, dir: &str, channel: StoreChannel) -> FS {
+    pub fn new(app: AppHandle, dir: &str, durability: Durability, compression: Compression, keys: Arc<Keyring>) -> FS {
         let dir = std::path::PathBuf::from(dir);
 
         if ! dir.is_dir() {
             DirBuilder::new().recursive(true).create(&dir).unwrap();
         }
 
+        let index = Index::open(&dir, &keys).unwrap();
+
+        info!("Indexed {} zones in {}", index.paths.len(), dir.display());
+
         FS {
             app: app,
             dir: dir,
-            rx: channel.rx,
+            durability: durability,
+            compression: compression,
+            keys: keys,
             read_pool: ThreadPool::new(NUM_THREADS),
             write_pool: ThreadPool::new(NUM_THREADS),
-            write_queue: Arc::new(Mutex::new(VecDeque::new()))
+            write_queue: Arc::new(Mutex::new(VecDeque::new())),
+            index: Arc::new(Mutex::new(index))
         }
     }
+}
+
+/// Paths of all stored Zones. Kept in memory, and appended to the index file before a Zone is
+/// first written.
+struct Index {
+    paths: HashSet<Path>,
+    file: File
+}
 
-    fn message_loop(self) {
-        loop {
-            let call = self.rx.recv().unwrap();
+impl Index {
+    /// Opens the index in `dir`, building it from Zone files if missing.
+    fn open(dir: &std::path::Path, keys: &Keyring) -> Result<Index, StoreError> {
+        let filepath = dir.join(INDEX);
 
-            match call {
-                StoreCall::List(reply) => self.list(reply),
-                StoreCall::Load(zone, path) => self.load(zone, path),
-                StoreCall::LoadData(path, tx) => self.load_data(path, tx),
-                StoreCall::RequestWrite(zone) => self.request_write(zone),
-                StoreCall::Write(zone, path, data) => self.write(zone, path, data)
+        if ! filepath.exists() {
+            let renamed = try!(build_index(dir, keys));
+
+            if renamed > 0 {
+                info!("Renamed {} zone files in {}", renamed, dir.display());
             }
         }
-    }
 
-    /// Lists all Zone Paths stored locally
-    pub fn list(&self, tx: Sender<Path>) {
-        let entries = match std::fs::read_dir(&self.dir) {
-            Err(err) => {
-                error!("Error listing directory.");
-                error!("  {:?}", err);
-                return;
-            },
-            Ok(entries) => entries
-        };
+        let mut contents = String::new();
 
-        for entry in entries {
-            let entry = match entry {
-                Err(err) => {
-                    error!("Error reading entry.");
-                    error!("  {:?}", err);
-                    return;
-                },
-                Ok(entry) => entry
-            };
+        if let Err(err) = File::open(&filepath).and_then(|mut f| f.read_to_string(&mut contents)) {
+            return Err(StoreError::ReadError(Box::new(err)));
+        }
 
+        let mut paths = HashSet::new();
 
-            match blocking_read(&entry.path()) {
-                Err(err) => {
-                    error!("Error loading {:?}: {}", entry, err.description());
-                    error!("  {:?}", err);
-                },
-                Ok(node) => {
-                    tx.send(node.path).unwrap();
+        for line in contents.lines() {
+            match serde_json::from_str(line) {
+                Err(err) => error!("Skipping bad index entry {:?}: {}", line, err),
+                Ok(path) => {
+                    paths.insert(Path::new(path));
                 }
             }
         }
+
+        let mut file = match OpenOptions::new().append(true).open(&filepath) {
+            Err(err) => return Err(StoreError::WriteError(Box::new(err))),
+            Ok(file) => file
+        };
+
+        // Terminate an entry torn by a crash, so the next one is intact
+        if ! contents.is_empty() && ! contents.ends_with('\n') {
+            if let Err(err) = file.write_all(b"\n") {
+                return Err(StoreError::WriteError(Box::new(err)));
+            }
+        }
+
+        Ok(Index { paths: paths, file: file })
+    }
+
+    /// Records `path` if not already indexed.
+    fn insert(&mut self, path: &Path, durability: Durability) -> std::io::Result<()> {
+        if self.paths.contains(path) {
+            return Ok(());
+        }
+
+        try!(self.file.write_all(index_entry(path).as_bytes()));
+
+        if durability.sync_file() {
+            try!(self.file.sync_data());
+        }
+
+        self.paths.insert(path.clone());
+
+        Ok(())
+    }
+}
+
+impl Store for FS {
+    /// Lists all Zone Paths stored locally
+    fn list(&self, tx: Sender<Path>) {
+        let paths: Vec<Path> = self.index.lock().unwrap().paths.iter().cloned().collect();
+
+        for path in paths {
+            tx.send(path).unwrap();
+        }
     }
 
     /// Loads data for a `Zone` asynchronously, notifying its handle when done.
-    pub fn load(&self, zone: ZoneHandle, path: Path) {
+    fn load(&self, zone: ZoneHandle, path: Path) {
+        let dir = self.dir.clone();
         let mut filepath = self.dir.clone();
 
         self.app.stats.store.reads_pending.increment();
 
+        let app = self.app.clone();
+        let keys = self.keys.clone();
         let stats = self.app.stats.clone();
 
         self.read_pool.execute(move|| {
@@ -129,13 +185,24 @@ impl FS {
 
             debug!("reading {}", filepath.display());
 
-            match blocking_read(&*filepath) {
+            match blocking_read(&*filepath, &keys) {
+                Err(StoreError::CorruptError(err)) => {
+                    error!("Corrupted {:?} - {}: {}", path, filepath.display(), err);
+                    stats.store.corruptions.increment();
+
+                    if let Err(err) = quarantine(&dir, &filepath) {
+                        error!("Error quarantining {}: {}", filepath.display(), err);
+                    }
+
+                    // Replicas will send their copy, merged once the Zone loads what is left
+                    app.cluster.recover_zone(path.clone());
+                    zone.load_failed(format!("Corrupted, recovering from replicas: {}", err), true);
+                },
                 Err(err) => {
                     error!("Error loading {:?} - {}: {}", path, filepath.display(), err.description());
                     error!("{:?}", err);
                     stats.store.reads_errors.increment();
-                    // TODO: set Zone to error state
-                    //zone.set_error(err);
+                    zone.load_failed(err.description().into(), false);
                 },
                 Ok(node) => zone.loaded(node)
             };
@@ -146,8 +213,9 @@ impl FS {
     }
 
     /// Asynchronously load and send `ZoneData` for `Path` to channel.
-    pub fn load_data(&self, path: Path, tx: Sender<Option<ZoneData>>) {
+    fn load_data(&self, path: Path, tx: Sender<Option<ZoneData>>) {
         let mut filepath = self.dir.clone();
+        let keys = self.keys.clone();
 
         self.read_pool.execute(move|| {
             debug!("Loading: {:?}", path);
@@ -158,12 +226,12 @@ impl FS {
 
             debug!("reading {}", filepath.display());
 
-            tx.send(blocking_read(&*filepath).ok()).is_ok(); // ignore if caller goes away
+            tx.send(blocking_read(&*filepath, &keys).ok()).is_ok(); // ignore if caller goes away
         });
     }
 
     /// Request for notification to write data.
-    pub fn request_write(&self, zone: ZoneHandle) {
+    fn request_write(&self, zone: ZoneHandle) {
         if self.write_pool.active_count() >= NUM_THREADS {
             // No write slots available, save for later
             self.write_queue.lock().unwrap().push_back(zone);
@@ -174,11 +242,15 @@ impl FS {
     }
 
     /// Write data for a `Zone` asynchronously, notifying its handle when done.
-    pub fn write(&self, zone: ZoneHandle, path: Path, data: Vec<u8>) {
+    fn write(&self, zone: ZoneHandle, path: Path, data: ZoneWrite) {
         let path = path.clone();
         let mut filepath = self.dir.clone();
+        let durability = self.durability;
 
+        let compression = self.compression;
+        let keys = self.keys.clone();
         let pending = self.write_queue.clone();
+        let index = self.index.clone();
 
         self.app.stats.store.writes_pending.increment();
 
@@ -193,17 +265,32 @@ impl FS {
 
             debug!("writing {}", filepath.display());
 
-            match blocking_write(&*filepath, data) {
+            let start = time::precise_time_ns();
+            let len = data.data.len();
+
+            // Indexed first, so a crash never leaves an unlisted file
+            let result = index.lock().unwrap().insert(&path, durability)
+                .map_err(|err| StoreError::WriteError(Box::new(err)))
+                .and_then(|_| blocking_write(&*filepath, data.data, durability, compression, keys.current()));
+
+            match result {
                 Err(err) => {
                     error!("Error writing {:?} - {}: {}", path, filepath.display(), err.description());
                     error!("{:?}", err);
                     stats.store.writes_errors.increment();
-                    // TODO set Zone to error state
-                    //zone.set_error(err);
+                    zone.save_failed(err.description().into());
                 },
-                Ok(_) => zone.saved()
+                Ok(written) => {
+                    if compression.enabled() {
+                        stats.store.compress_in_bytes.add(len);
+                        stats.store.compress_out_bytes.add(written - format::HEADER_LEN);
+                    }
+
+                    zone.saved();
+                }
             };
 
+            stats.write_latency(start);
             stats.store.writes_pending.decrement();
             stats.store.writes.increment();
 
@@ -215,9 +302,36 @@ impl FS {
             }
         });
     }
+
+    /// Blocks until writes in progress are complete.
+    fn flush(&self) {
+        self.write_pool.join();
+    }
+
+    /// Hard links all Zone files into `dir`. Files are replaced rather than modified on write, so
+    /// links are unaffected by later writes. The index is appended to, so it is copied.
+    fn snapshot(&self, dir: &std::path::Path) -> Result<Vec<String>, StoreError> {
+        let mut files = vec![INDEX.to_string()];
+
+        if let Err(err) = std::fs::copy(self.dir.join(INDEX), dir.join(INDEX)) {
+            return Err(StoreError::WriteError(Box::new(err)));
+        }
+
+        for filepath in try!(zone_files(&self.dir)) {
+            let filename = filepath.file_name().unwrap().to_string_lossy().into_owned();
+
+            if let Err(err) = link_or_copy(&filepath, &dir.join(&filename)) {
+                return Err(StoreError::WriteError(Box::new(err)));
+            }
+
+            files.push(filename);
+        }
+
+        Ok(files)
+    }
 }
 
-fn blocking_read(filepath: &std::path::Path) -> Result<ZoneData, StoreError> {
+fn blocking_read(filepath: &std::path::Path, keys: &Keyring) -> Result<ZoneData, StoreError> {
     debug!("blocking_read: {:?}", filepath);
 
     let mut file = match File::open(filepath) {
@@ -246,16 +360,122 @@ fn blocking_read(filepath: &std::path::Path) -> Result<ZoneData, StoreError> {
         return Err(StoreError::ReadError(Box::new(err)));
     }
 
-    match bincode::deserialize(&buffer) {
+    let (_header, payload) = try!(read_payload(&buffer, keys));
+
+    match bincode::deserialize(&payload) {
         Err(err) => {
             error!("err {}:", err.description());
-            Err(StoreError::ReadError(Box::new(err)))
+            Err(StoreError::CorruptError(Box::new(err)))
         },
         Ok(data) => Ok(data)
     }
 }
 
-fn blocking_write(filepath: &std::path::Path, serialized: Vec<u8>) -> Result<(), StoreError> {
+/// Verifies and decrypts file contents and upgrades them to the current format version, returning
+/// the original header and the payload.
+fn read_payload<'a>(buffer: &'a [u8], keys: &Keyring) -> Result<(Header, Cow<'a, [u8]>), StoreError> {
+    let (header, payload) = match format::decode(buffer, keys) {
+        // Not corrupt, the right key is needed
+        Err(err @ FormatError::UnknownKey(_)) |
+        Err(err @ FormatError::Decryption(_)) => return Err(StoreError::ReadError(Box::new(err))),
+        Err(err) => return Err(StoreError::CorruptError(Box::new(err))),
+        Ok(decoded) => decoded
+    };
+
+    if header.version == format::VERSION {
+        return Ok((header, payload));
+    }
+
+    match migrate::migrate(header.version, payload.into_owned()) {
+        Err(err) => Err(StoreError::ReadError(err)),
+        Ok(payload) => Ok((header, Cow::Owned(payload)))
+    }
+}
+
+/// Files handled by `migrate_dir` and `reencrypt_dir`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Rewritten {
+    /// Zone files rewritten
+    pub rewritten: usize,
+    /// Corrupted Zone files moved to the quarantine directory
+    pub quarantined: usize,
+    /// Zone files that could not be read, e.g. without their key, left as they are
+    pub skipped: usize
+}
+
+/// Rewrites all Zone files in `dir` in the current format version. The directory must not be in
+/// use by a running Store.
+pub fn migrate_dir(dir: &str, keys: &Keyring) -> Result<Rewritten, StoreError> {
+    rewrite_dir(dir, keys, |header| header.version != format::VERSION)
+}
+
+/// Rewrites all Zone files in `dir` not encrypted with the current key of `keys`, or encrypted
+/// if `keys` is empty. The directory must not be in use by a running Store.
+pub fn reencrypt_dir(dir: &str, keys: &Keyring) -> Result<Rewritten, StoreError> {
+    let current = keys.current().map(|k| k.id);
+
+    rewrite_dir(dir, keys, |header| header.version != format::VERSION || header.encryption_key() != current)
+}
+
+/// Rewrites Zone files selected by their header with the current format and key, keeping their
+/// compression. Directories without an index are indexed first, as when opened by `FS`, so files
+/// get their current names. The replica id and restored snapshot marker are plain text, and kept.
+fn rewrite_dir<F>(dir: &str, keys: &Keyring, rewrite: F) -> Result<Rewritten, StoreError> where F: Fn(&Header) -> bool {
+    let dir = std::path::Path::new(dir);
+    let mut result = Rewritten::default();
+
+    if ! dir.join(INDEX).exists() {
+        try!(build_index(dir, keys));
+    }
+
+    for filepath in try!(zone_files(dir)) {
+        let mut buffer = vec![];
+
+        if let Err(err) = File::open(&filepath).and_then(|mut f| f.read_to_end(&mut buffer)) {
+            return Err(StoreError::ReadError(Box::new(err)));
+        }
+
+        // Verify before replacing
+        let decoded = read_payload(&buffer, keys).and_then(|(header, payload)| {
+            match bincode::deserialize::<ZoneData>(&payload) {
+                Err(err) => Err(StoreError::CorruptError(Box::new(err))),
+                Ok(_) => Ok((header, payload))
+            }
+        });
+
+        let (header, payload) = match decoded {
+            Err(StoreError::CorruptError(err)) => {
+                error!("Corrupted {}: {}", filepath.display(), err);
+
+                if let Err(err) = quarantine(dir, &filepath) {
+                    return Err(StoreError::WriteError(Box::new(err)));
+                }
+
+                result.quarantined += 1;
+                continue;
+            },
+            Err(err) => {
+                error!("Skipping {}: {}", filepath.display(), err.description());
+                result.skipped += 1;
+                continue;
+            },
+            Ok(decoded) => decoded
+        };
+
+        if ! rewrite(&header) {
+            continue;
+        }
+
+        try!(blocking_write(&filepath, payload.into_owned(), Durability::Dir, header.compression(), keys.current()));
+
+        result.rewritten += 1;
+    }
+
+    Ok(result)
+}
+
+/// Writes `serialized` with a header, returning the number of bytes written.
+fn blocking_write(filepath: &std::path::Path, serialized: Vec<u8>, durability: Durability, compression: Compression, key: Option<&Key>) -> Result<usize, StoreError> {
     debug!("blocking_write: {:?}", filepath);
 
     let tmp_path = filepath.with_extension("tmp");
@@ -272,42 +492,151 @@ fn blocking_write(filepath: &std::path::Path, serialized: Vec<u8>) -> Result<(),
         Ok(file) => file,
     };
 
-    if let Err(err) = file.write_all(&serialized) {
+    let encoded = format::encode(&serialized, compression, key);
+
+    if let Err(err) = file.write_all(&encoded) {
         return Err(StoreError::WriteError(Box::new(err)));
     }
 
+    if durability.sync_file() {
+        if let Err(err) = file.sync_all() {
+            return Err(StoreError::WriteError(Box::new(err)));
+        }
+    }
+
     if let Err(err) = std::fs::rename(&tmp_path, &filepath) {
         return Err(StoreError::WriteError(Box::new(err)));
     }
 
-    Ok(())
+    if durability.sync_dir() {
+        if let Err(err) = sync_dir(filepath.parent().unwrap_or(std::path::Path::new("."))) {
+            return Err(StoreError::WriteError(Box::new(err)));
+        }
+    }
+
+    Ok(encoded.len())
 }
 
+/// Renames Zone files to their current names and writes an index of their Paths. Used for
+/// directories written before the index existed. Returns the number of files renamed.
+fn build_index(dir: &std::path::Path, keys: &Keyring) -> Result<usize, StoreError> {
+    let mut renamed = 0;
+    let mut index = String::new();
+
+    for filepath in try!(zone_files(dir)) {
+        let data = match blocking_read(&filepath, keys) {
+            Err(StoreError::CorruptError(err)) => {
+                error!("Corrupted {}: {}", filepath.display(), err);
+
+                if let Err(err) = quarantine(dir, &filepath) {
+                    return Err(StoreError::WriteError(Box::new(err)));
+                }
+
+                continue;
+            },
+            Err(err) => return Err(err),
+            Ok(data) => data
+        };
+
+        let target = dir.join(zonefilename(&data.path));
+
+        if target != filepath {
+            if let Err(err) = std::fs::rename(&filepath, &target) {
+                return Err(StoreError::WriteError(Box::new(err)));
+            }
+
+            renamed += 1;
+        }
+
+        index.push_str(&index_entry(&data.path));
+    }
+
+    let tmp_path = dir.join(INDEX).with_extension("tmp");
+
+    let result = File::create(&tmp_path)
+        .and_then(|mut f| f.write_all(index.as_bytes()).and_then(|_| f.sync_all()))
+        .and_then(|_| std::fs::rename(&tmp_path, dir.join(INDEX)))
+        .and_then(|_| sync_dir(dir));
+
+    match result {
+        Err(err) => Err(StoreError::WriteError(Box::new(err))),
+        Ok(_) => Ok(renamed)
+    }
+}
+
+fn index_entry(path: &Path) -> String {
+    format!("{}\n", serde_json::to_string(&path.path).unwrap())
+}
+
+/// Lists Zone files in `dir`, including those named by older versions.
+fn zone_files(dir: &std::path::Path) -> Result<Vec<std::path::PathBuf>, StoreError> {
+    let mut files = vec![];
+
+    for entry in try!(std::fs::read_dir(dir).map_err(|err| StoreError::ReadError(Box::new(err)))) {
+        let filepath = try!(entry.map_err(|err| StoreError::ReadError(Box::new(err)))).path();
+
+        if ! filepath.is_file() || filepath.extension().map_or(false, |ext| ext == "tmp") {
+            continue;
+        }
+
+        let ignored = [INDEX, replica::ID_FILE, snapshot::RESTORED_FILE];
+
+        if filepath.file_name().map_or(false, |name| ignored.iter().any(|ignored| name == *ignored)) {
+            continue;
+        }
+
+        files.push(filepath);
+    }
+
+    Ok(files)
+}
+
+/// Moves a corrupted Zone file out of the way, keeping it for inspection.
+fn quarantine(dir: &std::path::Path, filepath: &std::path::Path) -> std::io::Result<()> {
+    let mut target = dir.join(QUARANTINE_DIR);
+
+    if ! target.is_dir() {
+        try!(DirBuilder::new().recursive(true).create(&target));
+    }
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let filename = filepath.file_name().and_then(|f| f.to_str()).unwrap_or("zone");
+
+    target.push(format!("{}.{}", filename, timestamp));
+
+    std::fs::rename(filepath, target)
+}
+
+/// Readable prefix of the Path, followed by a hash of the full Path that is stable across builds.
 fn zonefilename(path: &Path) -> String {
-    let zonename = path.path.join(".");
     let mut filename = String::from("r");
 
-    if path.len() > 0 { 
-        filename.push_str(&zonename);
+    if path.len() > 0 {
+        filename.push_str(&path.path.join("."));
     }
 
     // Truncate and remove unsafe characters
-    filename.truncate(80);
-
-    let mut filename: String = filename.chars().map(|x| match x { 
+    let filename: String = filename.chars().take(64).map(|x| match x {
         '#' | '0'...'9' | 'A'...'Z' | 'a'...'z' => x,
         _  => '_'
     }).collect();
 
-    filename.push_str("_");
+    format!("{}_{:016x}.{}", filename, path_hash(path), EXTENSION)
+}
+
+/// 64 bit FNV-1a hash of Path components. Components are terminated by 0xFF, which never occurs in
+/// UTF-8, so different Paths never hash the same input.
+fn path_hash(path: &Path) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
 
-    // Add a unique hash
-    let mut hasher = DefaultHasher::new();
-    
-    zonename.hash(&mut hasher);
-    filename.push_str(&format!("{:X}", hasher.finish()));
+    for component in &path.path {
+        for &byte in component.as_bytes().iter().chain(&[0xFF]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
 
-    filename
+    hash
 }
 
 #[test]
@@ -324,16 +653,16 @@ fn test_read_write() {
 
     std::fs::remove_file(&file).ok();
 
-    let data = blocking_read(&file).unwrap();
+    let data = blocking_read(&file, &Keyring::default()).unwrap();
 
     assert_eq!(data, Default::default());
 
     let limit = bincode::Infinite;
     let serialized = bincode::serialize(&data, limit).unwrap();
 
-    blocking_write(&file, serialized).unwrap();
+    blocking_write(&file, serialized, Durability::None, Compression::None, None).unwrap();
 
-    assert_eq!(blocking_read(&file).unwrap(), data);
+    assert_eq!(blocking_read(&file, &Keyring::default()).unwrap(), data);
 
     use node::{Node, NodeTree, Vis};
     use serde_json::Value as JSON;
@@ -349,11 +678,98 @@ fn test_read_write() {
     let limit = bincode::Infinite;
     let serialized = bincode::serialize(&expected, limit).unwrap();
 
-    blocking_write(&file, serialized).unwrap();
+    blocking_write(&file, serialized, Durability::Dir, Compression::None, None).unwrap();
 
-    let verify = blocking_read(&file).unwrap();
+    let verify = blocking_read(&file, &Keyring::default()).unwrap();
 
     assert_eq!(verify, expected);
+
+    let serialized = bincode::serialize(&expected, limit).unwrap();
+
+    blocking_write(&file, serialized, Durability::None, Compression::Deflate, None).unwrap();
+
+    assert_eq!(blocking_read(&file, &Keyring::default()).unwrap(), expected);
+
+    // Flipped bits are detected and the file can be quarantined
+    let mut bytes = vec![];
+
+    File::open(&file).unwrap().read_to_end(&mut bytes).unwrap();
+
+    let last = bytes.len() - 1;
+
+    bytes[last] ^= 0x01;
+    File::create(&file).unwrap().write_all(&bytes).unwrap();
+
+    assert!(match blocking_read(&file, &Keyring::default()) { Err(StoreError::CorruptError(_)) => true, _ => false });
+
+    quarantine(&dir, &file).unwrap();
+
+    assert!(! file.exists());
+    assert!(dir.join(QUARANTINE_DIR).is_dir());
+}
+
+#[test]
+fn test_migrate_dir() {
+    let dir = std::path::PathBuf::from("test_data/migrate");
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    DirBuilder::new().recursive(true).create(&dir).unwrap();
+
+    let legacy = dir.join("legacy");
+    let file = dir.join(zonefilename(&path![moo]));
+    let data = ZoneData::new(path![moo], Default::default());
+    let serialized = bincode::serialize(&data, bincode::Infinite).unwrap();
+    let rewritten = |rewritten, quarantined, skipped| Rewritten { rewritten: rewritten, quarantined: quarantined, skipped: skipped };
+
+    // Version 0 files have no header
+    File::create(&legacy).unwrap().write_all(&serialized).unwrap();
+
+    // Not a Zone file
+    File::create(dir.join(replica::ID_FILE)).unwrap().write_all(b"moo\n").unwrap();
+
+    assert_eq!(blocking_read(&legacy, &Keyring::default()).unwrap(), data);
+
+    // Indexed and renamed first
+    assert_eq!(migrate_dir("test_data/migrate", &Keyring::default()).unwrap(), rewritten(1, 0, 0));
+    assert!(! legacy.exists());
+    assert!(dir.join(INDEX).exists());
+
+    // Corrupted files are quarantined without stopping the migration
+    let corrupted = dir.join(zonefilename(&path![cow]));
+
+    File::create(&corrupted).unwrap().write_all(&format::encode(&serialized[1..], Compression::None, None)).unwrap();
+
+    assert_eq!(migrate_dir("test_data/migrate", &Keyring::default()).unwrap(), rewritten(0, 1, 0));
+    assert!(! corrupted.exists());
+
+    let mut bytes = vec![];
+
+    File::open(&file).unwrap().read_to_end(&mut bytes).unwrap();
+
+    assert!(bytes.starts_with(format::MAGIC));
+    assert_eq!(blocking_read(&file, &Keyring::default()).unwrap(), data);
+
+    // Encrypt, then rotate keys
+    let key1 = "1 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    let key2 = "2 1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    let keys = Keyring::parse(key1).unwrap();
+
+    assert_eq!(reencrypt_dir("test_data/migrate", &keys).unwrap(), rewritten(1, 0, 0));
+    assert_eq!(reencrypt_dir("test_data/migrate", &keys).unwrap(), rewritten(0, 0, 0));
+    assert_eq!(blocking_read(&file, &keys).unwrap(), data);
+    assert!(match blocking_read(&file, &Keyring::default()) { Err(StoreError::ReadError(_)) => true, _ => false });
+
+    // Files that cannot be decrypted are left as they are
+    assert_eq!(reencrypt_dir("test_data/migrate", &Keyring::default()).unwrap(), rewritten(0, 0, 1));
+
+    let keys = Keyring::parse(&format!("{}\n{}", key1, key2)).unwrap();
+
+    assert_eq!(reencrypt_dir("test_data/migrate", &keys).unwrap(), rewritten(1, 0, 0));
+    assert_eq!(blocking_read(&file, &Keyring::parse(key2).unwrap()).unwrap(), data);
 }
 
 #[test]
@@ -364,10 +780,10 @@ fn test_list() {
         std::fs::remove_dir_all(dir).unwrap();
     }
 
-    let chan = StoreChannel::new();
+    use app::App;
 
     let app = App::new("127.0.0.1:42".parse().unwrap());
-    let store = FS::new(app.handle(), "127.0.0.1:42", chan);
+    let store = FS::new(app.handle(), "test_data/list", Durability::File, Compression::Deflate, Default::default());
 
     let noop_zone = ZoneHandle::test_handle(Arc::new(path![]));
     let limit = bincode::Infinite;
@@ -376,9 +792,12 @@ fn test_list() {
         let path = Path::new(vec![i.to_string()]);
         let zone_data = ZoneData::new(path.clone(), Default::default());
 
-        let serialized = bincode::serialize(&zone_data, limit).unwrap();
+        let write = ZoneWrite {
+            data: bincode::serialize(&zone_data, limit).unwrap(),
+            diff: vec![]
+        };
 
-        store.write(noop_zone.clone(), path, serialized);
+        store.write(noop_zone.clone(), path, write);
     }
 
     std::thread::sleep(std::time::Duration::from_millis(200));
@@ -397,3 +816,102 @@ fn test_list() {
         Path::new(vec!["2".into()]),
     ]);
 }
+
+#[test]
+fn test_index() {
+    use app::App;
+
+    // Names must not change between builds
+    assert_eq!(zonefilename(&path![moo.cow]), "rmoo_cow_aacb8a7ebf3e1893.zone");
+    assert_eq!(zonefilename(&Path::empty()), "r_cbf29ce484222325.zone");
+
+    let dir = std::path::PathBuf::from("test_data/index");
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    DirBuilder::new().recursive(true).create(&dir).unwrap();
+
+    // Legacy name, without an index
+    let legacy = dir.join("rmoo_4F1A");
+    let data = ZoneData::new(path![moo], Default::default());
+
+    blocking_write(&legacy, bincode::serialize(&data, bincode::Infinite).unwrap(), Durability::None, Compression::None, None).unwrap();
+
+    let app = App::new("127.0.0.1:42".parse().unwrap());
+    let store = FS::new(app.handle(), "test_data/index", Durability::File, Compression::None, Default::default());
+
+    assert!(! legacy.exists());
+    assert_eq!(blocking_read(&dir.join(zonefilename(&path![moo])), &Keyring::default()).unwrap(), data);
+
+    let data = ZoneData::new(path![cow], Default::default());
+    let write = ZoneWrite {
+        data: bincode::serialize(&data, bincode::Infinite).unwrap(),
+        diff: vec![]
+    };
+
+    store.write(ZoneHandle::test_handle(Arc::new(path![])), path![cow], write);
+    store.flush();
+
+    // Reopened from the index file
+    let store = FS::new(app.handle(), "test_data/index", Durability::File, Compression::None, Default::default());
+    let (tx, rx) = channel();
+
+    store.list(tx);
+
+    let mut paths: Vec<Path> = rx.iter().collect();
+
+    paths.sort();
+
+    assert_eq!(paths, [path![cow], path![moo]]);
+}
+
+#[test]
+fn test_write_failed() {
+    use std::time::{Duration, Instant};
+
+    use mioco::sync::mpsc::channel;
+
+    use app::App;
+    use command::Command;
+    use manager::Manager;
+
+    let dir = std::path::PathBuf::from("test_data/write_failed");
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    let mut app = App::new("127.0.0.1:1000".parse().unwrap());
+
+    start(FS::new(app.handle(), "test_data/write_failed", Durability::File, Compression::None, Default::default()), app.channels.store.take().unwrap());
+    Manager::spawn(&mut app);
+
+    let wait_for = |f: &Fn() -> bool| {
+        let start = Instant::now();
+
+        while ! f() {
+            assert!(start.elapsed() < Duration::from_secs(5), "Timed out");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    let (tx, _rx) = channel();
+    let zone = app.manager.load(&Path::empty());
+    let file = dir.join(zonefilename(&Path::empty()));
+
+    zone.dispatch(Command::from_json(r#"[ 1, "write", [], { "cow": 42 } ]"#).unwrap(), &tx);
+    wait_for(&|| zone.state().is_active());
+
+    // Zone stays dirty while its directory is gone, and is saved once it is back
+    std::fs::remove_dir_all(&dir).unwrap();
+    zone.dispatch(Command::from_json(r#"[ 2, "write", ["cow"], 43 ]"#).unwrap(), &tx);
+    wait_for(&|| app.stats.store.writes_errors.value() > 0);
+    assert!(zone.state().is_dirty() || zone.state().is_writing());
+
+    DirBuilder::new().recursive(true).create(&dir).unwrap();
+    wait_for(&|| zone.state().is_active());
+
+    assert_eq!(blocking_read(&file, &Keyring::default()).unwrap().tree, zone.dump());
+}
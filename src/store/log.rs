@@ -0,0 +1,720 @@
+//! A log-structured zone store.
+//!
+//! Instead of rewriting an entire file per Zone, each write appends the Zone's changes (a
+//! `NodeTree` diff) as a record to the active segment file. The location of every record is kept
+//! in an in-memory index, rebuilt by scanning all segments on startup. Zones are loaded by merging
+//! all their records.
+//!
+//! Full segments are sealed. Once enough segments are sealed, they are compacted in the
+//! background: all records of each Zone are merged into a single record in a new segment, and the
+//! old segments are removed. Records are read without holding the log's lock, so reads do not
+//! block appends.
+//!
+//! Records are stored as `[length: u32 LE][crc32: u32 LE][bincode Path][bincode NodeTree]`, the
+//! length and checksum covering the payload after them. Segments are synced when sealed, and
+//! appends always go to the newest segment, so only the newest segment can end in a torn record.
+//! A bad record anywhere else is corruption, and the log refuses to open.
+
+use std;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::error::Error;
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{self, ErrorKind, SeekFrom};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use bincode;
+use threadpool::ThreadPool;
+use time;
+
+use super::*;
+use super::format::crc32;
+use app::AppHandle;
+use node::NodeTree;
+use path::Path;
+use zone::{ZoneData, ZoneHandle};
+
+const NUM_READ_THREADS: usize = 10;
+const MAX_PENDING_WRITES: usize = 50;
+
+/// Size at which the active segment is sealed.
+const SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Number of sealed segments that triggers compaction.
+const COMPACT_SEGMENTS: usize = 4;
+
+/// Length and checksum before each record's payload.
+const RECORD_HEADER: u64 = 8;
+
+pub struct Log {
+    app: AppHandle,
+
+    state: Arc<Mutex<LogState>>,
+    compacting: Arc<AtomicBool>, // Also set while paused
+
+    read_pool: ThreadPool,
+    write_pool: ThreadPool, // Single thread, appends are sequential
+
+    write_queue: Arc<Mutex<VecDeque<ZoneHandle>>>
+}
+
+struct LogState {
+    dir: PathBuf,
+    segment_size: u64,
+    durability: Durability,
+
+    active: u64,                         // Id of segment being appended to
+    active_file: File,
+    active_len: u64,
+
+    sealed: BTreeSet<u64>,               // Ids of full segments
+    index: HashMap<Path, Vec<Location>>, // Records of each Zone
+    next_id: u64
+}
+
+/// Location of a record
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    len: u32
+}
+
+impl Log {
+    pub fn new(app: AppHandle, dir: &str, durability: Durability) -> Log {
+        let state = LogState::open(dir, SEGMENT_SIZE, durability)
+            .unwrap_or_else(|err| panic!("Could not open log in {}: {}", dir, err));
+
+        Log {
+            app: app,
+            state: Arc::new(Mutex::new(state)),
+            compacting: Arc::new(AtomicBool::new(false)),
+            read_pool: ThreadPool::new(NUM_READ_THREADS),
+            write_pool: ThreadPool::new(1),
+            write_queue: Arc::new(Mutex::new(VecDeque::new()))
+        }
+    }
+
+    /// Compacts sealed segments in a background thread, unless compaction is already running.
+    fn maybe_compact(state: &Arc<Mutex<LogState>>, compacting: &Arc<AtomicBool>, app: &AppHandle) {
+        if state.lock().unwrap().sealed.len() < COMPACT_SEGMENTS {
+            return;
+        }
+
+        if compacting.swap(true, Ordering::SeqCst) {
+            return; // already running
+        }
+
+        let state = state.clone();
+        let compacting = compacting.clone();
+        let stats = app.stats.clone();
+
+        thread::Builder::new().name("Log compaction".into()).spawn(move|| {
+            match compact(&state) {
+                Err(err) => error!("Error compacting log: {}", err.description()),
+                Ok(_) => stats.store.compactions.increment()
+            }
+
+            compacting.store(false, Ordering::SeqCst);
+        }).expect("Log compaction spawn failed");
+    }
+}
+
+impl Store for Log {
+    /// Lists all Zone Paths stored locally
+    fn list(&self, tx: Sender<Path>) {
+        let state = self.state.lock().unwrap();
+
+        for path in state.index.keys() {
+            tx.send(path.clone()).is_ok(); // ignore if caller goes away
+        }
+    }
+
+    /// Loads data for a `Zone` asynchronously by replaying its records, notifying its handle when
+    /// done.
+    fn load(&self, zone: ZoneHandle, path: Path) {
+        let state = self.state.clone();
+
+        self.app.stats.store.reads_pending.increment();
+
+        let stats = self.app.stats.clone();
+
+        self.read_pool.execute(move|| {
+            debug!("Loading: {:?}", path);
+
+            match read_zone(&state, &path) {
+                Err(err) => {
+                    error!("Error loading {:?}: {}", path, err.description());
+                    stats.store.reads_errors.increment();
+                    zone.load_failed(err.description().into(), false);
+                },
+                Ok(data) => zone.loaded(data)
+            };
+
+            stats.store.reads_pending.decrement();
+            stats.store.reads.increment();
+        });
+    }
+
+    /// Asynchronously load and send `ZoneData` for `Path` to channel.
+    fn load_data(&self, path: Path, tx: Sender<Option<ZoneData>>) {
+        let state = self.state.clone();
+
+        self.read_pool.execute(move|| {
+            let data = read_zone(&state, &path).ok();
+
+            tx.send(data).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Request for notification to write data.
+    fn request_write(&self, zone: ZoneHandle) {
+        let pending = self.write_pool.active_count() + self.write_pool.queued_count();
+
+        if pending >= MAX_PENDING_WRITES {
+            // Too many appends pending, save for later
+            self.write_queue.lock().unwrap().push_back(zone);
+        }
+        else {
+            zone.save();
+        }
+    }
+
+    /// Only changes are appended, see `compact`.
+    fn consumes(&self) -> usize {
+        WRITE_DIFF
+    }
+
+    /// Append changes for a `Zone` asynchronously, notifying its handle when done.
+    fn write(&self, zone: ZoneHandle, path: Path, data: ZoneWrite) {
+        let app = self.app.clone();
+        let state = self.state.clone();
+        let compacting = self.compacting.clone();
+        let pending = self.write_queue.clone();
+
+        self.app.stats.store.writes_pending.increment();
+
+        self.write_pool.execute(move|| {
+            debug!("Appending: {:?}", path);
+
+            let start = time::precise_time_ns();
+            let result = state.lock().unwrap().append(&path, &data.diff);
+
+            match result {
+                Err(err) => {
+                    error!("Error appending {:?}: {}", path, err.description());
+                    app.stats.store.writes_errors.increment();
+                    zone.save_failed(err.description().into());
+                },
+                Ok(_) => zone.saved()
+            };
+
+            app.stats.write_latency(start);
+            app.stats.store.writes_pending.decrement();
+            app.stats.store.writes.increment();
+
+            Log::maybe_compact(&state, &compacting, &app);
+
+            // "Wake" any zones waiting to write
+            if let Some(zone) = pending.lock().unwrap().pop_front() {
+                zone.save();
+            }
+        });
+    }
+
+    /// Blocks until appends in progress are complete.
+    fn flush(&self) {
+        self.write_pool.join();
+    }
+
+    /// Waits for compaction to finish, and keeps it from starting until resumed.
+    fn pause(&self) {
+        while self.compacting.swap(true, Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn resume(&self) {
+        self.compacting.store(false, Ordering::SeqCst);
+    }
+
+    /// Hard links sealed segments into `dir`, and copies the active segment as it is still being
+    /// appended to.
+    fn snapshot(&self, dir: &std::path::Path) -> Result<Vec<String>, StoreError> {
+        let state = self.state.lock().unwrap();
+        let mut files = vec![];
+
+        for &id in state.sealed.iter().chain(Some(state.active).iter()) {
+            let src = segment_path(&state.dir, id);
+            let filename = src.file_name().unwrap().to_string_lossy().into_owned();
+            let dst = dir.join(&filename);
+
+            let result = match id == state.active {
+                true => std::fs::copy(&src, &dst).map(|_| ()),
+                false => link_or_copy(&src, &dst)
+            };
+
+            try!(result.map_err(write_error));
+
+            files.push(filename);
+        }
+
+        Ok(files)
+    }
+}
+
+impl LogState {
+    /// Opens log in `dir`, scanning all segments to build the index.
+    fn open(dir: &str, segment_size: u64, durability: Durability) -> Result<LogState, StoreError> {
+        let dir = PathBuf::from(dir);
+
+        if ! dir.is_dir() {
+            try!(DirBuilder::new().recursive(true).create(&dir).map_err(write_error));
+        }
+
+        let mut ids = vec![];
+
+        for entry in try!(std::fs::read_dir(&dir).map_err(read_error)) {
+            let entry = try!(entry.map_err(read_error));
+            let filename = entry.file_name().to_string_lossy().into_owned();
+
+            if let Some(id) = segment_id(&filename) {
+                ids.push(id);
+            }
+            else if filename.ends_with(".tmp") {
+                // Interrupted compaction, the segments it replaces are still there
+                try!(std::fs::remove_file(entry.path()).map_err(write_error));
+            }
+        }
+
+        ids.sort();
+
+        let mut index: HashMap<Path, Vec<Location>> = HashMap::new();
+        let mut sealed = BTreeSet::new();
+        let mut last_len = 0;
+
+        for &id in &ids {
+            let newest = Some(&id) == ids.last();
+
+            last_len = try!(scan_segment(&segment_path(&dir, id), id, newest, &mut index));
+            sealed.insert(id);
+        }
+
+        // Continue appending to last segment if it has space
+        let (active, active_len) = match ids.last() {
+            Some(&id) if last_len < segment_size => {
+                sealed.remove(&id);
+                (id, last_len)
+            },
+            Some(&id) => (id + 1, 0),
+            None => (0, 0)
+        };
+
+        let active_file = try!(open_segment(&dir, active));
+
+        Ok(LogState {
+            dir: dir,
+            segment_size: segment_size,
+            durability: durability,
+            active: active,
+            active_file: active_file,
+            active_len: active_len,
+            sealed: sealed,
+            index: index,
+            next_id: active + 1
+        })
+    }
+
+    /// Appends a serialized `NodeTree` diff for Zone at `path`. A failed record is truncated, so
+    /// the next one is written at its offset.
+    fn append(&mut self, path: &Path, diff: &[u8]) -> Result<(), StoreError> {
+        let offset = self.active_len;
+        let mut result = write_record(&mut self.active_file, path, diff);
+
+        if result.is_ok() && self.durability.sync_file() {
+            if let Err(err) = self.active_file.sync_data() {
+                result = Err(write_error(err));
+            }
+        }
+
+        let len = match result {
+            Err(err) => {
+                if let Err(err) = self.active_file.set_len(offset) {
+                    error!("Could not truncate failed record in segment {}: {}", self.active, err);
+                }
+
+                return Err(err);
+            },
+            Ok(len) => len
+        };
+
+        self.active_len += RECORD_HEADER + len as u64;
+
+        let location = Location { segment: self.active, offset: offset, len: len };
+
+        self.index.entry(path.clone()).or_insert_with(Vec::new).push(location);
+
+        if self.active_len >= self.segment_size {
+            try!(self.rotate());
+        }
+
+        Ok(())
+    }
+
+    /// Seals the active segment and starts a new one. Sealed segments are synced, so they are
+    /// complete once a newer segment exists, see `scan_segment`.
+    fn rotate(&mut self) -> Result<(), StoreError> {
+        let id = self.next_id;
+
+        try!(self.active_file.sync_data().map_err(write_error));
+
+        self.active_file = try!(open_segment(&self.dir, id));
+
+        if self.durability.sync_dir() {
+            try!(sync_dir(&self.dir).map_err(write_error));
+        }
+        self.sealed.insert(self.active);
+        self.active = id;
+        self.active_len = 0;
+        self.next_id += 1;
+
+        Ok(())
+    }
+
+    /// Locations of all records of Zone at `path`.
+    fn locations(&self, path: &Path) -> Vec<Location> {
+        self.index.get(path).cloned().unwrap_or_default()
+    }
+}
+
+/// Loads `ZoneData` for Zone at `path` by merging all its records. The lock is only held to look
+/// up locations; if compaction removes a segment meanwhile, the lookup is repeated.
+fn read_zone(state: &Mutex<LogState>, path: &Path) -> Result<ZoneData, StoreError> {
+    loop {
+        let (dir, locations) = {
+            let state = state.lock().unwrap();
+
+            (state.dir.clone(), state.locations(path))
+        };
+
+        let mut data = ZoneData::new(path.clone(), Default::default());
+        let mut result = Ok(());
+
+        for location in &locations {
+            match read_record(&dir, location) {
+                Ok(mut tree) => { data.tree.merge(&mut tree); },
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+
+        match result {
+            Ok(_) => return Ok(data),
+            Err(_) if state.lock().unwrap().locations(path) != locations => continue, // compacted
+            Err(err) => return Err(err)
+        }
+    }
+}
+
+/// Merges all records of each Zone in the currently sealed segments into a new segment, then
+/// removes those segments. Appends continue while compacting. The new segment is written to a
+/// temporary file, and only renamed once complete, after sealing the active segment, so that
+/// appends still go to the newest segment.
+fn compact(state: &Mutex<LogState>) -> Result<(), StoreError> {
+    // Sealed segments are immutable, so they can be read without holding the lock
+    let (dir, segments, zones, id) = {
+        let mut state = state.lock().unwrap();
+
+        let segments = state.sealed.clone();
+        let zones: Vec<(Path, Vec<Location>)> = state.index.iter()
+            .map(|(path, locations)| {
+                let locations = locations.iter()
+                    .filter(|l| segments.contains(&l.segment))
+                    .cloned()
+                    .collect();
+
+                (path.clone(), locations)
+            })
+            .filter(|&(_, ref locations): &(Path, Vec<Location>)| ! locations.is_empty())
+            .collect();
+
+        let id = state.next_id;
+
+        state.next_id += 1;
+
+        (state.dir.clone(), segments, zones, id)
+    };
+
+    let tmp = dir.join(format!("segment_{:016}.tmp", id));
+    let mut file = try!(File::create(&tmp).map_err(write_error));
+    let mut offset = 0;
+    let mut compacted = vec![];
+
+    for (path, locations) in zones {
+        let mut tree: NodeTree = Default::default();
+
+        for location in &locations {
+            tree.merge(&mut try!(read_record(&dir, location)));
+        }
+
+        let diff = try!(bincode::serialize(&tree, bincode::Infinite).map_err(write_error));
+        let len = try!(write_record(&mut file, &path, &diff));
+
+        compacted.push((path, Location { segment: id, offset: offset, len: len }));
+        offset += RECORD_HEADER + len as u64;
+    }
+
+    // Compacted segment must be durable before old segments are removed
+    try!(file.sync_all().map_err(write_error));
+
+    {
+        let mut state = state.lock().unwrap();
+
+        try!(state.rotate());
+        try!(std::fs::rename(&tmp, segment_path(&dir, id)).map_err(write_error));
+        try!(sync_dir(&dir).map_err(write_error));
+
+        for (path, location) in compacted {
+            if let Some(locations) = state.index.get_mut(&path) {
+                locations.retain(|l| ! segments.contains(&l.segment));
+                locations.insert(0, location);
+            }
+        }
+
+        for segment in &segments {
+            state.sealed.remove(segment);
+        }
+
+        state.sealed.insert(id);
+    }
+
+    for segment in segments {
+        if let Err(err) = std::fs::remove_file(segment_path(&dir, segment)) {
+            error!("Error removing segment {}: {}", segment, err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds all records in segment to `index`, returning the length of valid data. A bad record in
+/// the `newest` segment, and everything after it, is truncated: it can only be an append that was
+/// never acknowledged, torn by a crash. Other segments are sealed or compacted, and were synced
+/// before a newer segment was created, so a bad record in them is corruption.
+fn scan_segment(filepath: &std::path::Path, id: u64, newest: bool, index: &mut HashMap<Path, Vec<Location>>) -> Result<u64, StoreError> {
+    let mut buffer = vec![];
+
+    try!(File::open(filepath).and_then(|mut f| f.read_to_end(&mut buffer)).map_err(read_error));
+
+    let header = RECORD_HEADER as usize;
+    let mut offset = 0;
+
+    while offset < buffer.len() {
+        let path = match parse_record(&buffer[offset..]) {
+            Some(path) => path,
+            None => break
+        };
+
+        let len = read_u32(&buffer[offset..]) as usize;
+        let location = Location { segment: id, offset: offset as u64, len: len as u32 };
+
+        index.entry(path).or_insert_with(Vec::new).push(location);
+        offset += header + len;
+    }
+
+    if offset < buffer.len() {
+        if ! newest {
+            let message = format!("Bad record in sealed segment {} at offset {}", filepath.display(), offset);
+
+            return Err(StoreError::CorruptError(Box::new(io::Error::new(ErrorKind::InvalidData, message))));
+        }
+
+        error!("Torn record in {} at offset {}, truncating", filepath.display(), offset);
+
+        let file = try!(OpenOptions::new().write(true).open(filepath).map_err(write_error));
+
+        try!(file.set_len(offset as u64).map_err(write_error));
+    }
+
+    Ok(offset as u64)
+}
+
+/// Returns the Zone path of the record at the start of `bytes`, or `None` if it is incomplete,
+/// fails its checksum or has no valid path.
+fn parse_record(bytes: &[u8]) -> Option<Path> {
+    let header = RECORD_HEADER as usize;
+
+    if bytes.len() < header {
+        return None;
+    }
+
+    let len = read_u32(bytes) as usize;
+
+    if bytes.len() - header < len {
+        return None;
+    }
+
+    let mut payload = &bytes[header..header + len];
+
+    if crc32(payload) != read_u32(&bytes[4..]) {
+        return None;
+    }
+
+    bincode::deserialize_from(&mut payload, bincode::Infinite).ok()
+}
+
+fn read_record(dir: &std::path::Path, location: &Location) -> Result<NodeTree, StoreError> {
+    let mut file = try!(File::open(segment_path(dir, location.segment)).map_err(read_error));
+    let mut buffer = vec![0; RECORD_HEADER as usize + location.len as usize];
+
+    try!(file.seek(SeekFrom::Start(location.offset)).map_err(read_error));
+    try!(file.read_exact(&mut buffer).map_err(read_error));
+
+    if crc32(&buffer[RECORD_HEADER as usize..]) != read_u32(&buffer[4..]) {
+        let message = format!("Bad checksum in segment {} at offset {}", location.segment, location.offset);
+
+        return Err(StoreError::CorruptError(Box::new(io::Error::new(ErrorKind::InvalidData, message))));
+    }
+
+    let mut payload = &buffer[RECORD_HEADER as usize..];
+    let _: Path = try!(bincode::deserialize_from(&mut payload, bincode::Infinite).map_err(read_error));
+
+    bincode::deserialize_from(&mut payload, bincode::Infinite).map_err(read_error)
+}
+
+/// Writes a record, returning the length of its payload.
+fn write_record(file: &mut File, path: &Path, diff: &[u8]) -> Result<u32, StoreError> {
+    let mut payload = try!(bincode::serialize(path, bincode::Infinite).map_err(write_error));
+
+    payload.extend_from_slice(diff);
+
+    let len = payload.len() as u32;
+    let crc = crc32(&payload);
+    let mut record = Vec::with_capacity(RECORD_HEADER as usize + payload.len());
+
+    record.extend_from_slice(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]);
+    record.extend_from_slice(&[crc as u8, (crc >> 8) as u8, (crc >> 16) as u8, (crc >> 24) as u8]);
+    record.extend_from_slice(&payload);
+
+    try!(file.write_all(&record).map_err(write_error));
+
+    Ok(len)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+fn open_segment(dir: &std::path::Path, id: u64) -> Result<File, StoreError> {
+    OpenOptions::new().create(true).append(true).open(segment_path(dir, id)).map_err(write_error)
+}
+
+fn segment_path(dir: &std::path::Path, id: u64) -> PathBuf {
+    dir.join(format!("segment_{:016}.log", id))
+}
+
+fn segment_id(filename: &str) -> Option<u64> {
+    if filename.starts_with("segment_") && filename.ends_with(".log") {
+        filename[8..filename.len() - 4].parse().ok()
+    }
+    else {
+        None
+    }
+}
+
+fn read_error<E: Error + 'static>(err: E) -> StoreError {
+    StoreError::ReadError(Box::new(err))
+}
+
+fn write_error<E: Error + 'static>(err: E) -> StoreError {
+    StoreError::WriteError(Box::new(err))
+}
+
+#[test]
+fn test_append_compact() {
+    use serde_json::Value as JSON;
+
+    use node::Node;
+
+    let dir = "test_data/log";
+
+    if std::path::Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    let diff = |key: &str, value: u64, ts: u64| {
+        let tree = Node::expand_from(&[key.to_string()], JSON::from(value), ts).noop_vis();
+
+        bincode::serialize(&tree, bincode::Infinite).unwrap()
+    };
+
+    let expected = |state: &Mutex<LogState>| {
+        (read_zone(state, &path!(moo)).unwrap(), read_zone(state, &path!(cow)).unwrap())
+    };
+
+    // Tiny segments, so every append seals a segment
+    let mut state = LogState::open(dir, 1, Durability::None).unwrap();
+
+    for i in 0..5 {
+        state.append(&path!(moo), &diff(&i.to_string(), i, 1000 + i)).unwrap();
+        state.append(&path!(cow), &diff("x", i, 1000 + i)).unwrap();
+    }
+
+    assert_eq!(state.sealed.len(), 10);
+
+    let state = Mutex::new(state);
+    let (moo, cow) = expected(&state);
+
+    assert_eq!(moo.tree.node.len(), 5);
+    assert_eq!(cow.tree.node.len(), 1);
+
+    // Index is rebuilt on open
+    let state = Mutex::new(LogState::open(dir, 1, Durability::None).unwrap());
+
+    assert_eq!(expected(&state), (moo.clone(), cow.clone()));
+
+    compact(&state).unwrap();
+
+    let (active, compacted) = {
+        let state = state.lock().unwrap();
+
+        // Compaction sealed the active segment, so appends go to the newest segment
+        assert_eq!(state.sealed.len(), 2);
+        assert_eq!(state.index[&path!(moo)].len(), 1);
+        assert_eq!(state.active, *state.sealed.iter().next_back().unwrap() + 1);
+
+        (segment_path(&state.dir, state.active), segment_path(&state.dir, state.index[&path!(moo)][0].segment))
+    };
+
+    assert_eq!(expected(&state), (moo.clone(), cow.clone()));
+
+    // Torn append to the active segment is truncated
+    OpenOptions::new().append(true).open(&active).unwrap().write_all(&[100, 0, 0, 0, 1]).unwrap();
+
+    let state = Mutex::new(LogState::open(dir, 1, Durability::None).unwrap());
+
+    assert_eq!(expected(&state), (moo, cow));
+    assert_eq!(std::fs::metadata(&active).unwrap().len(), 0);
+
+    // A flipped byte in a sealed segment is corruption
+    drop(state);
+
+    let mut file = OpenOptions::new().read(true).write(true).open(&compacted).unwrap();
+    let mut byte = [0];
+    let last = file.metadata().unwrap().len() - 1;
+
+    file.seek(SeekFrom::Start(last)).unwrap();
+    file.read_exact(&mut byte).unwrap();
+    file.seek(SeekFrom::Start(last)).unwrap();
+    file.write_all(&[byte[0] ^ 1]).unwrap();
+    drop(file);
+
+    assert!(match LogState::open(dir, 1, Durability::None) { Err(StoreError::CorruptError(_)) => true, _ => false });
+}
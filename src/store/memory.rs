@@ -0,0 +1,349 @@
+//! An in-memory zone store for tests and ephemeral deployments.
+//!
+//! Serialized `ZoneData` is kept per `Path`, so `Zone`s can hibernate and reload as with `FS`.
+//! Latency and read / write failures can be injected through `Faults` to exercise `Zone` state
+//! transitions.
+
+use std;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use bincode;
+use threadpool::ThreadPool;
+
+use super::*;
+use app::AppHandle;
+use path::Path;
+use zone::{ZoneData, ZoneHandle};
+
+const NUM_THREADS: usize = 4;
+
+pub struct Memory {
+    app: AppHandle,
+
+    data: Arc<Mutex<HashMap<Path, Vec<u8>>>>,
+    faults: Faults,
+
+    read_pool: ThreadPool,
+    write_pool: ThreadPool,
+
+    write_queue: Arc<Mutex<VecDeque<ZoneHandle>>>
+}
+
+/// Shareable handle to inject faults into a `Memory` store.
+#[derive(Clone)]
+pub struct Faults {
+    state: Arc<Mutex<FaultState>>
+}
+
+#[derive(Clone, Copy)]
+struct FaultState {
+    latency: Duration,
+    fail_reads: bool,
+    fail_writes: bool,
+    corrupt_reads: bool
+}
+
+impl Memory {
+    pub fn new(app: AppHandle, faults: Faults) -> Memory {
+        Memory {
+            app: app,
+            data: Arc::new(Mutex::new(HashMap::new())),
+            faults: faults,
+            read_pool: ThreadPool::new(NUM_THREADS),
+            write_pool: ThreadPool::new(NUM_THREADS),
+            write_queue: Arc::new(Mutex::new(VecDeque::new()))
+        }
+    }
+}
+
+impl Store for Memory {
+    /// Lists all Zone Paths stored
+    fn list(&self, tx: Sender<Path>) {
+        let data = self.data.lock().unwrap();
+
+        for path in data.keys() {
+            tx.send(path.clone()).is_ok(); // ignore if caller goes away
+        }
+    }
+
+    /// Loads data for a `Zone` asynchronously, notifying its handle when done.
+    fn load(&self, zone: ZoneHandle, path: Path) {
+        let data = self.data.clone();
+        let faults = self.faults.get();
+
+        self.app.stats.store.reads_pending.increment();
+
+        let app = self.app.clone();
+        let stats = self.app.stats.clone();
+
+        self.read_pool.execute(move|| {
+            thread::sleep(faults.latency);
+
+            match read(&data, &path, &faults) {
+                Err(StoreError::CorruptError(err)) => {
+                    error!("Corrupted {:?}: {}", path, err);
+                    stats.store.corruptions.increment();
+                    data.lock().unwrap().remove(&path);
+
+                    app.cluster.recover_zone(path.clone());
+                    zone.load_failed(format!("Corrupted, recovering from replicas: {}", err), true);
+                },
+                Err(err) => {
+                    error!("Error loading {:?}: {}", path, err.description());
+                    stats.store.reads_errors.increment();
+                    zone.load_failed(err.description().into(), false);
+                },
+                Ok(data) => zone.loaded(data)
+            };
+
+            stats.store.reads_pending.decrement();
+            stats.store.reads.increment();
+        });
+    }
+
+    /// Asynchronously load and send `ZoneData` for `Path` to channel.
+    fn load_data(&self, path: Path, tx: Sender<Option<ZoneData>>) {
+        let data = self.data.clone();
+        let faults = self.faults.get();
+
+        self.read_pool.execute(move|| {
+            thread::sleep(faults.latency);
+
+            tx.send(read(&data, &path, &faults).ok()).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Request for notification to write data.
+    fn request_write(&self, zone: ZoneHandle) {
+        if self.write_pool.active_count() >= NUM_THREADS {
+            // No write slots available, save for later
+            self.write_queue.lock().unwrap().push_back(zone);
+        }
+        else {
+            zone.save();
+        }
+    }
+
+    /// Write data for a `Zone` asynchronously, notifying its handle when done.
+    fn write(&self, zone: ZoneHandle, path: Path, write: ZoneWrite) {
+        let data = self.data.clone();
+        let faults = self.faults.get();
+        let pending = self.write_queue.clone();
+
+        self.app.stats.store.writes_pending.increment();
+
+        let stats = self.app.stats.clone();
+
+        self.write_pool.execute(move|| {
+            thread::sleep(faults.latency);
+
+            if faults.fail_writes {
+                error!("Error writing {:?}: injected failure", path);
+                stats.store.writes_errors.increment();
+                zone.save_failed("injected failure".into());
+            }
+            else {
+                data.lock().unwrap().insert(path, write.data);
+                zone.saved();
+            }
+
+            stats.store.writes_pending.decrement();
+            stats.store.writes.increment();
+
+            let mut pending = pending.lock().unwrap();
+
+            // "Wake" any zones waiting to write
+            if let Some(zone) = pending.pop_front() {
+                zone.save();
+            }
+        });
+    }
+
+    /// Blocks until writes in progress are complete.
+    fn flush(&self) {
+        self.write_pool.join();
+    }
+
+    /// Data is not stored in files.
+    fn snapshot(&self, _: &std::path::Path) -> Result<Vec<String>, StoreError> {
+        let err = io::Error::new(io::ErrorKind::Other, "memory store cannot be snapshotted");
+
+        Err(StoreError::OtherError(Box::new(err)))
+    }
+}
+
+impl Faults {
+    pub fn new() -> Faults {
+        Faults {
+            state: Arc::new(Mutex::new(FaultState {
+                latency: Duration::from_millis(0),
+                fail_reads: false,
+                fail_writes: false,
+                corrupt_reads: false
+            }))
+        }
+    }
+
+    /// Delay every read and write by `latency`.
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// Fail all reads until reset.
+    pub fn fail_reads(&self, fail: bool) {
+        self.state.lock().unwrap().fail_reads = fail;
+    }
+
+    /// Fail all writes until reset.
+    pub fn fail_writes(&self, fail: bool) {
+        self.state.lock().unwrap().fail_writes = fail;
+    }
+
+    /// Report stored data as corrupted on reads until reset. Loading drops it, as `FS` quarantines
+    /// corrupted files.
+    pub fn corrupt_reads(&self, corrupt: bool) {
+        self.state.lock().unwrap().corrupt_reads = corrupt;
+    }
+
+    fn get(&self) -> FaultState {
+        *self.state.lock().unwrap()
+    }
+}
+
+fn read(data: &Mutex<HashMap<Path, Vec<u8>>>, path: &Path, faults: &FaultState) -> Result<ZoneData, StoreError> {
+    if faults.fail_reads {
+        let err = io::Error::new(io::ErrorKind::Other, "injected failure");
+
+        return Err(StoreError::ReadError(Box::new(err)));
+    }
+
+    if faults.corrupt_reads && data.lock().unwrap().contains_key(path) {
+        let err = io::Error::new(io::ErrorKind::InvalidData, "injected corruption");
+
+        return Err(StoreError::CorruptError(Box::new(err)));
+    }
+
+    match data.lock().unwrap().get(path) {
+        None => Ok(Default::default()),
+        Some(serialized) => bincode::deserialize(serialized).map_err(|err| {
+            StoreError::ReadError(Box::new(err))
+        })
+    }
+}
+
+#[test]
+fn test_hibernate_reload() {
+    use std::time::Instant;
+
+    use mioco::sync::mpsc::channel;
+    use serde_json;
+
+    use app::App;
+    use command::Command;
+    use manager::Manager;
+    use zone::ZoneState;
+
+    let mut app = App::new("127.0.0.1:1000".parse().unwrap());
+    let faults = Faults::new();
+
+    start(Memory::new(app.handle(), faults.clone()), app.channels.store.take().unwrap());
+    Manager::spawn(&mut app);
+
+    let wait_for = |zone: &ZoneHandle, f: &Fn(ZoneState) -> bool| {
+        let start = Instant::now();
+
+        while ! f(zone.state()) {
+            assert!(start.elapsed() < Duration::from_secs(5), "Timed out in {:?}", zone.state());
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    let (tx, _rx) = channel();
+    let zone = app.manager.load(&Path::empty());
+
+    faults.set_latency(Duration::from_millis(20));
+
+    zone.dispatch(Command::from_json(r#"[ 1, "write", [], { "cow": 42 } ]"#).unwrap(), &tx);
+    wait_for(&zone, &|s| s.is_active());
+
+    zone.hibernate();
+    wait_for(&zone, &|s| s.is_idle());
+
+    let result = zone.dispatch(Command::from_json(r#"[ 2, "read", ["cow"], null ]"#).unwrap(), &tx);
+    let expected: serde_json::Value = serde_json::from_str(r#"[ { "cow": [ null, true, 42.0 ] }, null, null ]"#).unwrap();
+
+    assert_eq!(result.update.unwrap().to_json(), expected);
+
+    // Failed reads put Zone in error state until cleared
+    zone.hibernate();
+    wait_for(&zone, &|s| s.is_idle());
+    faults.fail_reads(true);
+
+    let result = zone.dispatch(Command::from_json(r#"[ 3, "read", ["cow"], null ]"#).unwrap(), &tx);
+
+    assert!(result.error.is_some());
+    assert!(zone.state().is_error());
+    assert!(zone.error().is_some());
+
+    faults.fail_reads(false);
+    zone.clear_error();
+    wait_for(&zone, &|s| s.is_active());
+    assert_eq!(zone.error(), None);
+
+    // Corrupted data is dropped, Zone stays in error state until a replica's copy is merged
+    let copy = zone.dump();
+
+    zone.hibernate();
+    wait_for(&zone, &|s| s.is_idle());
+    faults.corrupt_reads(true);
+
+    let result = zone.dispatch(Command::from_json(r#"[ 4, "read", ["cow"], null ]"#).unwrap(), &tx);
+
+    assert!(result.error.is_some());
+    assert!(zone.error().unwrap().starts_with("Corrupted"));
+    thread::sleep(Duration::from_millis(200)); // past the first retry delay
+    assert!(zone.state().is_error());
+
+    faults.corrupt_reads(false);
+    zone.merge(copy, false);
+    wait_for(&zone, &|s| s.is_ready());
+
+    let result = zone.dispatch(Command::from_json(r#"[ 5, "read", ["cow"], null ]"#).unwrap(), &tx);
+
+    assert_eq!(result.update.unwrap().to_json(), expected);
+    assert_eq!(zone.error(), None);
+    wait_for(&zone, &|s| s.is_active());
+
+    // Failed writes leave Zone dirty until retried
+    let errors = app.stats.store.writes_errors.value();
+
+    faults.fail_writes(true);
+
+    zone.dispatch(Command::from_json(r#"[ 6, "write", ["cow"], 43 ]"#).unwrap(), &tx);
+
+    let start = Instant::now();
+
+    while app.stats.store.writes_errors.value() == errors {
+        assert!(start.elapsed() < Duration::from_secs(5), "Timed out in {:?}", zone.state());
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    wait_for(&zone, &|s| s.is_dirty());
+
+    faults.fail_writes(false);
+    wait_for(&zone, &|s| s.is_active());
+
+    zone.hibernate();
+    wait_for(&zone, &|s| s.is_idle());
+
+    let result = zone.dispatch(Command::from_json(r#"[ 7, "read", ["cow"], null ]"#).unwrap(), &tx);
+    let expected: serde_json::Value = serde_json::from_str(r#"[ { "cow": [ null, true, 43.0 ] }, null, null ]"#).unwrap();
+
+    assert_eq!(result.update.unwrap().to_json(), expected);
+}
@@ -0,0 +1,45 @@
+//! Migrations between on-disk format versions.
+//!
+//! Whenever the encoding of `ZoneData` changes (e.g. a change to `Node`, `Vis` or `Value`),
+//! `format::VERSION` is bumped and a migration is registered here that upgrades payloads of the
+//! previous version. Old files are upgraded when loaded, and rewritten on the next write.
+
+use std::error::Error;
+
+use super::format::VERSION;
+
+/// Upgrades a payload by a single version.
+pub type Migration = fn(Vec<u8>) -> Result<Vec<u8>, Box<Error>>;
+
+/// Registered migrations, indexed by the version they upgrade from.
+const MIGRATIONS: &'static [Migration] = &[
+    v0_to_v1,
+    v1_to_v2,
+    v2_to_v3
+];
+
+/// Upgrades `payload` from `version` to the current version.
+pub fn migrate(version: u16, mut payload: Vec<u8>) -> Result<Vec<u8>, Box<Error>> {
+    assert_eq!(MIGRATIONS.len(), VERSION as usize, "Missing migrations");
+
+    for migration in &MIGRATIONS[version as usize..] {
+        payload = try!(migration(payload));
+    }
+
+    Ok(payload)
+}
+
+/// Version 0 files have no header. The payload is unchanged.
+fn v0_to_v1(payload: Vec<u8>) -> Result<Vec<u8>, Box<Error>> {
+    Ok(payload)
+}
+
+/// Version 2 adds header flags for compression. The payload is unchanged.
+fn v1_to_v2(payload: Vec<u8>) -> Result<Vec<u8>, Box<Error>> {
+    Ok(payload)
+}
+
+/// Version 3 adds the encryption key id to the header. The payload is unchanged.
+fn v2_to_v3(payload: Vec<u8>) -> Result<Vec<u8>, Box<Error>> {
+    Ok(payload)
+}
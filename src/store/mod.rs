@@ -4,29 +4,114 @@
 //!
 //! Zones can load data or request to save data. When requesting to save data, `Store` will notify
 //! the Zone when it is not busy, at which point the Zone can send its latest copy of its data.
+//!
+//! Backends implement the `Store` trait, and are driven by `process`. The backend is chosen when
+//! the Store "process" is spawned.
+//!
+//! Writes can be paused, e.g. to snapshot a consistent set of files. While paused, write requests
+//! are deferred until resumed, and backends pause background work such as compaction.
 
+pub mod encryption;
+pub mod format;
 pub mod fs;
+pub mod log;
+pub mod memory;
+pub mod migrate;
 pub mod null;
 
+use std;
+use std::collections::VecDeque;
 use std::error::Error;
 use std::fmt;
+use std::fs::File;
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
 
 use bincode;
 
+use app::App;
+use compression::Compression;
+use self::encryption::Keyring;
+use replica::Replica;
+use node::NodeTree;
 use path::Path;
 use zone::{ZoneData, ZoneHandle};
 
+/// Storage backend. Methods are called from the Store "process" and should not block, i.e.
+/// reads and writes are expected to complete asynchronously and notify the `Zone` when done.
+pub trait Store {
+    /// Lists all Zone Paths stored locally
+    fn list(&self, tx: Sender<Path>);
+
+    /// Loads data for a `Zone` asynchronously, notifying its handle when done.
+    fn load(&self, zone: ZoneHandle, path: Path);
+
+    /// Asynchronously load and send `ZoneData` for `Path` to channel.
+    fn load_data(&self, path: Path, tx: Sender<Option<ZoneData>>);
+
+    /// Request for notification to write data.
+    fn request_write(&self, zone: ZoneHandle);
+
+    /// Write data for a `Zone` asynchronously, notifying its handle when done.
+    fn write(&self, zone: ZoneHandle, path: Path, data: ZoneWrite);
+
+    /// Parts of `ZoneWrite` this backend stores, `WRITE_DATA` and / or `WRITE_DIFF`. Other parts
+    /// are not serialized and left empty.
+    fn consumes(&self) -> usize {
+        WRITE_DATA
+    }
+
+    /// Blocks until writes in progress are complete.
+    fn flush(&self);
+
+    /// Blocks until background work changing stored files is complete, and stops it until
+    /// `resume`. Called when writes are paused.
+    fn pause(&self) {}
+
+    /// Resumes background work.
+    fn resume(&self) {}
+
+    /// Hard links or copies all stored files into `dir`, returning their names. Only called while
+    /// writes are paused.
+    fn snapshot(&self, dir: &std::path::Path) -> Result<Vec<String>, StoreError>;
+}
+
+/// Available `Store` backends.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    FS,
+    Log,
+    Memory,
+    Null
+}
+
+/// How far writes are flushed before a `Zone` is notified that its data is saved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    /// Leave flushing to the OS. Fastest, but a power loss may lose or zero out recent writes.
+    None,
+    /// fsync written files.
+    File,
+    /// fsync written files and their directory, so new and renamed files survive a power loss.
+    Dir
+}
+
 /// A handle to the Store process. This is the shareable public interface.
 #[derive(Clone)]
 pub struct StoreHandle {
-    tx: Sender<StoreCall>
+    tx: Sender<StoreCall>,
+    consumes: Arc<AtomicUsize>
 }
 
 /// Channel (both ends) to talk to Store, `rx` needed to spawn Store.
 pub struct StoreChannel {
     rx: Receiver<StoreCall>,
-    tx: Sender<StoreCall>
+    tx: Sender<StoreCall>,
+    consumes: Arc<AtomicUsize>
 }
 
 /// Used for dispatching calls via message passing.
@@ -35,26 +120,185 @@ pub enum StoreCall {
     Load(ZoneHandle, Path),
     LoadData(Path, Sender<Option<ZoneData>>),
     RequestWrite(ZoneHandle),
-    Write(ZoneHandle, Path, Vec<u8>)
+    Write(ZoneHandle, Path, ZoneWrite),
+    Pause(Sender<()>),
+    Resume,
+    Snapshot(std::path::PathBuf, Sender<Result<Vec<String>, String>>)
+}
+
+/// `ZoneWrite` part with the entire `ZoneData`, see `Store::consumes`.
+pub const WRITE_DATA: usize = 1;
+
+/// `ZoneWrite` part with only the changes, see `Store::consumes`.
+pub const WRITE_DIFF: usize = 2;
+
+/// Serialized data sent by a `Zone` to be persisted. Backends may store either the entire
+/// `ZoneData` or only the changes.
+pub struct ZoneWrite {
+    /// Serialized `ZoneData`
+    pub data: Vec<u8>,
+
+    /// Serialized `NodeTree` of changes since the previous write
+    pub diff: Vec<u8>
 }
 
 /// Storage error that includes generic Error-implementing errors
 #[derive(Debug)]
 pub enum StoreError {
+    /// Data was read but failed verification
+    CorruptError(Box<Error>),
     ReadError(Box<Error>),
     OtherError(Box<Error>),
     WriteError(Box<Error>)
 }
 
+/// Start the Store "process" with the given `backend`. Only `Backend::FS` supports compression
+/// and encryption.
+pub fn spawn(app: &mut App, backend: Backend, durability: Durability, compression: Compression, keys: Arc<Keyring>) {
+    let channel = app.channels.store.take().expect("Receiver already taken");
+
+    match backend {
+        Backend::FS => {
+            start(fs::FS::new(app.handle(), &app.data_dir, durability, compression, keys), channel);
+        },
+        Backend::Log => {
+            start(log::Log::new(app.handle(), &app.data_dir, durability), channel);
+        },
+        Backend::Memory => start(memory::Memory::new(app.handle(), memory::Faults::new()), channel),
+        Backend::Null => start(null::Null::new(), channel)
+    }
+}
+
+/// Default data directory of file based backends.
+pub fn data_dir(id: &Replica) -> String {
+    format!("data_{}", id)
+}
+
+/// Runs `store` in its own thread, handling calls made through `channel`.
+pub fn start<S: Store + Send + 'static>(store: S, channel: StoreChannel) {
+    channel.consumes.store(store.consumes(), Ordering::SeqCst);
+
+    thread::Builder::new().name("Store".into()).spawn(move|| {
+        process(store, channel.rx);
+    }).expect("Store spawn failed");
+}
+
+/// Generic Store message loop. Dispatches calls to any `Store` implementation until all handles
+/// are dropped.
+pub fn process<S: Store>(store: S, rx: Receiver<StoreCall>) {
+    let mut paused = false;
+    let mut deferred = VecDeque::new();
+
+    loop {
+        let call = match rx.recv() {
+            Ok(call) => call,
+            Err(_) => return
+        };
+
+        match call {
+            StoreCall::List(reply) => store.list(reply),
+            StoreCall::Load(zone, path) => store.load(zone, path),
+            StoreCall::LoadData(path, tx) => store.load_data(path, tx),
+            StoreCall::RequestWrite(zone) => {
+                match paused {
+                    true => deferred.push_back(StoreCall::RequestWrite(zone)),
+                    false => store.request_write(zone)
+                }
+            },
+            StoreCall::Write(zone, path, data) => {
+                match paused {
+                    true => deferred.push_back(StoreCall::Write(zone, path, data)),
+                    false => store.write(zone, path, data)
+                }
+            },
+            StoreCall::Pause(reply) => {
+                paused = true;
+                store.flush();
+                store.pause();
+                reply.send(()).is_ok(); // ignore if caller goes away
+            },
+            StoreCall::Resume => {
+                paused = false;
+                store.resume();
+
+                for call in deferred.drain(..) {
+                    match call {
+                        StoreCall::RequestWrite(zone) => store.request_write(zone),
+                        StoreCall::Write(zone, path, data) => store.write(zone, path, data),
+                        _ => unreachable!()
+                    }
+                }
+            },
+            StoreCall::Snapshot(dir, reply) => {
+                let result = store.snapshot(&dir).map_err(|err| err.to_string());
+
+                reply.send(result).is_ok(); // ignore if caller goes away
+            }
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Backend, String> {
+        match s {
+            "fs" => Ok(Backend::FS),
+            "log" => Ok(Backend::Log),
+            "memory" => Ok(Backend::Memory),
+            "null" => Ok(Backend::Null),
+            _ => Err(format!("Unknown store backend: {}", s))
+        }
+    }
+}
+
+impl Durability {
+    pub fn sync_file(&self) -> bool {
+        *self != Durability::None
+    }
+
+    pub fn sync_dir(&self) -> bool {
+        *self == Durability::Dir
+    }
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Durability, String> {
+        match s {
+            "none" => Ok(Durability::None),
+            "file" => Ok(Durability::File),
+            "dir" => Ok(Durability::Dir),
+            _ => Err(format!("Unknown durability: {}", s))
+        }
+    }
+}
+
+/// Hard links `src` to `dst`, falling back to copying (e.g. across filesystems).
+pub fn link_or_copy(src: &std::path::Path, dst: &std::path::Path) -> io::Result<()> {
+    std::fs::hard_link(src, dst).or_else(|_| std::fs::copy(src, dst).map(|_| ()))
+}
+
+/// Flushes directory entries, e.g. after creating or renaming files in `dir`.
+pub fn sync_dir(dir: &std::path::Path) -> io::Result<()> {
+    File::open(dir).and_then(|d| d.sync_all())
+}
+
 impl StoreChannel {
     pub fn new() -> StoreChannel {
         let (tx, rx) = channel();
 
-        StoreChannel { rx: rx, tx: tx }
+        StoreChannel {
+            rx: rx,
+            tx: tx,
+            // Until started, writes are serialized for any backend
+            consumes: Arc::new(AtomicUsize::new(WRITE_DATA | WRITE_DIFF))
+        }
     }
 
     pub fn handle(&self) -> StoreHandle {
-        StoreHandle { tx: self.tx.clone() }
+        StoreHandle { tx: self.tx.clone(), consumes: self.consumes.clone() }
     }
 }
 
@@ -84,18 +328,46 @@ impl StoreHandle {
         rx.recv().unwrap()
     }
 
+    /// Pauses writes, returning once writes in progress are complete.
+    pub fn pause(&self) {
+        let (tx, rx) = channel();
+
+        self.tx.send(StoreCall::Pause(tx)).unwrap();
+
+        rx.recv().unwrap()
+    }
+
+    /// Resumes paused writes.
+    pub fn resume(&self) {
+        self.tx.send(StoreCall::Resume).unwrap();
+    }
+
+    /// Links or copies all stored files into `dir`, returning their names. Writes should be paused.
+    pub fn snapshot(&self, dir: &std::path::Path) -> Result<Vec<String>, String> {
+        let (tx, rx) = channel();
+
+        self.tx.send(StoreCall::Snapshot(dir.to_path_buf(), tx)).unwrap();
+
+        rx.recv().unwrap()
+    }
+
     /// Ask for non-busy write notification.
     pub fn request_write(&self, zone: &ZoneHandle) {
         self.tx.send(StoreCall::RequestWrite(zone.clone())).unwrap();
     }
 
-    /// Saves data for a zone and notifies zone directly via its handle.
-    pub fn write(&self, zone: &ZoneHandle, path: &Path, data: &ZoneData) {
+    /// Saves data for a zone and notifies zone directly via its handle. `diff` contains changes
+    /// since the previous write. Only the parts the backend consumes are serialized.
+    pub fn write(&self, zone: &ZoneHandle, path: &Path, data: &ZoneData, diff: &NodeTree) {
         // Optimization: seralize to send over channel instead of cloning ZoneData
         let limit = bincode::Infinite;
-        let serialized = bincode::serialize(&data, limit).unwrap();
+        let consumes = self.consumes.load(Ordering::SeqCst);
+        let write = ZoneWrite {
+            data: if consumes & WRITE_DATA != 0 { bincode::serialize(&data, limit).unwrap() } else { vec![] },
+            diff: if consumes & WRITE_DIFF != 0 { bincode::serialize(&diff, limit).unwrap() } else { vec![] }
+        };
 
-        self.tx.send(StoreCall::Write(zone.clone(), path.clone(), serialized)).unwrap();
+        self.tx.send(StoreCall::Write(zone.clone(), path.clone(), write)).unwrap();
     }
 
     /// Creates a noop StoreHandle for testing
@@ -104,7 +376,8 @@ impl StoreHandle {
         use std::sync::mpsc::channel;
 
         StoreHandle {
-            tx: channel().0
+            tx: channel().0,
+            consumes: Arc::new(AtomicUsize::new(WRITE_DATA | WRITE_DIFF))
         }
     }
 }
@@ -112,6 +385,7 @@ impl StoreHandle {
 impl fmt::Display for StoreError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            StoreError::CorruptError(ref err) => write!(f, "Corrupt data: {}", err),
             StoreError::ReadError(ref err) => write!(f, "Read error: {}", err.description()),
             StoreError::OtherError(ref err) => write!(f, "Other error: {}", err.description()),
             StoreError::WriteError(ref err) => write!(f, "Write error: {}", err.description())
@@ -122,6 +396,7 @@ impl fmt::Display for StoreError {
 impl Error for StoreError {
     fn description(&self) -> &str {
         match *self {
+            StoreError::CorruptError(ref err) => err.description(),
             StoreError::ReadError(ref err) => err.description(),
             StoreError::OtherError(ref err) => err.description(),
             StoreError::WriteError(ref err) => err.description()
@@ -130,6 +405,7 @@ impl Error for StoreError {
 
     fn cause(&self) -> Option<&Error> {
         match *self {
+            StoreError::CorruptError(ref err) => Some(&**err),
             StoreError::ReadError(ref err) => Some(&**err),
             StoreError::OtherError(ref err) => Some(&**err),
             StoreError::WriteError(ref err) => Some(&**err)
@@ -1,5 +1,7 @@
 /// Leaf value storable in Node
 
+use serde_json::Value as JSON;
+
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum Value {
     /// Represents a JSON null value
@@ -27,6 +29,19 @@ impl Default for Value {
     }
 }
 
+impl Value {
+    pub fn to_json(&self) -> JSON {
+        match *self {
+            Value::Null => JSON::Null,
+            Value::Bool(v) => JSON::Bool(v),
+            Value::I64(v) => v.into(),
+            Value::U64(v) => v.into(),
+            Value::F64(v) => v.into(),
+            Value::String(ref s) => JSON::String(String::from(&**s))
+        }
+    }
+}
+
 impl From<String> for Value {
     fn from(s: String) -> Self {
         Value::String(s.into_boxed_str())
@@ -0,0 +1,591 @@
+//! Write-ahead log for acknowledged client writes.
+//!
+//! `Zone`s persist their data some time after a write is acknowledged. To not lose acknowledged
+//! writes in a crash, `write` and `kill` commands are appended to the WAL before they are
+//! dispatched, and replayed into their Zones on startup. Replaying is safe as merges are
+//! idempotent.
+//!
+//! Each `Zone` reports when it holds unsaved changes, and since when. An entry is no longer needed
+//! once it was dispatched before every currently unsaved change was made, i.e. the Zone it was
+//! merged into has saved it since. Segments are deleted once all their entries are no longer
+//! needed, oldest first.
+//!
+//! Data routed to delegated Zones is merged asynchronously. A `Hold` counts as an unsaved change
+//! until the delegated Zone has merged the data and reported it as unsaved itself.
+//!
+//! Writes that fail, e.g. as their Zone could not be loaded, are cancelled so that they are not
+//! replayed.
+//!
+//! Records are stored as `[length: u32 LE][checksum: u32 LE][bincode Entry]`. Records cancelling
+//! an entry have `CANCEL` set in the length, and a bincode `(segment, offset)` of the entry.
+
+use std;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use bincode;
+use mioco::sync::mpsc::{channel, Receiver, Sender};
+use serde_json;
+use time;
+
+use app::{App, AppHandle};
+use command::Command;
+use path::Path;
+use store::{self, Durability};
+use store::format::crc32;
+
+/// Size at which the active segment is sealed.
+const SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Set in the length of a record cancelling an entry.
+const CANCEL: u32 = 1 << 31;
+
+/// A handle to the WAL process. This is the shareable public interface.
+#[derive(Clone)]
+pub struct WalHandle {
+    next_hold: Arc<AtomicUsize>,
+    tx: Sender<WalCall>
+}
+
+/// Keeps entries dispatched after `since` while data is merged into another Zone, released when
+/// dropped. See `Zone::merge`.
+pub struct Hold {
+    id: usize,
+    pub since: u64,
+    wal: WalHandle
+}
+
+/// Used for dispatching calls via message passing.
+enum WalCall {
+    Append(Entry, Sender<u64>),
+    Done(u64, u64),
+    Failed(u64),
+    Hold(usize, u64),
+    Release(usize),
+    Replayed(u64),
+    ZoneDirty(Path, Option<u64>)
+}
+
+/// A logged command.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Entry {
+    call: String,
+    path: Path,
+    params: String, // JSON
+    timestamp: u64
+}
+
+pub struct Wal {
+    app: AppHandle,
+    state: WalState,
+    rx: Receiver<WalCall>
+}
+
+struct WalState {
+    dir: PathBuf,
+    durability: Durability,
+
+    active: u64,                    // Id of segment being appended to
+    active_file: File,
+    segments: BTreeMap<u64, Segment>,
+
+    next_seq: u64,
+    in_flight: HashMap<u64, (u64, u64)>, // Entry seq -> segment id and offset
+    holds: HashMap<usize, u64>,          // Data in transit to delegated Zones -> since
+    zones: HashMap<Path, u64>            // Zones with unsaved changes -> since
+}
+
+struct Segment {
+    len: u64,
+    pending: usize, // Entries being dispatched
+    completed: u64  // Time last entry was dispatched
+}
+
+impl WalHandle {
+    /// Appends `command` to the log, returning its sequence number once written, or `None` if it
+    /// could not be written.
+    pub fn append(&self, command: &Command) -> Option<u64> {
+        let (tx, rx) = channel();
+
+        self.tx.send(WalCall::Append(Entry::new(command), tx)).expect("WAL process not running");
+
+        rx.recv().ok()
+    }
+
+    /// Signals that the command logged as `seq` was dispatched.
+    pub fn done(&self, seq: u64) {
+        self.send(WalCall::Done(seq, time::precise_time_ns()));
+    }
+
+    /// Signals that the command logged as `seq` failed, so it is not replayed.
+    pub fn failed(&self, seq: u64) {
+        self.send(WalCall::Failed(seq));
+    }
+
+    /// Keeps entries dispatched after `since` until the returned `Hold` is dropped.
+    pub fn hold(&self, since: u64) -> Hold {
+        let id = self.next_hold.fetch_add(1, Ordering::SeqCst);
+
+        self.send(WalCall::Hold(id, since));
+
+        Hold { id: id, since: since, wal: self.clone() }
+    }
+
+    /// Called by `Zone` when its earliest unsaved change (if any) changes.
+    pub fn zone_dirty(&self, path: &Path, since: Option<u64>) {
+        self.send(WalCall::ZoneDirty(path.clone(), since));
+    }
+
+    fn send(&self, call: WalCall) {
+        self.tx.send(call).is_ok(); // ignore if WAL is gone
+    }
+}
+
+impl Wal {
+    /// Start the WAL "process" on log in `dir`, returning entries to be replayed with `replay`.
+    /// Must be called before other processes are spawned, as it adds a handle to `App`.
+    pub fn spawn(app: &mut App, dir: &str, durability: Durability) -> Vec<Entry> {
+        let (state, entries) = WalState::open(dir, durability).unwrap();
+        let (tx, rx) = channel();
+
+        app.wal = Some(WalHandle { next_hold: Arc::new(AtomicUsize::new(0)), tx: tx });
+
+        let mut wal = Wal {
+            app: app.handle(),
+            state: state,
+            rx: rx
+        };
+
+        thread::Builder::new().name("WAL".into()).spawn(move|| {
+            wal.message_loop();
+        }).expect("WAL spawn failed");
+
+        entries
+    }
+
+    fn message_loop(&mut self) {
+        loop {
+            let call = match self.rx.recv() {
+                Ok(call) => call,
+                Err(_) => return
+            };
+
+            match call {
+                WalCall::Append(entry, reply) => {
+                    match self.state.append(&entry) {
+                        Err(err) => error!("Error appending to WAL: {}", err),
+                        Ok(seq) => {
+                            self.app.stats.wal.appends.increment();
+                            reply.send(seq).is_ok();
+                        }
+                    }
+                },
+                WalCall::Done(seq, completed) => self.state.done(seq, completed),
+                WalCall::Failed(seq) => if let Err(err) = self.state.fail(seq) {
+                    error!("Error cancelling WAL entry: {}", err);
+                },
+                WalCall::Hold(id, since) => { self.state.holds.insert(id, since); },
+                WalCall::Release(id) => { self.state.holds.remove(&id); },
+                WalCall::Replayed(completed) => self.state.replayed(completed),
+                WalCall::ZoneDirty(path, since) => self.state.zone_dirty(path, since)
+            };
+
+            let removed = self.state.truncate();
+
+            for _ in 0..removed {
+                self.app.stats.wal.truncations.increment();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Hold {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hold({})", self.since)
+    }
+}
+
+impl Drop for Hold {
+    fn drop(&mut self) {
+        self.wal.send(WalCall::Release(self.id));
+    }
+}
+
+/// Replays `entries` from a previous run into their `Zone`s.
+pub fn replay(app: &AppHandle, entries: Vec<Entry>) {
+    let (tx, _rx) = channel();
+
+    for entry in entries {
+        let command = match entry.to_command() {
+            Err(err) => {
+                error!("Bad WAL entry {:?}: {}", entry, err);
+                continue;
+            },
+            Ok(command) => command
+        };
+
+        let (prefix, zone) = app.manager.find_nearest(&command.path.resolved());
+
+        let c = Command {
+            path: command.path.slice(prefix.len()),
+            ..command
+        };
+
+        zone.dispatch(c, &tx);
+        app.stats.wal.replayed.increment();
+    }
+
+    if let Some(ref wal) = app.wal {
+        wal.send(WalCall::Replayed(time::precise_time_ns()));
+    }
+}
+
+impl Entry {
+    fn new(command: &Command) -> Entry {
+        Entry {
+            call: command.call.to_string(),
+            path: command.path.clone(),
+            params: command.params.to_string(),
+            timestamp: command.timestamp
+        }
+    }
+
+    fn to_command(&self) -> Result<Command, String> {
+        Ok(Command {
+            id: 0,
+            call: try!(self.call.parse()),
+            path: self.path.clone(),
+            params: try!(serde_json::from_str(&self.params).map_err(|e| e.to_string())),
+            timestamp: self.timestamp
+        })
+    }
+}
+
+impl WalState {
+    /// Opens log in `dir`, returning all logged entries.
+    fn open(dir: &str, durability: Durability) -> io::Result<(WalState, Vec<Entry>)> {
+        let dir = PathBuf::from(dir);
+
+        if ! dir.is_dir() {
+            try!(DirBuilder::new().recursive(true).create(&dir));
+        }
+
+        let mut ids = vec![];
+
+        for entry in try!(std::fs::read_dir(&dir)) {
+            if let Some(id) = try!(entry).file_name().to_str().and_then(segment_id) {
+                ids.push(id);
+            }
+        }
+
+        ids.sort();
+
+        let mut entries = vec![];
+        let mut cancelled = HashSet::new();
+        let mut segments = BTreeMap::new();
+
+        for &id in &ids {
+            let len = try!(read_segment(&segment_path(&dir, id), id, &mut entries, &mut cancelled));
+
+            // Active segment that was never appended to
+            if len == 0 {
+                try!(std::fs::remove_file(segment_path(&dir, id)));
+                continue;
+            }
+
+            // Kept until replayed entries are saved
+            segments.insert(id, Segment { len: len, pending: 0, completed: std::u64::MAX });
+        }
+
+        let active = ids.last().map_or(0, |id| id + 1);
+        let active_file = try!(open_segment(&dir, active, durability));
+
+        segments.insert(active, Segment { len: 0, pending: 0, completed: 0 });
+
+        let state = WalState {
+            dir: dir,
+            durability: durability,
+            active: active,
+            active_file: active_file,
+            segments: segments,
+            next_seq: 0,
+            in_flight: HashMap::new(),
+            holds: HashMap::new(),
+            zones: HashMap::new()
+        };
+
+        let entries = entries.into_iter()
+            .filter(|&(location, _)| ! cancelled.contains(&location))
+            .map(|(_, entry)| entry)
+            .collect();
+
+        Ok((state, entries))
+    }
+
+    fn append(&mut self, entry: &Entry) -> Result<u64, Box<Error>> {
+        let payload = try!(bincode::serialize(entry, bincode::Infinite));
+        let offset = try!(self.write_record(&payload, 0));
+        let seq = self.next_seq;
+
+        self.next_seq += 1;
+        self.in_flight.insert(seq, (self.active, offset));
+        self.segments.get_mut(&self.active).unwrap().pending += 1;
+
+        try!(self.maybe_rotate());
+
+        Ok(seq)
+    }
+
+    /// Cancels entry `seq`, which is then no longer in flight.
+    fn fail(&mut self, seq: u64) -> Result<(), Box<Error>> {
+        let (id, offset) = match self.in_flight.remove(&seq) {
+            Some(location) => location,
+            None => return Ok(())
+        };
+
+        self.segments.get_mut(&id).unwrap().pending -= 1;
+
+        let payload = try!(bincode::serialize(&(id, offset), bincode::Infinite));
+
+        try!(self.write_record(&payload, CANCEL));
+        try!(self.maybe_rotate());
+
+        Ok(())
+    }
+
+    /// Appends a record to the active segment, returning its offset.
+    fn write_record(&mut self, payload: &[u8], flags: u32) -> Result<u64, Box<Error>> {
+        let mut record = Vec::with_capacity(8 + payload.len());
+
+        write_u32(&mut record, payload.len() as u32 | flags);
+        write_u32(&mut record, crc32(payload));
+        record.extend_from_slice(payload);
+
+        try!(self.active_file.write_all(&record));
+
+        if self.durability.sync_file() {
+            try!(self.active_file.sync_data());
+        }
+
+        let segment = self.segments.get_mut(&self.active).unwrap();
+        let offset = segment.len;
+
+        segment.len += record.len() as u64;
+
+        Ok(offset)
+    }
+
+    fn maybe_rotate(&mut self) -> io::Result<()> {
+        match self.segments[&self.active].len >= SEGMENT_SIZE {
+            true => self.rotate(),
+            false => Ok(())
+        }
+    }
+
+    fn done(&mut self, seq: u64, completed: u64) {
+        if let Some((id, _)) = self.in_flight.remove(&seq) {
+            let segment = self.segments.get_mut(&id).unwrap();
+
+            segment.pending -= 1;
+
+            if completed > segment.completed {
+                segment.completed = completed;
+            }
+        }
+    }
+
+    /// Replayed entries are needed until changes made while replaying are saved.
+    fn replayed(&mut self, completed: u64) {
+        for segment in self.segments.values_mut() {
+            if segment.completed == std::u64::MAX {
+                segment.completed = completed;
+            }
+        }
+    }
+
+    fn zone_dirty(&mut self, path: Path, since: Option<u64>) {
+        match since {
+            Some(since) => self.zones.insert(path, since),
+            None => self.zones.remove(&path)
+        };
+    }
+
+    /// Removes segments whose entries are all saved, returning the number removed. Segments are
+    /// removed oldest first, so that entries are removed before the records cancelling them.
+    fn truncate(&mut self) -> usize {
+        // Changes made before this have been saved
+        let saved = self.zones.values().chain(self.holds.values()).cloned().min().unwrap_or(std::u64::MAX);
+
+        let expired: Vec<u64> = self.segments.iter()
+            .take_while(|&(_, s)| s.pending == 0 && s.completed < saved)
+            .filter(|&(_, s)| s.len > 0)
+            .map(|(&id, _)| id)
+            .collect();
+
+        for &id in &expired {
+            if id == self.active {
+                if let Err(err) = self.rotate() {
+                    error!("Error rotating WAL: {}", err);
+                    continue;
+                }
+            }
+
+            self.segments.remove(&id);
+
+            if let Err(err) = std::fs::remove_file(segment_path(&self.dir, id)) {
+                error!("Error removing WAL segment {}: {}", id, err);
+            }
+        }
+
+        expired.len()
+    }
+
+    /// Seals the active segment and starts a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        let id = self.active + 1;
+
+        self.active_file = try!(open_segment(&self.dir, id, self.durability));
+        self.active = id;
+        self.segments.insert(id, Segment { len: 0, pending: 0, completed: 0 });
+
+        Ok(())
+    }
+}
+
+/// Reads all entries in segment `id` with their segment and offset, and the locations of entries
+/// cancelled. A torn record at the end (e.g. from a crash while appending) is ignored.
+fn read_segment(filepath: &std::path::Path, id: u64, entries: &mut Vec<((u64, u64), Entry)>, cancelled: &mut HashSet<(u64, u64)>) -> io::Result<u64> {
+    let mut buffer = vec![];
+
+    try!(File::open(filepath).and_then(|mut f| f.read_to_end(&mut buffer)));
+
+    let mut offset = 0;
+
+    while offset + 8 <= buffer.len() {
+        let len = read_u32(&buffer[offset..]);
+        let checksum = read_u32(&buffer[offset + 4..]);
+        let start = offset + 8;
+        let end = start + (len & ! CANCEL) as usize;
+
+        if end > buffer.len() || crc32(&buffer[start..end]) != checksum {
+            break;
+        }
+
+        let payload = &buffer[start..end];
+
+        let result = match len & CANCEL {
+            0 => bincode::deserialize(payload).map(|entry| entries.push(((id, offset as u64), entry))),
+            _ => bincode::deserialize(payload).map(|location| { cancelled.insert(location); })
+        };
+
+        if result.is_err() {
+            break;
+        }
+
+        offset = end;
+    }
+
+    if offset < buffer.len() {
+        error!("Torn record in {} at offset {}", filepath.display(), offset);
+    }
+
+    Ok(buffer.len() as u64)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+fn write_u32(bytes: &mut Vec<u8>, n: u32) {
+    bytes.extend_from_slice(&[n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]);
+}
+
+fn open_segment(dir: &std::path::Path, id: u64, durability: Durability) -> io::Result<File> {
+    let file = try!(OpenOptions::new().create(true).append(true).open(segment_path(dir, id)));
+
+    if durability.sync_dir() {
+        try!(store::sync_dir(dir));
+    }
+
+    Ok(file)
+}
+
+fn segment_path(dir: &std::path::Path, id: u64) -> PathBuf {
+    dir.join(format!("wal_{:016}.log", id))
+}
+
+fn segment_id(filename: &str) -> Option<u64> {
+    if filename.starts_with("wal_") && filename.ends_with(".log") {
+        filename[4..filename.len() - 4].parse().ok()
+    }
+    else {
+        None
+    }
+}
+
+#[test]
+fn test_truncate() {
+    let dir = "test_data/wal";
+
+    if std::path::Path::new(dir).exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    let command = Command::from_json(r#"[ 1, "write", ["moo"], { "cow": 42 } ]"#).unwrap();
+    let (mut state, entries) = WalState::open(dir, Durability::None).unwrap();
+
+    assert!(entries.is_empty());
+
+    let seq = state.append(&Entry::new(&command)).unwrap();
+
+    // In flight
+    assert_eq!(state.truncate(), 0);
+
+    state.zone_dirty(path!(moo), Some(100));
+    state.done(seq, 200);
+
+    // Zone has not saved the entry yet
+    assert_eq!(state.truncate(), 0);
+
+    // Entries are replayed after a restart
+    let (mut state, entries) = WalState::open(dir, Durability::None).unwrap();
+
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].to_command().unwrap(), Command { id: 0, ..command.clone() });
+
+    state.replayed(200);
+    state.zone_dirty(path!(moo), Some(100));
+    assert_eq!(state.truncate(), 0);
+
+    // Zone saved
+    state.zone_dirty(path!(moo), None);
+    assert_eq!(state.truncate(), 1);
+
+    let (mut state, entries) = WalState::open(dir, Durability::None).unwrap();
+
+    assert!(entries.is_empty());
+
+    // Failed entries are not replayed, and held data keeps entries
+    let failed = state.append(&Entry::new(&command)).unwrap();
+    let seq = state.append(&Entry::new(&command)).unwrap();
+
+    state.fail(failed).unwrap();
+    state.holds.insert(0, 100);
+    state.done(seq, 200);
+    assert_eq!(state.truncate(), 0);
+
+    let (_, entries) = WalState::open(dir, Durability::None).unwrap();
+
+    assert_eq!(entries.len(), 1);
+
+    state.holds.remove(&0);
+    assert_eq!(state.truncate(), 1);
+}
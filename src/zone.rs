@@ -4,13 +4,18 @@
 //!
 //! `ZoneHandle` is the shareable / clonable public interface to a `Zone`.
 
+use std::cmp;
 use std::collections::VecDeque;
 use std::hash::{Hash, Hasher};
+use std::mem;
 use std::sync::Arc;
+use std::sync::mpsc;
+use std::time::Duration;
 
 use mioco;
 use mioco::sync::mpsc::{channel, Receiver, Sender};
 use serde_json::Value;
+use time;
 
 use app::AppHandle;
 use command::{Call, Command};
@@ -18,6 +23,8 @@ use delegate::delegate;
 use listener::{Listener, RListener};
 use node::{DelegatedMatch, Node, Update, Vis, NodeTree};
 use path::Path;
+use router;
+use wal::Hold;
 
 /// Persistent Zone data
 #[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
@@ -26,6 +33,10 @@ pub struct ZoneData {
     pub tree: NodeTree // Mergeable data for this Zone
 }
 
+/// Delay before first retry to load data after a failure, doubled on each further failure.
+const RETRY_BASE_MS: u64 = 100;
+const RETRY_MAX_MS: u64 = 60 * 1000;
+
 /// Public shareable handle to a `Zone`
 #[derive(Clone)]
 pub struct ZoneHandle {
@@ -36,14 +47,23 @@ pub struct ZoneHandle {
 /// Zones communicate via message passing. This enum is a list of valid calls.
 enum ZoneCall {
     UserCommand(UserCommand),
+    ClearError,
+    Digest(Sender<u64>),
     Dump(Sender<NodeTree>),
+    DumpDigested(Sender<NodeTree>),
+    Error(Sender<Option<String>>),
+    Flush(Box<Fn() + Send>),
     Hibernate,
     Load,
     Loaded(ZoneData),
-    Merge(NodeTree, bool),
-    MergeWithListeners(NodeTree, Vec<RListener>),
+    LoadFailed(String, bool),
+    Merge(NodeTree, bool, Option<Hold>),
+    MergeWithListeners(NodeTree, Vec<RListener>, Option<Hold>),
+    Retry,
+    RetrySave,
     Save,
     Saved,
+    SaveFailed(String),
     Size(Sender<usize>),
     State(Sender<ZoneState>)
 }
@@ -54,10 +74,11 @@ struct UserCommand {
     listener: Sender<String>
 }
 
-#[derive(Default)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct ZoneResult {
     pub update: Option<Update>,
-    pub delegated: Vec<DelegatedMatch>
+    pub delegated: Vec<DelegatedMatch>,
+    pub error: Option<String>
 }
 
 /// Tracks current state of a Zone
@@ -77,6 +98,16 @@ pub struct Zone {
     rx: Receiver<ZoneCall>,     // Zone message inbox
     queued: VecDeque<ZoneCall>, // When Zone data is not active, queue up all commands
     listeners: Vec<Listener>,   // List of binds
+    digest: Option<u64>,        // Digest of data, kept while hibernated as data is saved
+    unsaved: NodeTree,          // Changes since last write
+    unsaved_since: Option<u64>, // Time of first unsaved change
+    writing: NodeTree,          // Changes being written
+    writing_since: Option<u64>, // Time of first change being written
+    flushing: Vec<Box<Fn() + Send>>, // Called once data is saved
+    error: Option<String>,      // Reason data could not be loaded
+    retries: u32,               // Failed attempts to load data
+    recovering: bool,           // Data was lost, waiting for replicas
+    save_retries: u32,          // Failed attempts to save data
     writes: u64                 // Number of writes since last fragment check
     // TODO: size: u64,
     // TODO: prefixes: Option<BTreeMap<String, Node>>
@@ -102,16 +133,41 @@ impl ZoneHandle {
         self.tx.send(ZoneCall::Loaded(data)).unwrap();
     }
 
+    /// Signal `Zone` that data could not be loaded. Usually called by `Store`. With `recover`, the
+    /// data is lost and `Zone` waits for replicas to send theirs instead of retrying.
+    pub fn load_failed(&self, error: String, recover: bool) {
+        self.tx.send(ZoneCall::LoadFailed(error, recover)).unwrap();
+    }
+
+    /// Signal `Zone` in error state to retry loading data now.
+    pub fn clear_error(&self) {
+        self.tx.send(ZoneCall::ClearError).unwrap();
+    }
+
+    /// Gets the reason data could not be loaded, if in error state.
+    pub fn error(&self) -> Option<String> {
+        let (tx, rx) = channel();
+
+        self.tx.send(ZoneCall::Error(tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
     /// Merge data into this `Zone`. The effective parent visibility (through all ancestors) must
     /// be provided.
     pub fn merge(&self, diff: NodeTree, replicate: bool) {
-        self.tx.send(ZoneCall::Merge(diff, replicate)).unwrap();
+        self.merge_held(diff, replicate, None);
+    }
+
+    /// Same as `merge`, for data delegated by another `Zone`. The `Hold` keeps logged writes until
+    /// the data is merged, see `wal`.
+    pub fn merge_held(&self, diff: NodeTree, replicate: bool, hold: Option<Hold>) {
+        self.tx.send(ZoneCall::Merge(diff, replicate, hold)).unwrap();
     }
 
-    /// Same as `merge` except a list of listeners is also provided. The listeners expect to see
-    /// changes that would bring them up to date with data in this `Zone`
-    pub fn merge_with_listeners(&self, diff: NodeTree, listeners: Vec<RListener>) {
-        self.tx.send(ZoneCall::MergeWithListeners(diff, listeners)).unwrap();
+    /// Same as `merge_held` except a list of listeners is also provided. The listeners expect to
+    /// see changes that would bring them up to date with data in this `Zone`
+    pub fn merge_with_listeners(&self, diff: NodeTree, listeners: Vec<RListener>, hold: Option<Hold>) {
+        self.tx.send(ZoneCall::MergeWithListeners(diff, listeners, hold)).unwrap();
     }
 
     pub fn path(&self) -> Path {
@@ -133,6 +189,26 @@ impl ZoneHandle {
         self.tx.send(ZoneCall::Saved).unwrap();
     }
 
+    /// Signal `Zone` that save has failed. Usually called by `Store` after write fails.
+    pub fn save_failed(&self, error: String) {
+        self.tx.send(ZoneCall::SaveFailed(error)).unwrap();
+    }
+
+    /// Waits up to `timeout` until all changes so far are saved. Returns false on timeout.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let (tx, rx) = mpsc::channel();
+
+        self.on_saved(move || {
+            tx.send(()).is_ok(); // ignore if caller gave up
+        });
+        rx.recv_timeout(timeout).is_ok()
+    }
+
+    /// Calls `f` from the `Zone` once all changes so far are saved, without waiting for it.
+    pub fn on_saved<F>(&self, f: F) where F: Fn() + Send + 'static {
+        self.tx.send(ZoneCall::Flush(Box::new(f))).unwrap();
+    }
+
     /// Get raw data of this `Zone`.
     pub fn dump(&self) -> NodeTree {
         let (tx, rx) = channel();
@@ -141,6 +217,22 @@ impl ZoneHandle {
         rx.recv().unwrap()
     }
 
+    /// Get digest of this `Zone`'s data, or `None` if it could not be loaded.
+    pub fn digest(&self) -> Option<u64> {
+        let (tx, rx) = channel();
+
+        self.tx.send(ZoneCall::Digest(tx)).unwrap();
+        rx.recv().ok()
+    }
+
+    /// Same as `dump`, with digests cached in the returned nodes, see `Node::cache_digests`.
+    pub fn dump_digested(&self) -> NodeTree {
+        let (tx, rx) = channel();
+
+        self.tx.send(ZoneCall::DumpDigested(tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
     /// Get approximate storage size of this `Zone`.
     pub fn size(&self) -> usize {
         let (tx, rx) = channel();
@@ -194,6 +286,7 @@ impl ZoneState {
     const ACTIVE: u64  = 3;
     const DIRTY: u64   = 4;
     const WRITING: u64 = 5;
+    const ERROR: u64   = 6;
 
     /// Zone is idle / hibernating
     pub fn is_idle(&self) -> bool { self.state == ZoneState::IDLE }
@@ -214,12 +307,15 @@ impl ZoneState {
     /// Write pending, data will be clean when done
     pub fn is_writing(&self) -> bool { self.state == ZoneState::WRITING }
 
+    /// Data could not be loaded, loading will be retried or recovered. User commands fail.
+    pub fn is_error(&self) -> bool { self.state == ZoneState::ERROR }
+
     /// Data is ready, allow reads and writes
-    pub fn is_ready(&self) -> bool { self.state >= ZoneState::ACTIVE }
+    pub fn is_ready(&self) -> bool { self.state >= ZoneState::ACTIVE && self.state <= ZoneState::WRITING }
 
     /// Set to specified state
     pub fn set(&mut self, state: u64) {
-        assert!(state <= ZoneState::WRITING);
+        assert!(state <= ZoneState::ERROR);
         self.state = state;
     }
 }
@@ -260,6 +356,16 @@ impl Zone {
             rx: rx,
             queued: VecDeque::new(),
             listeners: vec![],
+            digest: None,
+            unsaved: Default::default(),
+            unsaved_since: None,
+            writing: Default::default(),
+            writing_since: None,
+            flushing: vec![],
+            error: None,
+            retries: 0,
+            recovering: false,
+            save_retries: 0,
             writes: 0
         }
     }
@@ -278,13 +384,32 @@ impl Zone {
                 let call = self.rx.recv().unwrap();
 
                 match call {
+                    ZoneCall::ClearError |
+                    ZoneCall::Error(_) |
+                    ZoneCall::Flush(_) |
                     ZoneCall::Load |
                     ZoneCall::Loaded(_) |
+                    ZoneCall::LoadFailed(..) |
                     ZoneCall::Hibernate |
+                    ZoneCall::Retry |
+                    ZoneCall::RetrySave |
                     ZoneCall::Size(_) |
                     ZoneCall::State(_) => {
                         self.handle_call(call);
                     },
+                    ZoneCall::UserCommand(cmd) if self.state.is_error() => {
+                        cmd.reply.send(self.error_result()).unwrap();
+                    },
+                    ZoneCall::Digest(_) if self.digest.is_some() => {
+                        self.handle_call(call);
+                    },
+                    ZoneCall::Digest(_) if self.state.is_error() => (), // reply dropped, see `digest`
+                    ZoneCall::Merge(..) if self.recovering => {
+                        // A replica sent its copy, merged into what is left locally once loaded
+                        self.queued.push_back(call);
+                        self.recovering = false;
+                        self.retry();
+                    },
                     _ => {
                         self.queued.push_back(call);
 
@@ -305,35 +430,63 @@ impl Zone {
 
                 cmd.reply.send(result).unwrap(); // TODO: don't crash the Zone!
             },
+            ZoneCall::ClearError => {
+                self.clear_error();
+            },
+            ZoneCall::Digest(reply) => {
+                reply.send(self.digest()).is_ok(); // ignore if caller gave up
+            },
             ZoneCall::Dump(reply) => {
                 reply.send(self.dump()).unwrap();
             },
+            ZoneCall::DumpDigested(reply) => {
+                self.digest();
+                reply.send(self.dump()).unwrap();
+            },
+            ZoneCall::Error(reply) => {
+                reply.send(self.error.clone()).unwrap();
+            },
+            ZoneCall::Flush(done) => {
+                self.flush(done);
+            },
             ZoneCall::Load => {
                 self.load();
             },
             ZoneCall::Loaded(data) => {
                 self.loaded(data);
             },
-            ZoneCall::Merge(diff, replicate) => {
-                self.merge(diff, replicate);
+            ZoneCall::LoadFailed(error, recover) => {
+                self.load_failed(error, recover);
+            },
+            ZoneCall::Merge(diff, replicate, hold) => {
+                self.merge_held(diff, replicate, hold);
 
                 if replicate {
                     self.split_check();
                 }
             },
-            ZoneCall::MergeWithListeners(diff, listeners) => {
-                self.merge_with_listeners(diff, listeners);
+            ZoneCall::MergeWithListeners(diff, listeners, hold) => {
+                self.merge_with_listeners(diff, listeners, hold);
                 self.split_check();
             },
             ZoneCall::Hibernate => {
                 self.hibernate();
             },
+            ZoneCall::Retry => {
+                self.retry();
+            },
+            ZoneCall::RetrySave => {
+                self.retry_save();
+            },
             ZoneCall::Save => {
                 self.save();
             },
             ZoneCall::Saved => {
                 self.saved();
             },
+            ZoneCall::SaveFailed(error) => {
+                self.save_failed(error);
+            },
             ZoneCall::Size(reply) => {
                 reply.send(self.size()).unwrap();
             },
@@ -345,10 +498,14 @@ impl Zone {
 
     pub fn dispatch(&mut self, command: Command, tx: Sender<String>) -> ZoneResult {
         match command.call {
+            Call::Auth => {
+                // Handled by `Client`, never dispatched to a `Zone`
+                ZoneResult { ..Default::default() }
+            },
             Call::Bind => {
                 let (update, delegated) = self.bind(&command.path, tx);
 
-                ZoneResult { update: update, delegated: delegated }
+                ZoneResult { update: update, delegated: delegated, ..Default::default() }
             },
             Call::Kill => {
                 self.kill(&command.path, command.timestamp);
@@ -358,7 +515,7 @@ impl Zone {
             Call::Read => {
                 let (update, delegated) = self.read(&command.path);
 
-                ZoneResult { update: update, delegated: delegated }
+                ZoneResult { update: update, delegated: delegated, ..Default::default() }
             },
             Call::Write => {
                 self.write(&command.path, command.timestamp, command.params);
@@ -390,15 +547,34 @@ impl Zone {
 
     /// Merge value(s). Merge is generic and most operations are defined as a merge. Set
     /// `replicate` flag if merge was due to a user command.
-    pub fn merge(&mut self, mut diff: NodeTree, replicate: bool) {
+    pub fn merge(&mut self, diff: NodeTree, replicate: bool) {
+        self.merge_held(diff, replicate, None);
+    }
+
+    /// Same as `merge`, for data held since an earlier change. Data sent on to delegated Zones is
+    /// held in turn, see `wal`.
+    pub fn merge_held(&mut self, mut diff: NodeTree, replicate: bool, hold: Option<Hold>) {
         let (update, externals) = self.data.tree.merge(&mut diff);
 
+        self.digest = None;
+
         // Only notify if there are changes
         if let Some(update) = update {
             self.notify(&update);
         }
 
-        if ! diff.node.is_noop() {
+        // Zones owned by other replicas are kept in memory only, see `shard`, unless the data is
+        // held for the WAL as no owner took it. It is saved here then, and replicated to owners.
+        if ! diff.node.is_noop() && (hold.is_some() || self.app.shards.read().unwrap().is_owner(&self.path)) {
+            self.unsaved.merge(&mut diff.clone());
+
+            let since = hold.as_ref().map_or_else(time::precise_time_ns, |h| h.since);
+
+            if self.unsaved_since.map_or(true, |s| since < s) {
+                self.unsaved_since = Some(since);
+                self.report_unsaved();
+            }
+
             self.writes += 1;
             self.dirty();
         }
@@ -426,10 +602,10 @@ impl Zone {
 
                 // Data meant for delegated node
                 if x_listeners.is_empty() {
-                    self.app.manager.send_external(&self.path, external, replicate);
+                    router::send_external(&self.app, &self.path, external, replicate, self.hold(&hold));
                 }
                 else {
-                    self.app.manager.send_external_with_listeners(&self.path, external, x_listeners);
+                    router::send_external_with_listeners(&self.app, &self.path, external, x_listeners, self.hold(&hold));
                 }
             }
         }
@@ -445,7 +621,7 @@ impl Zone {
     /// TODO: This might be better implemented as a merge_bind operation (where
     /// bind takes in a "cached values" parameter), which will solve the
     /// recursive delegation problem.
-    pub fn merge_with_listeners(&mut self, diff: NodeTree, listeners: Vec<RListener>) {
+    pub fn merge_with_listeners(&mut self, diff: NodeTree, listeners: Vec<RListener>, hold: Option<Hold>) {
         // First, bring listeners up to date
         let (update, externals) = {
             // TODO: workaround merge mutating receiver and argument
@@ -482,7 +658,7 @@ impl Zone {
 
                 // Recursively propagate listeners-with-cached-data
                 if ! x_listeners.is_empty() {
-                    self.app.manager.send_external_with_listeners(&self.path, external, x_listeners);
+                    router::send_external_with_listeners(&self.app, &self.path, external, x_listeners, self.hold(&hold));
                 }
             }
         }
@@ -496,7 +672,7 @@ impl Zone {
 
         // Merge data delegated from parent. Each parent replica should have
         // independently delegated, so don't replicate
-        self.merge(diff, false);
+        self.merge_held(diff, false, hold);
 
         // Add delegated listeners to `Zone`
         self.listeners.append(&mut listeners);
@@ -529,14 +705,81 @@ impl Zone {
 
             self.data.tree = data.tree;
             self.state.set(ZoneState::ACTIVE);
+            self.error = None;
+            self.retries = 0;
         }
         else {
             unimplemented!()
         }
     }
 
-    /// Callback to notify Zone to hibernate.
+    /// Callback for stores to signal that data could not be loaded. Queued user commands fail, and
+    /// loading is retried with backoff. Other queued calls wait for data. The Zone no longer counts
+    /// as loaded by `Manager` until retried.
+    ///
+    /// With `recover`, the store lost the data, e.g. to corruption, and asked replicas for theirs.
+    /// Loading is retried once a replica's copy is merged, or the error is cleared.
+    pub fn load_failed(&mut self, error: String, recover: bool) {
+        if ! self.state.is_loading() {
+            error!("Zone {:?} failed to load while {:?}, ignoring: {}", self.path, self.state, error);
+            return;
+        }
+
+        error!("Zone {:?} failed to load: {}", self.path, error);
+
+        self.state.set(ZoneState::ERROR);
+        self.error = Some(error);
+        self.recovering = recover;
+        self.app.manager.zone_hibernated(self.handle.clone());
+
+        for call in mem::replace(&mut self.queued, VecDeque::new()) {
+            match call {
+                ZoneCall::UserCommand(cmd) => {
+                    cmd.reply.send(self.error_result()).unwrap();
+                },
+                call => self.queued.push_back(call)
+            }
+        }
+
+        if recover {
+            // Digest of lost data would hide the loss from anti-entropy
+            self.digest = None;
+            return;
+        }
+
+        let delay = cmp::min(RETRY_BASE_MS << cmp::min(self.retries, 16), RETRY_MAX_MS);
+        let handle = self.handle.clone();
+
+        self.retries += 1;
+
+        mioco::spawn(move|| {
+            mioco::sleep(Duration::from_millis(delay));
+            handle.tx.send(ZoneCall::Retry).is_ok(); // ignore if Zone is gone
+        });
+    }
+
+    /// Retries loading data after a failure, once `Manager` allows. Lost data is not reloaded
+    /// until recovered, see `load_failed`.
+    pub fn retry(&mut self) {
+        if self.state.is_error() && ! self.recovering {
+            self.state.set(ZoneState::INIT);
+            self.app.manager.zone_request_load(self.handle.clone());
+        }
+    }
+
+    /// Resets backoff and retries loading data now. Lost data is given up on, loading what is left.
+    pub fn clear_error(&mut self) {
+        self.retries = 0;
+        self.recovering = false;
+        self.retry();
+    }
+
+    /// Callback to notify Zone to hibernate. Zones in error state are not loaded, see `load_failed`.
     pub fn hibernate(&mut self) {
+        if self.state.is_error() {
+            return;
+        }
+
         if self.state.is_active() {
             self.state.set(ZoneState::IDLE);
             self.data.tree = Default::default();
@@ -550,7 +793,9 @@ impl Zone {
     /// Callback to notify Zone of available resources to persist dirty data.
     pub fn save(&mut self) {
         if self.state.is_dirty() {
-            self.app.store.write(&self.handle, &self.path, &self.data);
+            self.writing = mem::replace(&mut self.unsaved, Default::default());
+            self.writing_since = self.unsaved_since.take();
+            self.app.store.write(&self.handle, &self.path, &self.data, &self.writing);
             self.state.set(ZoneState::WRITING);
         }
         else {
@@ -560,8 +805,17 @@ impl Zone {
 
     /// Callback to notify Zone that data was persisted.
     pub fn saved(&mut self) {
+        self.writing = Default::default();
+        self.writing_since = None;
+        self.save_retries = 0;
+        self.report_unsaved();
+
         if self.state.is_writing() {
             self.state.set(ZoneState::ACTIVE);
+
+            for done in self.flushing.drain(..) {
+                done();
+            }
         }
         else if self.state.is_dirty() {
             // Zone dirtied itself during a write
@@ -572,6 +826,62 @@ impl Zone {
         }
     }
 
+    /// Callback to notify Zone that data could not be persisted. Changes being written are unsaved
+    /// again, so the WAL keeps them, and the write is requested again with backoff.
+    pub fn save_failed(&mut self, error: String) {
+        error!("Zone {:?} failed to save: {}", self.path, error);
+
+        let mut unsaved = mem::replace(&mut self.writing, Default::default());
+
+        unsaved.merge(&mut self.unsaved);
+        self.unsaved = unsaved;
+        self.unsaved_since = self.writing_since.take().into_iter().chain(self.unsaved_since).min();
+
+        if self.state.is_writing() || self.state.is_dirty() {
+            self.state.set(ZoneState::DIRTY);
+        }
+        else {
+            unimplemented!();
+        }
+
+        let delay = cmp::min(RETRY_BASE_MS << cmp::min(self.save_retries, 16), RETRY_MAX_MS);
+        let handle = self.handle.clone();
+
+        self.save_retries += 1;
+
+        mioco::spawn(move|| {
+            mioco::sleep(Duration::from_millis(delay));
+            handle.tx.send(ZoneCall::RetrySave).is_ok(); // ignore if Zone is gone
+        });
+    }
+
+    /// Requests a write again after a failure, see `save_failed`.
+    pub fn retry_save(&mut self) {
+        if self.state.is_dirty() {
+            self.app.store.request_write(&self.handle);
+        }
+    }
+
+    /// Calls `done` once all changes so far are saved. Zones that are not loaded have nothing to
+    /// save.
+    pub fn flush(&mut self, done: Box<Fn() + Send>) {
+        if self.state.is_dirty() || self.state.is_writing() {
+            self.flushing.push(done);
+        }
+        else {
+            done();
+        }
+    }
+
+    fn error_result(&self) -> ZoneResult {
+        let error = self.error.as_ref().map_or("unknown error", |e| &e[..]);
+
+        ZoneResult {
+            error: Some(format!("Zone unavailable: {}", error)),
+            ..Default::default()
+        }
+    }
+
     /// Get zone path.
     pub fn path(&self) -> Path {
         (*self.path).clone()
@@ -582,6 +892,18 @@ impl Zone {
         self.data.tree.clone()
     }
 
+    /// Get digest of data, caching digests of all nodes, see `Node::cache_digests`. Only nodes
+    /// changed since are hashed again.
+    pub fn digest(&mut self) -> u64 {
+        let digest = match self.digest {
+            Some(digest) => digest,
+            None => self.data.tree.node.cache_digests()
+        };
+
+        self.digest = Some(digest);
+        digest
+    }
+
     /// Get estimated size.
     pub fn size(&self) -> usize {
         // TODO: size does not handle cloaked data properly
@@ -622,6 +944,20 @@ impl Zone {
         unimplemented!();
     }
 
+    /// Reports the earliest unsaved change to the WAL, which keeps logged writes until saved.
+    fn report_unsaved(&self) {
+        if let Some(ref wal) = self.app.wal {
+            wal.zone_dirty(&self.path, self.writing_since.into_iter().chain(self.unsaved_since).min());
+        }
+    }
+
+    /// Holds logged writes while data is sent to a delegated Zone, from the change being merged.
+    fn hold(&self, merging: &Option<Hold>) -> Option<Hold> {
+        let since = merging.as_ref().map_or_else(time::precise_time_ns, |h| h.since);
+
+        self.app.wal.as_ref().map(|wal| wal.hold(since))
+    }
+
     /// Notifies listeners
     fn notify(&mut self, update: &Update) {
         self.listeners.retain(|listener| {
@@ -677,4 +1013,8 @@ fn test_zone_state() {
     state.set(ZoneState::WRITING);
     assert!(state.is_writing());
     assert!(state.is_ready());
+
+    state.set(ZoneState::ERROR);
+    assert!(state.is_error());
+    assert!(!state.is_ready());
 }